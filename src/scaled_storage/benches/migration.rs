@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use scaled_storage::node::Node;
+use std::collections::HashSet;
+
+fn node_with_keys(key_count: usize) -> Node<String, String> {
+    let mut node = Node::new("index_node_id".to_string(), HashSet::new());
+    node.add_node("index_node_id".to_string());
+
+    for id in 0..key_count {
+        node.insert_data(format!("data_key_{}", id), format!("data_{}", id));
+    }
+
+    node.add_node("node_1".to_string());
+    node
+}
+
+fn migration(c: &mut Criterion) {
+    let mut group = c.benchmark_group("migration");
+    group.sample_size(10);
+
+    for key_count in [1_000, 10_000, 100_000, 500_000] {
+        let node = node_with_keys(key_count);
+
+        group.bench_with_input(
+            BenchmarkId::new("get_data_to_migrate", key_count),
+            &node,
+            |b, node| b.iter(|| node.get_data_to_migrate().count()),
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("take_data", key_count),
+            &key_count,
+            |b, &key_count| {
+                b.iter_batched(
+                    || node_with_keys(key_count),
+                    |mut node| {
+                        let keys = node.get_keys_to_migrate();
+                        node.take_data(&keys)
+                    },
+                    criterion::BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, migration);
+criterion_main!(benches);
@@ -0,0 +1,98 @@
+/// candid interface of the house-keeping methods, generated from their rust types
+use crate::node_manager::{
+    AuditReport, CanisterManagerEvent, CanisterManagerEventResponse, InitCanisterManagerParam,
+    NodeInfo, NodeMember, WasmInitArgs,
+};
+use candid::parser::types::FuncMode;
+use candid::types::internal::TypeContainer;
+use candid::types::{Function, Type};
+use candid::utils::{service_compatible, CandidSource};
+
+/// Candid service of the house-keeping methods `scaled_storage_canister!` declares. An app's
+/// `.did` file declares these methods next to its own ones, see `check_service`.
+pub fn housekeeping_service() -> String {
+    let mut env = TypeContainer::new();
+    let mut service = vec![
+        (
+            "audit_placement",
+            vec![env.add::<bool>()],
+            vec![env.add::<Vec<Result<AuditReport, String>>>()],
+            vec![],
+        ),
+        (
+            "handle_event",
+            vec![env.add::<CanisterManagerEvent>()],
+            vec![env.add::<CanisterManagerEventResponse>()],
+            vec![],
+        ),
+        (
+            "init_canister_manager",
+            vec![env.add::<InitCanisterManagerParam>()],
+            vec![],
+            vec![],
+        ),
+        (
+            "init_wasm",
+            vec![env.add::<WasmInitArgs>()],
+            vec![env.add::<bool>()],
+            vec![],
+        ),
+        (
+            "node_info",
+            vec![],
+            vec![env.add::<NodeInfo>()],
+            vec![FuncMode::Query],
+        ),
+        (
+            "set_node_weight",
+            vec![env.add::<NodeMember>()],
+            vec![env.add::<bool>()],
+            vec![],
+        ),
+    ]
+    .into_iter()
+    .map(|(name, args, rets, modes)| (name.to_string(), Type::Func(Function { modes, args, rets })))
+    .collect::<Vec<_>>();
+    service.sort_unstable_by_key(|(name, _)| name.clone());
+    candid::bindings::candid::compile(&env.env, &Some(Type::Service(service)))
+}
+
+/// Checks that the service of an app's `.did` file offers every house-keeping method with types
+/// the library's methods can be called with and whose replies it can decode. Other methods
+/// and type names don't matter.
+pub fn check_service(did: &str) -> Result<(), String> {
+    let housekeeping = housekeeping_service();
+    service_compatible(CandidSource::Text(did), CandidSource::Text(&housekeeping))
+        .map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_service, housekeeping_service};
+
+    #[test]
+    fn housekeeping_service_is_valid_candid() {
+        let service = housekeeping_service();
+
+        assert!(service.contains("node_info : () -> (NodeInfo) query;"));
+        assert!(service.contains("position : nat64;"));
+        assert_eq!(check_service(&service), Ok(()));
+    }
+
+    #[test]
+    fn services_missing_or_changing_methods_are_rejected() {
+        let service = housekeeping_service();
+
+        let without_node_info = service.replace("node_info : () -> (NodeInfo) query;", "");
+        assert!(check_service(&without_node_info).is_err());
+
+        let with_nat8_position = service.replace("position : nat64;", "position : nat8;");
+        assert!(check_service(&with_nat8_position).is_err());
+
+        let with_app_methods = service.replace(
+            "service : {",
+            "service : {\n  get_data : (text) -> (text) query;",
+        );
+        assert_eq!(check_service(&with_app_methods), Ok(()));
+    }
+}
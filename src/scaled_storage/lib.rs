@@ -1,3 +1,10 @@
+pub use scaled_storage_macros::routed;
+
+pub mod interface;
+mod macros;
+pub mod merge;
+pub mod merkle;
 pub mod node;
 pub mod node_manager;
-
+pub mod placement;
+pub mod stable;
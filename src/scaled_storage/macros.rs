@@ -0,0 +1,162 @@
+/// Declares the canister manager of a canister and its housekeeping endpoints, so an app only
+/// writes its own data endpoints:
+/// `init`, `pre_upgrade`, `post_upgrade`, `init_canister_manager`, `init_wasm`, `heartbeat`,
+/// `handle_event`, `set_node_weight`, `audit_placement` and `node_info`.
+///
+/// Takes the data type and the "should scale up" policy, a closure from the number of keys of a
+/// node to whether it should create a new node. It may be followed by `placement = <type>` and by
+/// `setup = <closure>`, which `init` calls with the new manager, for example to set the
+/// replication factor of the first node. The app's endpoints get the manager from the generated
+/// `canister_manager()`. The crate needs `ic_kit` and `ic_cdk` as dependencies, like any canister
+/// built on `ic_kit`.
+///
+/// `handle_event` traps unless the caller is a node of the cluster, and `init_canister_manager`,
+/// `init_wasm`, `set_node_weight` and `audit_placement` unless it is a controller: the installer,
+/// which for a node created by scaling up is its parent, and the controllers it inherits.
+///
+/// ```ignore
+/// scaled_storage::scaled_storage_canister!(String, |size| size > 50);
+/// // or with an ordered placement
+/// scaled_storage::scaled_storage_canister!(
+///     String,
+///     |size| size > 50,
+///     placement = KeyRangePlacement<Principal>,
+///     setup = |manager| manager.canister.set_replication_factor(3)
+/// );
+///
+/// #[query]
+/// fn get_local(key: String) -> Option<String> {
+///     canister_manager().borrow().canister.get_data(&key).cloned()
+/// }
+/// ```
+#[macro_export]
+macro_rules! scaled_storage_canister {
+    (@canister $data:ty, $placement:ty, $should_scale_up:expr, $setup:expr) => {
+        thread_local! {
+            static CANISTER_MANAGER: std::cell::RefCell<
+                Option<$crate::node_manager::SharedCanisterManager<$data, $placement>>,
+            > = const { std::cell::RefCell::new(None) };
+        }
+
+        /// handle to the manager, borrow it only between awaits since other messages run during them
+        fn canister_manager() -> $crate::node_manager::SharedCanisterManager<$data, $placement> {
+            CANISTER_MANAGER.with(|manager| manager.borrow().clone().unwrap())
+        }
+
+        #[ic_kit::macros::init]
+        fn init() {
+            let mut manager = $crate::node_manager::CanisterManager::<$data, $placement>::new(
+                ic_kit::ic::id(),
+                $should_scale_up,
+            );
+            manager.set_controllers(vec![ic_kit::ic::caller()]);
+            let setup: fn(&mut $crate::node_manager::CanisterManager<$data, $placement>) = $setup;
+            setup(&mut manager);
+            CANISTER_MANAGER.with(|canister_manager| {
+                *canister_manager.borrow_mut() = Some(manager.into());
+            });
+        }
+
+        // a failed snapshot traps, which aborts the upgrade and keeps the running canister
+        #[ic_kit::macros::pre_upgrade]
+        fn pre_upgrade() {
+            canister_manager()
+                .borrow()
+                .save_stable(&mut $crate::stable::writer())
+                .unwrap();
+        }
+
+        #[ic_kit::macros::post_upgrade]
+        fn post_upgrade() {
+            let manager = $crate::node_manager::CanisterManager::<$data, $placement>::load_stable(
+                &mut $crate::stable::reader(),
+                $should_scale_up,
+            )
+            .unwrap();
+            CANISTER_MANAGER.with(|canister_manager| {
+                *canister_manager.borrow_mut() = Some(manager.into());
+            });
+        }
+
+        // traps unless the caller is a controller, the installer or the parent node
+        fn only_controllers() {
+            if !canister_manager()
+                .borrow()
+                .is_controller(&ic_kit::ic::caller())
+            {
+                ic_kit::ic::trap("only controllers may call this method");
+            }
+        }
+
+        #[ic_kit::macros::update]
+        async fn init_canister_manager(param: $crate::node_manager::InitCanisterManagerParam) {
+            only_controllers();
+            canister_manager().lifecyle_init_node(param.args).await
+        }
+
+        #[ic_kit::macros::update]
+        fn init_wasm(param: $crate::node_manager::WasmInitArgs) -> bool {
+            only_controllers();
+            canister_manager().borrow_mut().lifecycle_init_wasm(param)
+        }
+
+        #[ic_kit::macros::heartbeat]
+        async fn heartbeat() {
+            canister_manager().lifecyle_heartbeat_node().await;
+        }
+
+        #[ic_kit::macros::update]
+        async fn handle_event(
+            event: $crate::node_manager::CanisterManagerEvent,
+        ) -> $crate::node_manager::CanisterManagerEventResponse {
+            let manager = canister_manager();
+            if let Err(error) = manager.borrow().authorize_event(&ic_kit::ic::caller()) {
+                ic_kit::ic::trap(&error);
+            }
+            manager.lifecycle_handle_event(event).await
+        }
+
+        // changes a node's share of the data, refused unless the caller is a controller
+        #[ic_kit::macros::update]
+        async fn set_node_weight(member: $crate::node_manager::NodeMember) -> bool {
+            canister_manager().lifecycle_set_weight(member).await
+        }
+
+        // counts misplaced keys on every node and hands them to their owners when `repair` is
+        // true, controllers only
+        #[ic_kit::macros::update]
+        async fn audit_placement(
+            repair: bool,
+        ) -> Vec<Result<$crate::node_manager::AuditReport, String>> {
+            only_controllers();
+            canister_manager().audit_cluster(repair).await
+        }
+
+        #[ic_kit::macros::query]
+        fn node_info() -> $crate::node_manager::NodeInfo {
+            canister_manager().borrow().node_info()
+        }
+    };
+    ($data:ty, $should_scale_up:expr, placement = $placement:ty, setup = $setup:expr) => {
+        $crate::scaled_storage_canister!(@canister $data, $placement, $should_scale_up, $setup);
+    };
+    ($data:ty, $should_scale_up:expr, placement = $placement:ty) => {
+        $crate::scaled_storage_canister!(@canister $data, $placement, $should_scale_up, |_| {});
+    };
+    ($data:ty, $should_scale_up:expr, setup = $setup:expr) => {
+        $crate::scaled_storage_canister!(
+            @canister $data,
+            $crate::placement::AnchorPlacement<ic_kit::candid::Principal>,
+            $should_scale_up,
+            $setup
+        );
+    };
+    ($data:ty, $should_scale_up:expr) => {
+        $crate::scaled_storage_canister!(
+            @canister $data,
+            $crate::placement::AnchorPlacement<ic_kit::candid::Principal>,
+            $should_scale_up,
+            |_| {}
+        );
+    };
+}
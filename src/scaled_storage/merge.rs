@@ -0,0 +1,135 @@
+/// Conflict resolution for values that meet on one node
+use crate::node::Versioned;
+use candid::CandidType;
+use serde::Deserialize;
+
+/// How a value handed over by another node, by a migration, replication or anti-entropy, is
+/// combined with the local value of the same key. The key keeps the higher of the two versions.
+///
+/// The default is last-writer-wins by version, the incoming value winning ties. Implement it with
+/// an empty `impl Merge for MyData {}` to keep that, or override `merge` for CRDTs, see also
+/// `Timestamped` and `Siblings`.
+pub trait Merge: Sized {
+    fn merge(local: Versioned<Self>, incoming: Versioned<Self>) -> Self {
+        match incoming.version >= local.version {
+            true => incoming.value,
+            false => local.value,
+        }
+    }
+}
+
+macro_rules! last_writer_wins {
+    ($($data:ty),*) => {
+        $(impl Merge for $data {})*
+    };
+}
+
+last_writer_wins!(
+    String, bool, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64
+);
+
+impl<T> Merge for Vec<T> {}
+
+impl<T> Merge for Option<T> {}
+
+/// last-writer-wins by the writer's clock instead of by version, versions decide ties
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Deserialize)]
+pub struct Timestamped<T> {
+    pub value: T,
+    pub timestamp: u64,
+}
+
+impl<T> Merge for Timestamped<T> {
+    fn merge(local: Versioned<Self>, incoming: Versioned<Self>) -> Self {
+        match (local.value.timestamp, local.version)
+            .cmp(&(incoming.value.timestamp, incoming.version))
+        {
+            std::cmp::Ordering::Greater => local.value,
+            _ => incoming.value,
+        }
+    }
+}
+
+/// Keeps both values when they were written at the same version, which means neither write saw
+/// the other, and leaves resolving them to the next write. A newer version replaces them.
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Deserialize)]
+pub struct Siblings<T> {
+    pub values: Vec<T>,
+}
+
+impl<T: PartialEq> Merge for Siblings<T> {
+    fn merge(local: Versioned<Self>, incoming: Versioned<Self>) -> Self {
+        match local.version.cmp(&incoming.version) {
+            std::cmp::Ordering::Greater => local.value,
+            std::cmp::Ordering::Less => incoming.value,
+            std::cmp::Ordering::Equal => {
+                let mut values = local.value.values;
+                for value in incoming.value.values {
+                    if !values.contains(&value) {
+                        values.push(value);
+                    }
+                }
+                Siblings { values }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Merge, Siblings, Timestamped};
+    use crate::node::Versioned;
+
+    fn versioned<T>(value: T, version: u64) -> Versioned<T> {
+        Versioned { value, version }
+    }
+
+    #[test]
+    fn newer_versions_win_by_default() {
+        assert_eq!(
+            String::merge(versioned("old".into(), 1), versioned("new".into(), 2)),
+            "new"
+        );
+        assert_eq!(
+            String::merge(versioned("new".into(), 3), versioned("old".into(), 2)),
+            "new"
+        );
+        assert_eq!(u64::merge(versioned(1, 2), versioned(2, 2)), 2);
+    }
+
+    #[test]
+    fn timestamps_win_over_versions() {
+        let early = Timestamped {
+            value: "early",
+            timestamp: 10,
+        };
+        let late = Timestamped {
+            value: "late",
+            timestamp: 20,
+        };
+        assert_eq!(
+            Timestamped::merge(versioned(late.clone(), 1), versioned(early.clone(), 5)),
+            late
+        );
+        assert_eq!(
+            Timestamped::merge(versioned(early, 5), versioned(late.clone(), 1)),
+            late
+        );
+    }
+
+    #[test]
+    fn siblings_keep_concurrent_values() {
+        let left = Siblings { values: vec!["a"] };
+        let right = Siblings {
+            values: vec!["b", "a"],
+        };
+        assert_eq!(
+            Siblings::merge(versioned(left.clone(), 2), versioned(right.clone(), 2)).values,
+            vec!["a", "b"]
+        );
+        assert_eq!(
+            Siblings::merge(versioned(left, 3), versioned(right.clone(), 2)).values,
+            vec!["a"]
+        );
+    }
+}
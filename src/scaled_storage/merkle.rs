@@ -0,0 +1,117 @@
+/// Merkle tree over the partitions of a node, used to find the partitions replicas disagree on
+use crate::node::{PartitionId, PARTITION_COUNT};
+use crate::placement::hash_of;
+use std::ops::Range;
+
+/// position of a tree node, 1 is the root and the children of `i` are `2i` and `2i + 1`
+pub type TreeIndex = u32;
+
+pub const ROOT: TreeIndex = 1;
+
+/// Binary tree with one leaf per partition. A leaf is the xor of the hashes of the partition's
+/// keys with their versions and value digests, so a write updates it without reading the rest of
+/// the partition, and every inner node hashes its children. Nodes holding the same values at the
+/// same versions end up with the same hashes, and empty subtrees hash to 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleTree {
+    hashes: Vec<u64>,
+}
+
+impl Default for MerkleTree {
+    fn default() -> Self {
+        MerkleTree {
+            hashes: vec![0; 2 * PARTITION_COUNT as usize],
+        }
+    }
+}
+
+impl MerkleTree {
+    /// adds `key` at `version` with a value hashing to `digest` to its partition's leaf, or
+    /// removes it if it was added before
+    pub fn toggle(&mut self, partition: PartitionId, key: &str, version: u64, digest: u64) {
+        let mut index = PARTITION_COUNT + partition % PARTITION_COUNT;
+        self.hashes[index as usize] ^= hash_of((key, version, digest));
+        while index > ROOT {
+            index /= 2;
+            let (left, right) = (self.hash(2 * index), self.hash(2 * index + 1));
+            self.hashes[index as usize] = match (left, right) {
+                (0, 0) => 0,
+                _ => hash_of((left, right)),
+            };
+        }
+    }
+
+    pub fn root(&self) -> u64 {
+        self.hash(ROOT)
+    }
+
+    /// 0 for indexes outside the tree
+    pub fn hash(&self, index: TreeIndex) -> u64 {
+        self.hashes.get(index as usize).copied().unwrap_or(0)
+    }
+
+    /// None for leaves
+    pub fn children(index: TreeIndex) -> Option<[TreeIndex; 2]> {
+        match index < PARTITION_COUNT {
+            true => Some([2 * index, 2 * index + 1]),
+            false => None,
+        }
+    }
+
+    /// partitions below `index`
+    pub fn partitions(index: TreeIndex) -> Range<PartitionId> {
+        let depth = PARTITION_COUNT.trailing_zeros() - (31 - index.leading_zeros());
+        let start = (index << depth) - PARTITION_COUNT;
+        start..start + (1 << depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MerkleTree, ROOT};
+    use crate::node::PARTITION_COUNT;
+
+    #[test]
+    fn toggling_twice_restores_the_tree() {
+        let mut tree = MerkleTree::default();
+        tree.toggle(3, "key", 1, 0);
+        assert_ne!(tree.root(), 0);
+        tree.toggle(3, "key", 1, 0);
+        assert_eq!(tree, MerkleTree::default());
+    }
+
+    #[test]
+    fn leaves_do_not_depend_on_write_order() {
+        let (mut first, mut second) = (MerkleTree::default(), MerkleTree::default());
+        first.toggle(7, "a", 1, 0);
+        first.toggle(7, "b", 2, 0);
+        second.toggle(7, "b", 2, 0);
+        second.toggle(7, "a", 1, 0);
+        assert_eq!(first.root(), second.root());
+
+        second.toggle(7, "b", 2, 0);
+        second.toggle(7, "b", 3, 0);
+        assert_ne!(first.root(), second.root());
+        assert_eq!(first.hash(3), second.hash(3));
+    }
+
+    #[test]
+    fn leaves_tell_values_apart() {
+        let (mut first, mut second) = (MerkleTree::default(), MerkleTree::default());
+        first.toggle(7, "a", 1, 10);
+        second.toggle(7, "a", 1, 20);
+        assert_ne!(first.root(), second.root());
+    }
+
+    #[test]
+    fn subtrees_cover_their_partitions() {
+        assert_eq!(MerkleTree::partitions(ROOT), 0..PARTITION_COUNT);
+        assert_eq!(
+            MerkleTree::partitions(3),
+            PARTITION_COUNT / 2..PARTITION_COUNT
+        );
+        assert_eq!(MerkleTree::partitions(PARTITION_COUNT + 5), 5..6);
+        assert_eq!(MerkleTree::children(PARTITION_COUNT + 5), None);
+        assert_eq!(MerkleTree::children(2), Some([4, 5]));
+    }
+}
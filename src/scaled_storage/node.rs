@@ -1,19 +1,74 @@
 /// IC - A DHT solution for the internet computer
-use anchorhash::AnchorHash;
-// use anchorhash::AnchorHash::
-use highway::HighwayBuildHasher;
+use crate::merge::Merge;
+use crate::merkle::MerkleTree;
+use crate::placement::{hashed_partition, AnchorPlacement, Placement, DEFAULT_CAPACITY};
+use ic_cdk::export::candid::{CandidType, Deserialize};
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::hash::Hash;
 
-pub struct Node<TId: Hash + Eq + Clone, Data: Default + Clone> {
+/// number of virtual partitions keys are grouped into, placement works on whole partitions so a
+/// partition's keys always move together
+pub const PARTITION_COUNT: u32 = 1024;
+
+pub type PartitionId = u32;
+
+/// clock of nodes without one, keys never expire
+fn no_clock() -> u64 {
+    0
+}
+
+/// digest of nodes without one, the merkle tree only tells versions apart
+fn no_digest<Data>(_: &Data) -> u64 {
+    0
+}
+
+/// digest standing for the value of removed keys in the merkle tree
+const REMOVED_DIGEST: u64 = u64::MAX;
+
+pub struct Node<TId: Hash + Eq + Clone, Data: Default + Clone, P = AnchorPlacement<TId>> {
     pub id: TId,
-    data: HashMap<String, Data>,
-    pub next_node_id: Option<TId>,
-    pub prev_node_id: Option<TId>,
+    partitions: HashMap<PartitionId, BTreeMap<String, Data>>,
+    /// node that created this one by scaling up, none for the first node
+    pub parent_id: Option<TId>,
+    /// nodes this one created by scaling up, oldest first
+    pub children: Vec<TId>,
     // pub index_node_id: TId,
     all_nodes: Vec<TId>,
-    hash: AnchorHash<String, TId, HighwayBuildHasher>,
+    /// weights other than the default of 1
+    weights: HashMap<TId, u32>,
+    placement: P,
+    /// placements from before every membership change since the hand-off started, oldest first,
+    /// with the position of the change in `history`, while keys may still sit on their owners
+    /// under any of them, see `handoff_sources`
+    previous_placements: Vec<(usize, P)>,
+    /// number of membership changes seen, see `epoch`
+    epoch: u64,
+    /// every change applied to the placement in order, see `placement_history`
+    history: Vec<PlacementChange<TId>>,
+    /// keys prepared by a cross-node transaction, mapped to the transaction id and its expiry
+    locks: HashMap<String, (String, u64)>,
+    /// version of every key holding data, bumped on each write
+    versions: HashMap<String, u64>,
+    /// digest of the value of every key holding data when its version was set, see `set_digest`
+    digests: HashMap<String, u64>,
+    /// version of the removal of keys this node still replicates, by partition, so a key written
+    /// again continues from it, see `version`
+    removed: HashMap<PartitionId, HashMap<String, u64>>,
+    /// digest of the keys and versions, kept in step with `versions` and `removed`
+    tree: MerkleTree,
+    /// expiry time of the keys written with a ttl
+    expiries: HashMap<String, u64>,
+    /// the same keys ordered by expiry, swept from the front
+    expiry_queue: BTreeSet<(u64, String)>,
+    /// current time for ttls, see `set_clock`
+    clock: fn() -> u64,
+    /// hash of a value for the merkle tree, see `set_digest`
+    digest: fn(&Data) -> u64,
+    /// number of nodes holding a copy of every key, see `set_replication_factor`
+    replication_factor: usize,
 }
 
 impl<TId, Data> Node<TId, Data>
@@ -22,386 +77,2239 @@ where
     Data: Default + Clone,
 {
     pub fn new(id: TId, all_nodes: HashSet<TId>) -> Self {
+        Self::with_capacity(id, all_nodes, DEFAULT_CAPACITY)
+    }
+
+    /// capacity is the number of nodes the AnchorHash is built for, see `AnchorPlacement` for growing past it
+    pub fn with_capacity(id: TId, all_nodes: HashSet<TId>, capacity: u16) -> Self {
+        let all_nodes: Vec<TId> = all_nodes.into_iter().collect();
+        Node {
+            id,
+            placement: AnchorPlacement::new(capacity, all_nodes.clone()),
+            previous_placements: vec![],
+            epoch: 0,
+            history: vec![],
+            all_nodes,
+            weights: HashMap::new(),
+            partitions: HashMap::new(),
+            locks: HashMap::new(),
+            versions: HashMap::new(),
+            digests: HashMap::new(),
+            removed: HashMap::new(),
+            tree: MerkleTree::default(),
+            expiries: HashMap::new(),
+            expiry_queue: BTreeSet::new(),
+            clock: no_clock,
+            digest: no_digest,
+            replication_factor: 1,
+            parent_id: None,
+            children: vec![],
+        }
+    }
+}
+
+impl<TId, Data, P> Node<TId, Data, P>
+where
+    TId: Eq + Hash + Clone,
+    Data: Default + Clone,
+    P: Placement<TId>,
+{
+    /// creates a node with an explicit placement strategy, the placement starts empty and is filled by `add_node`
+    pub fn with_placement(id: TId, placement: P) -> Self {
         Node {
             id,
-            // index_node_id,
-            all_nodes: all_nodes.clone().into_iter().collect(),
-            hash: anchorhash::Builder::with_hasher(Default::default())
-                .with_resources(all_nodes)
-                .build(100),
-            data: HashMap::new(),
-            prev_node_id: None,
-            next_node_id: None,
+            all_nodes: vec![],
+            weights: HashMap::new(),
+            placement,
+            previous_placements: vec![],
+            epoch: 0,
+            history: vec![],
+            partitions: HashMap::new(),
+            locks: HashMap::new(),
+            versions: HashMap::new(),
+            digests: HashMap::new(),
+            removed: HashMap::new(),
+            tree: MerkleTree::default(),
+            expiries: HashMap::new(),
+            expiry_queue: BTreeSet::new(),
+            clock: no_clock,
+            digest: no_digest,
+            replication_factor: 1,
+            parent_id: None,
+            children: vec![],
         }
     }
 
-    pub fn with_data_mut<'a, F, R>(&mut self, key: String, action: F) -> NodeResult<TId, Option<R>>
+    /// Every call counts as a write and bumps the key's version, see `with_data` for reads. Keys
+    /// prepared by a cross-node transaction refuse writes until it commits or aborts.
+    pub fn with_data_mut<'a, F, R>(
+        &mut self,
+        key: String,
+        action: F,
+    ) -> NodeResult<TId, Result<Option<R>, WriteError>>
     where
         F: FnOnce(&mut Data) -> R,
     {
         match self.node_id_from_data_key(&key) {
             Some(node_id) => {
                 if node_id.clone() == self.id {
-                    match self.data.get_mut(&key) {
-                        Some(data) => NodeResult::Result(Some(action(data))),
-                        None => NodeResult::Result(None),
+                    if self.is_locked(&key) {
+                        return NodeResult::Result(Err(WriteError::Locked));
+                    }
+                    match self.get_data_mut(&key) {
+                        Some(data) => {
+                            let result = action(data);
+                            self.bump_version(&key);
+                            NodeResult::Result(Ok(Some(result)))
+                        }
+                        None => NodeResult::Result(Ok(None)),
                     }
                 } else {
                     NodeResult::NodeId(node_id.clone())
                 }
             }
-            None => NodeResult::Result(None),
+            None => NodeResult::Result(Ok(None)),
         }
     }
 
-    pub fn insert_data(&mut self, key: String, data: Data) {
-        self.data.insert(key, data);
+    /// Reads a key together with its version, which a later `with_data_mut_if_version` can expect.
+    /// Replicas serve reads too, so the value may lag behind the owner's until it is replicated.
+    pub fn with_data<F, R>(&self, key: String, action: F) -> NodeResult<TId, Option<Versioned<R>>>
+    where
+        F: FnOnce(&Data) -> R,
+    {
+        match self.node_id_from_data_key(&key) {
+            Some(node_id) if self.is_foreign_key(&key) => NodeResult::NodeId(node_id.clone()),
+            Some(_) => NodeResult::Result(self.get_data(&key).map(|data| Versioned {
+                value: action(data),
+                version: self.version(&key),
+            })),
+            None => NodeResult::Result(None),
+        }
     }
 
-    /// same functionality as with_data_mut but keys not in node are added
-    pub fn with_upsert_data_mut<'a, F, R>(
+    /// Compare-and-swap: runs `action` like `with_upsert_data_mut`, but only if the key is still at
+    /// `expected_version`, 0 meaning the key must not hold data yet. Returns the result with the
+    /// key's new version, or the current version when another write got there first.
+    pub fn with_data_mut_if_version<F, R>(
         &mut self,
         key: String,
+        expected_version: u64,
         action: F,
-    ) -> NodeResult<TId, Option<R>>
+    ) -> NodeResult<TId, Result<Versioned<R>, WriteError>>
     where
         F: FnOnce(&mut Data) -> R,
     {
-        match self.node_id_from_data_key(&key) {
-            Some(node_id) => {
-                if node_id.clone() == self.id {
-                    match self.data.get_mut(&key) {
-                        Some(data) => NodeResult::Result(Some(action(data))),
-                        None => {
-                            let data =
-                                action(&mut self.data.entry(key).or_insert(Default::default()));
-                            NodeResult::Result(Some(data))
-                        }
-                    }
-                } else {
-                    NodeResult::NodeId(node_id.clone())
-                }
+        let current_version = self.version(&key);
+        if current_version != expected_version {
+            return match self.node_id_from_data_key(&key) {
+                Some(node_id) if *node_id != self.id => NodeResult::NodeId(node_id.clone()),
+                _ => NodeResult::Result(Err(WriteError::VersionConflict { current_version })),
+            };
+        }
+
+        match self.with_upsert_data_mut(key.clone(), action) {
+            NodeResult::NodeId(node_id) => NodeResult::NodeId(node_id),
+            NodeResult::Result(Ok(Some(value))) => NodeResult::Result(Ok(Versioned {
+                value,
+                version: self.version(&key),
+            })),
+            NodeResult::Result(Ok(None)) => {
+                NodeResult::Result(Err(WriteError::VersionConflict { current_version }))
             }
-            None => NodeResult::Result(None),
+            NodeResult::Result(Err(error)) => NodeResult::Result(Err(error)),
         }
     }
 
-    fn node_id_from_data_key(&self, data_key: &String) -> Option<&TId> {
-        self.hash.get_resource(data_key.clone())
+    /// Version of a key, bumped by every write. Removing a key counts as a write and the key keeps
+    /// that version while this node replicates it, so versions never repeat. 0 means the key was
+    /// never written.
+    pub fn version(&self, key: &String) -> u64 {
+        match self.versions.get(key) {
+            // an expired key counts as removed even before it is swept
+            Some(version) if self.is_expired(key) => version + 1,
+            Some(version) => *version,
+            None => self.removed_version(key).unwrap_or(0),
+        }
     }
 
-    pub fn add_node(&mut self, node_id: TId) -> bool {
-        // check if node_id is already in hash
-        match self.hash.resources().any(|id| id == &node_id) {
-            true => false,
-            false => {
-                self.all_nodes.push(node_id.clone());
-                self.hash.add_resource(node_id).is_ok()
+    /// version of the removal of a key that holds no data, see `version`
+    pub fn removed_version(&self, key: &str) -> Option<u64> {
+        self.removed
+            .get(&self.placement.partition_for_key(key))
+            .and_then(|removed| removed.get(key))
+            .copied()
+    }
+
+    fn bump_version(&mut self, key: &String) {
+        let version = self.version(key) + 1;
+        self.set_version(key, Some(version));
+    }
+
+    /// Every version change goes through here to keep the merkle tree up to date, after the value
+    /// is written. Writing a key clears its removal.
+    fn set_version(&mut self, key: &str, version: Option<u64>) {
+        let partition = self.placement.partition_for_key(key);
+        let previous = match version {
+            Some(version) => {
+                let digest = self
+                    .partitions
+                    .get(&partition)
+                    .and_then(|data| data.get(key))
+                    .map_or(0, |data| (self.digest)(data));
+                self.versions
+                    .insert(key.to_string(), version)
+                    .zip(self.digests.insert(key.to_string(), digest))
             }
+            None => self.versions.remove(key).zip(self.digests.remove(key)),
+        };
+        if let Some((previous, digest)) = previous {
+            self.tree.toggle(partition, key, previous, digest);
+        }
+        if let Some(version) = version {
+            self.set_removed_version(key, None);
+            self.tree.toggle(partition, key, version, self.digests[key]);
         }
     }
 
-    pub fn remove_node(&mut self, node_id: &TId) -> bool {
-        self.all_nodes.retain(|id| id != node_id);
-        self.hash.remove_resource(node_id).is_ok()
+    /// like `set_version` for removed keys
+    fn set_removed_version(&mut self, key: &str, version: Option<u64>) {
+        let partition = self.placement.partition_for_key(key);
+        let removed = self.removed.entry(partition).or_default();
+        let previous = match version {
+            Some(version) => removed.insert(key.to_string(), version),
+            None => removed.remove(key),
+        };
+        if removed.is_empty() {
+            self.removed.remove(&partition);
+        }
+        if let Some(previous) = previous {
+            self.tree.toggle(partition, key, previous, REMOVED_DIGEST);
+        }
+        if let Some(version) = version {
+            self.tree.toggle(partition, key, version, REMOVED_DIGEST);
+        }
     }
 
-    fn get_keys_to_migrate(&self) -> Vec<&String> {
-        self.data
-            .keys()
-            .filter(|key| -> bool {
-                match self.node_id_from_data_key(key) {
-                    Some(node_id) => *node_id != self.id,
-                    None => false,
-                }
-            })
+    /// merkle tree over the keys, versions and values held by this node, replicas compare it to
+    /// find drift
+    pub fn merkle_tree(&self) -> &MerkleTree {
+        &self.tree
+    }
+
+    /// Keys of a local partition with their versions, expired keys included until they are
+    /// removed. Removed keys are listed with the version of their removal.
+    pub fn key_versions(&self, partition: PartitionId) -> Vec<(String, u64)> {
+        let removed = self.removed.get(&partition).into_iter().flatten();
+        self.partitions
+            .get(&partition)
+            .into_iter()
+            .flat_map(BTreeMap::keys)
+            .map(|key| (key.clone(), self.versions.get(key).copied().unwrap_or(0)))
+            .chain(removed.map(|(key, version)| (key.clone(), *version)))
             .collect()
     }
 
-    pub fn get_data_to_migrate(&self) -> Vec<(String, Data)> {
-        let keys_to_migrate = self.get_keys_to_migrate();
-        self.data
+    /// True when `node_id` replicates `partition` and this node replicates it too or still holds
+    /// keys of it, so the two should agree on the keys that belong to `node_id`. Ordered placements
+    /// keep every key in one partition and share none.
+    pub fn shares_partition(&self, node_id: &TId, partition: PartitionId) -> bool {
+        if self.placement.is_ordered() {
+            return false;
+        }
+        let replicas = self.placement.replicas_for(partition, self.replica_count());
+        replicas.contains(&node_id)
+            && (replicas.contains(&&self.id) || self.partitions.contains_key(&partition))
+    }
+
+    /// Local keys of `partitions` that `node_id` replicates but is missing or holds at an older
+    /// version, given the keys and versions `node_id` has there.
+    pub fn keys_behind_on(
+        &self,
+        node_id: &TId,
+        partitions: &[PartitionId],
+        remote_versions: &HashMap<String, u64>,
+    ) -> Vec<String> {
+        partitions
             .iter()
-            .filter(|(key, _)| keys_to_migrate.contains(key))
-            .map(|(key, data)| (key.clone(), data.clone()))
+            .flat_map(|partition| self.key_versions(*partition))
+            .filter(|(key, version)| {
+                remote_versions
+                    .get(key)
+                    .is_none_or(|remote| remote < version)
+                    && self.replicas_of(key).contains(&node_id)
+            })
+            .map(|(key, _)| key)
             .collect()
     }
 
-    pub fn all_nodes(&self) -> Vec<&TId> {
-        self.all_nodes.iter().collect()
+    /// Runs `action` over several local keys at once. Returns `NodeResult::NodeId` when every key
+    /// is owned by one other node and `TransactionError::CrossNode` when they are spread over several,
+    /// see hash tags for co-locating them. The action works on copies of the keys, which are written
+    /// back together only if it returns `Ok`, so an `Err` leaves every key untouched.
+    pub fn with_transaction<F, R, E>(
+        &mut self,
+        keys: Vec<String>,
+        action: F,
+    ) -> NodeResult<TId, Result<R, TransactionError<E>>>
+    where
+        F: FnOnce(&mut Transaction<Data>) -> Result<R, E>,
+    {
+        let mut owners = keys.iter().map(|key| self.node_id_from_data_key(key));
+        let owner = match owners.next() {
+            Some(Some(owner)) => owner,
+            _ => return NodeResult::Result(Err(TransactionError::NoOwner)),
+        };
+        if !owners.all(|other| other == Some(owner)) {
+            return NodeResult::Result(Err(TransactionError::CrossNode));
+        }
+        if *owner != self.id {
+            return NodeResult::NodeId(owner.clone());
+        }
+        if keys.iter().any(|key| self.locks.contains_key(key)) {
+            return NodeResult::Result(Err(TransactionError::Locked));
+        }
+
+        let mut transaction = Transaction {
+            entries: keys
+                .into_iter()
+                .map(|key| {
+                    let data = self.get_data(&key).cloned();
+                    (key, data)
+                })
+                .collect(),
+        };
+
+        match action(&mut transaction) {
+            Ok(result) => {
+                for (key, data) in transaction.entries {
+                    match data {
+                        Some(data) => self.insert_data(key, data),
+                        None => {
+                            self.take_data(&[key]);
+                        }
+                    }
+                }
+                NodeResult::Result(Ok(result))
+            }
+            Err(error) => NodeResult::Result(Err(TransactionError::Aborted(error))),
+        }
     }
 
-    pub fn size(&self) -> usize {
-        self.data.len()
+    /// owner of `key` under the current placement
+    pub fn owner_of(&self, key: &String) -> Option<&TId> {
+        self.node_id_from_data_key(key)
     }
-    // fn handle_request(request: Request) -> Response {}
-    // fn migrate_data_request()->Request{}
-    // fn on_migrate_data_request(node_id: TId, data: Vec<Data>) -> Response {}
 
-    // fn ping() {}
-    // fn on_ping_request(){}
-}
+    /// Locks `keys` for `transaction` until `expires_at`, all of them or none. Fails when a key is
+    /// owned by another node or is locked by another transaction whose lock hasn't expired at `now`.
+    /// Locked keys refuse single key writes and other transactions until the lock is released.
+    pub fn lock_keys(
+        &mut self,
+        transaction: &String,
+        keys: &[String],
+        now: u64,
+        expires_at: u64,
+    ) -> Result<(), String> {
+        for key in keys {
+            if self.is_foreign_key(key) {
+                return Err(format!("key {} is not owned by this node", key));
+            }
+            match self.locks.get(key) {
+                Some((holder, expiry)) if holder != transaction && *expiry > now => {
+                    return Err(format!("key {} is locked by transaction {}", key, holder));
+                }
+                _ => {}
+            }
+        }
+        for key in keys {
+            self.locks
+                .insert(key.clone(), (transaction.clone(), expires_at));
+        }
+        Ok(())
+    }
 
-#[derive(Debug, PartialEq)]
-pub enum NodeResult<TId, Data> {
-    NodeId(TId),
-    Result(Data),
-}
+    /// releases the locks of `transaction` and returns the keys it held
+    pub fn unlock_keys(&mut self, transaction: &String) -> Vec<String> {
+        let keys: Vec<String> = self
+            .locks
+            .iter()
+            .filter(|(_, (holder, _))| holder == transaction)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &keys {
+            self.locks.remove(key);
+        }
+        keys
+    }
 
-impl<TId, Data> NodeResult<TId, Data> {
-    pub fn or_forward_unwrap<O: FnOnce(TId) -> Data>(self, op: O) -> Data {
-        match self {
-            NodeResult::NodeId(node_id) => op(node_id),
-            NodeResult::Result(data) => data,
+    /// Extends the locks `transaction` holds on `keys` until `expires_at`. Fails without changing
+    /// anything when one of them is no longer held, for example because it expired.
+    pub fn renew_locks(
+        &mut self,
+        transaction: &String,
+        keys: &[String],
+        now: u64,
+        expires_at: u64,
+    ) -> Result<(), String> {
+        for key in keys {
+            match self.locks.get(key) {
+                Some((holder, expiry)) if holder == transaction && *expiry > now => {}
+                _ => {
+                    return Err(format!(
+                        "key {} is no longer locked by {}",
+                        key, transaction
+                    ))
+                }
+            }
+        }
+        for key in keys {
+            self.locks
+                .insert(key.clone(), (transaction.clone(), expires_at));
         }
+        Ok(())
     }
-}
 
-enum Request {
-    Migrate,
-    Ping,
-}
+    /// releases locks left behind by coordinators that never committed or aborted
+    pub fn release_expired_locks(&mut self, now: u64) -> usize {
+        let count = self.locks.len();
+        self.locks.retain(|_, (_, expires_at)| *expires_at > now);
+        count - self.locks.len()
+    }
 
-//
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub fn is_locked(&self, key: &String) -> bool {
+        self.locks.contains_key(key)
+    }
 
-    #[test]
-    fn can_add_node() {
-        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+    /// every locked key with its transaction and the expiry of the lock
+    pub fn locks(&self) -> impl Iterator<Item = (&String, &String, u64)> + '_ {
+        self.locks
+            .iter()
+            .map(|(key, (transaction, expires_at))| (key, transaction, *expires_at))
+    }
 
-        node_1.add_node("node_1".to_string());
-        assert_eq!(
-            node_1.node_id_from_data_key(&"data_key".to_string()),
-            Some(&"node_1".to_string())
-        );
+    /// locks a key again in a node rebuilt after an upgrade, see `locks`
+    pub(crate) fn restore_lock(&mut self, key: String, transaction: String, expires_at: u64) {
+        self.locks.insert(key, (transaction, expires_at));
     }
 
-    #[test]
-    fn can_remove_node() {
-        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+    /// keeps the key's ttl unless it already expired, see `insert_data_with_ttl`
+    pub fn insert_data(&mut self, key: String, data: Data) {
+        self.remove_if_expired(&key);
+        self.partitions
+            .entry(self.placement.partition_for_key(&key))
+            .or_default()
+            .insert(key.clone(), data);
+        self.bump_version(&key);
+    }
 
-        node_1.add_node("node_1".to_string());
-        node_1.remove_node(&"node_1".to_string());
-        assert_eq!(node_1.node_id_from_data_key(&"data_key".to_string()), None);
+    /// inserts data handed over by another node, keeping the version it had there
+    pub fn insert_versioned_data(&mut self, key: String, data: Data, version: u64) {
+        self.partitions
+            .entry(self.placement.partition_for_key(&key))
+            .or_default()
+            .insert(key.clone(), data);
+        self.set_version(&key, Some(version));
     }
 
-    #[test]
-    fn all_nodes() {
-        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+    /// like `insert_data` but the key expires `ttl` nanoseconds from now
+    pub fn insert_data_with_ttl(&mut self, key: String, data: Data, ttl: u64) {
+        let expires_at = (self.clock)().saturating_add(ttl);
+        self.insert_data(key.clone(), data);
+        self.set_expiry(&key, Some(expires_at));
+    }
 
-        node_1.add_node("node_1".to_string());
-        node_1.add_node("node_2".to_string());
-        node_1.add_node("node_3".to_string());
-        assert_eq!(node_1.all_nodes(), vec!["node_1", "node_2", "node_3"]);
+    /// like `with_upsert_data_mut` but the key expires `ttl` nanoseconds from now, every call
+    /// pushes the expiry back
+    pub fn with_upsert_data_mut_ttl<F, R>(
+        &mut self,
+        key: String,
+        ttl: u64,
+        action: F,
+    ) -> NodeResult<TId, Result<Option<R>, WriteError>>
+    where
+        F: FnOnce(&mut Data) -> R,
+    {
+        let expires_at = (self.clock)().saturating_add(ttl);
+        let result = self.with_upsert_data_mut(key.clone(), action);
+        if let NodeResult::Result(Ok(Some(_))) = result {
+            self.set_expiry(&key, Some(expires_at));
+        }
+        result
+    }
 
-        node_1.remove_node(&"node_2".to_string());
-        assert_eq!(node_1.all_nodes(), vec!["node_1", "node_3"]);
+    /// time source for ttls, the canister manager uses `ic::time`. Without one keys never expire.
+    pub fn set_clock(&mut self, clock: fn() -> u64) {
+        self.clock = clock;
     }
 
-    #[test]
-    fn data_is_distributed_on_different_nodes() {
-        let mut index_node = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+    /// Hash of a value for the merkle tree, so replicas holding different values at the same
+    /// version disagree. The canister manager hashes the candid encoding. Set it before any write.
+    pub fn set_digest(&mut self, digest: fn(&Data) -> u64) {
+        self.digest = digest;
+    }
 
-        index_node.add_node("index_node_id".to_string());
-        index_node.add_node("node_1".to_string());
+    /// sets when a local key expires, None keeps it forever. Returns false if the key holds no data.
+    pub fn set_expiry(&mut self, key: &String, expires_at: Option<u64>) -> bool {
+        if self.get_data(key).is_none() {
+            return false;
+        }
+        self.clear_expiry(key);
+        if let Some(expires_at) = expires_at {
+            self.expiries.insert(key.clone(), expires_at);
+            self.expiry_queue.insert((expires_at, key.clone()));
+        }
+        true
+    }
 
-        let mut node_ids: Vec<String> = vec![];
+    pub fn expires_at(&self, key: &String) -> Option<u64> {
+        self.expiries.get(key).copied()
+    }
 
-        for id in 1..15 {
-            let data_key = format!("data_key_{}", id);
-            let node_id = index_node.node_id_from_data_key(&data_key).unwrap();
-            node_ids.push(node_id.clone());
+    fn clear_expiry(&mut self, key: &str) {
+        if let Some(expires_at) = self.expiries.remove(key) {
+            self.expiry_queue.remove(&(expires_at, key.to_string()));
         }
+    }
 
-        // "index_node_id" and "node_1" should exist in node_ids
-        assert!(node_ids.contains(&"node_1".to_string()));
-        assert!(node_ids.contains(&"index_node_id".to_string()));
+    fn is_expired(&self, key: &str) -> bool {
+        self.expiries
+            .get(key)
+            .is_some_and(|expires_at| *expires_at <= (self.clock)())
+    }
 
-        //node_1 should exist atleast 4 times in node_ids, Iterator::filter()
-        let node_1_count = node_ids
-            .iter()
-            .filter(|&x| x == &"node_1".to_string())
-            .count();
-        assert!(node_1_count >= 4);
+    fn remove_if_expired(&mut self, key: &String) {
+        if self.is_expired(key) {
+            self.take_data(std::slice::from_ref(key));
+        }
+    }
 
-        //same with index_node_id
-        let index_node_id_count = node_ids
+    /// Removes up to `limit` expired keys, soonest expiry first, and returns how many were removed.
+    /// Only expired keys are visited, so the work done is bounded by `limit`.
+    pub fn remove_expired(&mut self, limit: usize) -> usize {
+        if self.expiry_queue.is_empty() {
+            return 0;
+        }
+        let now = (self.clock)();
+        let expired: Vec<String> = self
+            .expiry_queue
             .iter()
-            .filter(|&x| x == &"index_node_id".to_string())
-            .count();
-        assert!(index_node_id_count >= 4);
+            .take_while(|(expires_at, _)| *expires_at <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect();
+        self.take_data(&expired);
+        expired.len()
     }
-    #[test]
-    fn node_id_from_data_key_must_be_deterministic() {
-        let mut node_ids: Vec<String> = vec![];
-
-        for _ in 1..100 {
-            let mut index_node =
-                Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
-
-            index_node.add_node("index_node_id".to_string());
-            index_node.add_node("node_1".to_string());
 
-            let data_key = "data_key".to_string();
-            let node_id = index_node.node_id_from_data_key(&data_key).unwrap();
-            node_ids.push(node_id.clone());
+    /// same functionality as with_data_mut but keys not in node are added
+    pub fn with_upsert_data_mut<'a, F, R>(
+        &mut self,
+        key: String,
+        action: F,
+    ) -> NodeResult<TId, Result<Option<R>, WriteError>>
+    where
+        F: FnOnce(&mut Data) -> R,
+    {
+        match self.node_id_from_data_key(&key) {
+            Some(node_id) => {
+                if node_id.clone() == self.id {
+                    if self.is_locked(&key) {
+                        return NodeResult::Result(Err(WriteError::Locked));
+                    }
+                    self.remove_if_expired(&key);
+                    let data = self
+                        .partitions
+                        .entry(self.placement.partition_for_key(&key))
+                        .or_default()
+                        .entry(key.clone())
+                        .or_default();
+                    let result = action(data);
+                    self.bump_version(&key);
+                    NodeResult::Result(Ok(Some(result)))
+                } else {
+                    NodeResult::NodeId(node_id.clone())
+                }
+            }
+            None => NodeResult::Result(Ok(None)),
         }
-        //all ids should be same
-        assert_eq!(node_ids.iter().collect::<HashSet<_>>().len(), 1);
     }
 
-    #[test]
-    fn node_id_from_data_key_must_not_change_due_to_outoforder_node_insertions() {
-        let mut node_ids_1: Vec<String> = vec![];
-        let mut node_ids_2: Vec<String> = vec![];
+    /// partition a key is stored in under hashed placements, only the key's hash tag is hashed
+    pub fn partition_from_data_key(data_key: &str) -> PartitionId {
+        hashed_partition(data_key)
+    }
 
-        for id in 1..100 {
-            let mut index_node =
-                Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+    fn node_id_from_data_key(&self, data_key: &String) -> Option<&TId> {
+        self.placement.node_for_key(data_key)
+    }
 
-            index_node.add_node("index_node_id".to_string());
-            index_node.add_node("node_1".to_string());
+    /// true when this node is not among the key's replicas and should hand it over
+    pub fn is_foreign_key(&self, data_key: &str) -> bool {
+        let replicas = self.replicas_of(data_key);
+        !replicas.is_empty() && !replicas.contains(&&self.id)
+    }
 
-            let data_key = &format!("data_key_{}", id);
-            let node_id = index_node.node_id_from_data_key(&data_key).unwrap();
-            node_ids_1.push(node_id.clone());
-        }
+    fn is_foreign_partition(&self, partition: PartitionId) -> bool {
+        let replicas = self.placement.replicas_for(partition, self.replica_count());
+        !replicas.is_empty() && !replicas.contains(&&self.id)
+    }
 
-        for id in 1..100 {
-            let mut index_node_2 =
-                Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+    /// Number of nodes that hold a copy of every key, 1 by default. Writes are served by the
+    /// key's owner and copied to the next distinct nodes of the placement by the canister manager,
+    /// every node of a cluster must use the same factor.
+    pub fn set_replication_factor(&mut self, replication_factor: usize) {
+        self.replication_factor = replication_factor.max(1);
+    }
 
-            index_node_2.add_node("node_1".to_string());
-            index_node_2.add_node("index_node_id".to_string());
+    pub fn replication_factor(&self) -> usize {
+        self.replication_factor
+    }
 
-            let data_key = &format!("data_key_{}", id);
-            let node_id = index_node_2.node_id_from_data_key(&data_key).unwrap();
-            node_ids_2.push(node_id.clone());
-        }
+    /// nodes holding copies of `key`, its owner first
+    pub fn replicas_of(&self, key: &str) -> Vec<&TId> {
+        self.placement.replicas_for_key(key, self.replica_count())
+    }
+
+    fn replica_count(&self) -> usize {
+        self.replication_factor.min(self.all_nodes.len().max(1))
+    }
+
+    /// returns false without changing anything if the node is already placed
+    pub fn add_node(&mut self, node_id: TId) -> bool {
+        self.change_placement(vec![PlacementChange::Add(node_id)])
+    }
+
+    /// adds `node_id` as the owner of the keys from `range_start` up to the next range,
+    /// ordered placements only
+    pub fn add_node_at(&mut self, node_id: TId, range_start: String) -> bool {
+        self.change_placement(vec![PlacementChange::AddAt(node_id, range_start)])
+    }
+
+    pub fn placement_is_ordered(&self) -> bool {
+        self.placement.is_ordered()
+    }
+
+    pub fn range_start(&self, node_id: &TId) -> Option<String> {
+        self.placement.range_start(node_id)
+    }
+
+    /// median of the keys this node owns, where its range is split when scaling up in ordered mode
+    pub fn split_key(&self) -> Option<String> {
+        if !self.placement.is_ordered() {
+            return None;
+        }
+        let owned_keys: Vec<&String> = self
+            .partitions
+            .values()
+            .flat_map(|data| data.keys())
+            .filter(|key| self.node_id_from_data_key(key) == Some(&self.id))
+            .collect();
+        match owned_keys.len() {
+            0 | 1 => None,
+            len => Some(owned_keys[len / 2].clone()),
+        }
+    }
+
+    /// adds the node with its weight as one membership change
+    pub fn add_weighted_node(&mut self, node_id: TId, weight: u32) -> bool {
+        match weight {
+            1 => self.change_placement(vec![PlacementChange::Add(node_id)]),
+            _ => self.change_placement(vec![
+                PlacementChange::Add(node_id.clone()),
+                PlacementChange::SetWeight(node_id, weight),
+            ]),
+        }
+    }
+
+    pub fn remove_node(&mut self, node_id: &TId) -> bool {
+        self.change_placement(vec![PlacementChange::Remove(node_id.clone())])
+    }
+
+    /// Changes the share of partitions `node_id` owns, callers migrate the partitions that change
+    /// owner. Returns false without changing anything if the node already has this weight.
+    pub fn set_weight(&mut self, node_id: &TId, weight: u32) -> bool {
+        if self.placement.contains(node_id) && self.weight(node_id) == weight {
+            return false;
+        }
+        self.change_placement(vec![PlacementChange::SetWeight(node_id.clone(), weight)])
+    }
+
+    /// Applies `changes` to a copy of the placement and keeps the copy only if all of them
+    /// succeeded, so a refused change leaves the node, its epoch and its hand-off untouched.
+    fn change_placement(&mut self, changes: Vec<PlacementChange<TId>>) -> bool {
+        let mut placement = self.placement.clone();
+        if !changes.iter().all(|change| change.apply(&mut placement)) {
+            return false;
+        }
+        let previous = std::mem::replace(&mut self.placement, placement);
+        self.epoch += 1;
+        if !self.all_nodes.is_empty() {
+            self.previous_placements
+                .push((self.history.len(), previous));
+        }
+        for change in changes {
+            self.record(change);
+        }
+        true
+    }
+
+    /// follows an applied change in the members, their weights and the history
+    fn record(&mut self, change: PlacementChange<TId>) {
+        match &change {
+            PlacementChange::Add(node_id) | PlacementChange::AddAt(node_id, _) => {
+                self.all_nodes.push(node_id.clone())
+            }
+            PlacementChange::SetWeight(node_id, 1) => {
+                self.weights.remove(node_id);
+            }
+            PlacementChange::SetWeight(node_id, weight) => {
+                self.weights.insert(node_id.clone(), *weight);
+            }
+            PlacementChange::Remove(node_id) => {
+                self.all_nodes.retain(|id| id != node_id);
+                self.weights.remove(node_id);
+            }
+        }
+        self.history.push(change);
+    }
+
+    /// Every change applied to the placement, in order. Placements depend on the order of their
+    /// changes and not only on the resulting members and weights, so a node built elsewhere only
+    /// agrees with this one on the owner of every key once it replayed them, see `restore_placement`.
+    pub fn placement_history(&self) -> &[PlacementChange<TId>] {
+        &self.history
+    }
+
+    /// Rebuilds the placement of a node created without members by applying `history` in order.
+    /// The changes at the positions in `handoffs` start a hand-off like live changes do, see
+    /// `handoffs`. Returns false if a change was refused, which stops the replay.
+    pub fn restore_placement(
+        &mut self,
+        history: Vec<PlacementChange<TId>>,
+        handoffs: &[usize],
+    ) -> bool {
+        for (position, change) in history.into_iter().enumerate() {
+            if handoffs.contains(&position) && !self.all_nodes.is_empty() {
+                self.previous_placements
+                    .push((position, self.placement.clone()));
+            }
+            if !change.apply(&mut self.placement) {
+                return false;
+            }
+            self.record(change);
+        }
+        true
+    }
+
+    /// counts the membership changes this node has applied, it changes whenever ownership may have
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// sets the epoch of a node rebuilt after an upgrade, whose members were added again
+    pub(crate) fn restore_epoch(&mut self, epoch: u64) {
+        self.epoch = epoch;
+    }
+
+    /// True while keys may still be on the owners they had before a membership change. Every
+    /// membership change starts a hand-off or joins the running one, `finish_handoff` ends it.
+    pub fn is_handing_off(&self) -> bool {
+        !self.previous_placements.is_empty()
+    }
+
+    /// forgets the previous placements once every key has reached its new owner
+    pub fn finish_handoff(&mut self) {
+        self.previous_placements.clear();
+    }
+
+    /// positions in `placement_history` of the changes made since the hand-off started
+    pub fn handoffs(&self) -> Vec<usize> {
+        self.previous_placements
+            .iter()
+            .map(|(position, _)| *position)
+            .collect()
+    }
+
+    /// Nodes to fetch `key` from during a hand-off when this node has become one of the key's
+    /// replicas but doesn't hold it yet: its owners before each change of the hand-off, newest
+    /// first, since a key moved by several changes may still sit on any of them.
+    pub fn handoff_sources(&self, key: &str) -> Vec<&TId> {
+        let holds_key = self
+            .partitions
+            .get(&self.placement.partition_for_key(key))
+            .is_some_and(|data| data.contains_key(key))
+            && !self.is_expired(key);
+        if holds_key || self.is_foreign_key(key) {
+            return vec![];
+        }
+        let mut sources: Vec<&TId> = vec![];
+        for (_, placement) in self.previous_placements.iter().rev() {
+            match placement.node_for_key(key) {
+                Some(owner) if *owner != self.id && !sources.contains(&owner) => {
+                    sources.push(owner)
+                }
+                _ => {}
+            }
+        }
+        sources
+    }
+
+    pub fn weight(&self, node_id: &TId) -> u32 {
+        self.weights.get(node_id).copied().unwrap_or(1)
+    }
+
+    /// keys no longer owned by this node, only foreign partitions are visited
+    pub fn get_keys_to_migrate(&self) -> Vec<String> {
+        self.get_data_to_migrate()
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Keys to send to other nodes, grouped by receiver: keys no longer held here go to every one
+    /// of their replicas. With a replication factor above 1 the keys still held here are re-sent
+    /// to their other replicas too, since those may have just joined, so a receiver can get the
+    /// same key from several of its replicas.
+    pub fn get_keys_to_migrate_by_node(&self) -> HashMap<TId, Vec<String>> {
+        match self.replication_factor {
+            1 => self.misplaced_keys(usize::MAX),
+            _ => self.group_by_replicas(self.partitions.values().flat_map(BTreeMap::keys)),
+        }
+    }
+
+    /// Keys held here that this node doesn't replicate, left behind by failed migrations, grouped
+    /// by the nodes that should hold them. Writes to them are forwarded to owners that may not
+    /// have them. Stops after `limit` keys without visiting the rest.
+    pub fn misplaced_keys(&self, limit: usize) -> HashMap<TId, Vec<String>> {
+        self.group_by_replicas(self.get_data_to_migrate().map(|(key, _)| key).take(limit))
+    }
+
+    fn group_by_replicas<'k>(
+        &self,
+        keys: impl Iterator<Item = &'k String>,
+    ) -> HashMap<TId, Vec<String>> {
+        let mut keys_by_node: HashMap<TId, Vec<String>> = HashMap::new();
+        for key in keys {
+            for node_id in self.replicas_of(key) {
+                if *node_id != self.id {
+                    keys_by_node
+                        .entry(node_id.clone())
+                        .or_default()
+                        .push(key.clone());
+                }
+            }
+        }
+        keys_by_node
+    }
+
+    /// borrows every entry no longer owned by this node without cloning the values.
+    /// Hashed placements skip owned partitions, ordered placements check every key.
+    pub fn get_data_to_migrate(&self) -> impl Iterator<Item = (&String, &Data)> + '_ {
+        let ordered = self.placement.is_ordered();
+        self.partitions
+            .iter()
+            .filter(move |(partition, _)| ordered || self.is_foreign_partition(**partition))
+            .flat_map(|(_, data)| data.iter())
+            .filter(move |(key, _)| !ordered || self.is_foreign_key(key))
+    }
+
+    /// expired keys are treated as missing
+    pub fn get_data(&self, key: &String) -> Option<&Data> {
+        if self.is_expired(key) {
+            return None;
+        }
+        self.partitions
+            .get(&self.placement.partition_for_key(key))
+            .and_then(|data| data.get(key))
+    }
+
+    fn get_data_mut(&mut self, key: &String) -> Option<&mut Data> {
+        self.remove_if_expired(key);
+        self.partitions
+            .get_mut(&self.placement.partition_for_key(key))
+            .and_then(|data| data.get_mut(key))
+    }
+
+    /// moves the given entries out of the node, used once their hand-off has been acknowledged
+    pub fn take_data(&mut self, keys: &[String]) -> Vec<(String, Data)> {
+        self.take_keys(keys.iter())
+    }
+
+    /// like `take_data` but keeps the keys this node is still a replica of
+    pub fn take_foreign_data(&mut self, keys: &[String]) -> Vec<(String, Data)> {
+        let foreign_keys: Vec<&String> =
+            keys.iter().filter(|key| self.is_foreign_key(key)).collect();
+        self.take_keys(foreign_keys.into_iter())
+    }
+
+    /// the removal bumps the version of the keys this node still replicates, see `version`
+    fn take_keys<'k>(&mut self, keys: impl Iterator<Item = &'k String>) -> Vec<(String, Data)> {
+        let taken = keys
+            .filter_map(|key| {
+                let taken = self
+                    .partitions
+                    .get_mut(&self.placement.partition_for_key(key))
+                    .and_then(|data| data.remove_entry(key));
+                let removed_version = match self.is_foreign_key(key) {
+                    true => None,
+                    false if taken.is_some() => self.versions.get(key).map(|version| version + 1),
+                    false => self.removed_version(key),
+                };
+                self.set_version(key, None);
+                self.set_removed_version(key, removed_version);
+                self.clear_expiry(key);
+                taken
+            })
+            .collect();
+        self.partitions.retain(|_, data| !data.is_empty());
+        taken
+    }
+
+    /// Removes a key that was removed on another node at `version`, unless this node holds a
+    /// newer write of it. Does nothing for keys this node doesn't replicate.
+    pub fn remove_versioned_data(&mut self, key: &String, version: u64) {
+        if version <= self.version(key) || self.is_foreign_key(key) {
+            return;
+        }
+        self.take_keys(std::iter::once(key));
+        self.set_removed_version(key, Some(version));
+    }
+
+    /// number of keys in every non-empty local partition, ordered by partition id
+    pub fn partition_sizes(&self) -> Vec<(PartitionId, usize)> {
+        let mut sizes: Vec<_> = self
+            .partitions
+            .iter()
+            .map(|(partition, data)| (*partition, data.len()))
+            .collect();
+        sizes.sort_unstable();
+        sizes
+    }
+
+    pub fn all_nodes(&self) -> Vec<&TId> {
+        self.all_nodes.iter().collect()
+    }
+
+    pub fn size(&self) -> usize {
+        self.partitions.values().map(BTreeMap::len).sum()
+    }
+    // fn handle_request(request: Request) -> Response {}
+    // fn migrate_data_request()->Request{}
+    // fn on_migrate_data_request(node_id: TId, data: Vec<Data>) -> Response {}
+
+    // fn ping() {}
+    // fn on_ping_request(){}
+}
+
+impl<TId, Data, P> Node<TId, Data, P>
+where
+    TId: Eq + Hash + Clone,
+    Data: Default + Clone + Merge,
+    P: Placement<TId>,
+{
+    /// Like `insert_versioned_data`, but a local value of the key is merged with the incoming one
+    /// and the key keeps the higher version, see `Merge`. Data older than a local removal of the
+    /// key is dropped.
+    pub fn merge_versioned_data(&mut self, key: String, data: Data, version: u64) {
+        let local_version = self.version(&key);
+        if self.get_data(&key).is_none() && version <= local_version {
+            return;
+        }
+        let data = match self.get_data_mut(&key) {
+            Some(local) => Data::merge(
+                Versioned {
+                    value: std::mem::take(local),
+                    version: local_version,
+                },
+                Versioned {
+                    value: data,
+                    version,
+                },
+            ),
+            None => data,
+        };
+        self.insert_versioned_data(key, data, version.max(local_version));
+    }
+}
+
+impl<TId, Data, P> Node<TId, Data, P>
+where
+    TId: Eq + Hash + Clone,
+    Data: Default + Clone,
+    P: Placement<TId>,
+{
+    /// Up to `limit` entries with keys in `[start, end)`, in key order, from the node owning `start`.
+    /// The cursor is the `start` of the next page: the next local key once `limit` is reached,
+    /// otherwise the start of the following range. It is None once the scan has reached `end`.
+    /// Only ordered placements keep keys in order, other placements return an empty page.
+    pub fn scan(
+        &self,
+        start: &String,
+        end: Option<&String>,
+        limit: usize,
+    ) -> NodeResult<TId, ScanPage<Data>> {
+        if !self.placement.is_ordered() {
+            return NodeResult::Result(ScanPage::default());
+        }
+        match self.node_id_from_data_key(start) {
+            Some(node_id) if *node_id != self.id => return NodeResult::NodeId(node_id.clone()),
+            None => return NodeResult::Result(ScanPage::default()),
+            _ => {}
+        }
+
+        let range_end = self.placement.next_range_start(start);
+        let upper = match (end, range_end) {
+            (Some(end), Some(range_end)) => Some(end.min(range_end)),
+            (end, range_end) => end.or(range_end),
+        };
+
+        let mut entries: Vec<(String, Data)> = self
+            .partitions
+            .get(&0)
+            .into_iter()
+            .flat_map(|data| data.range::<String, _>(start..))
+            .take_while(|(key, _)| upper.is_none_or(|upper| *key < upper))
+            .filter(|(key, _)| !self.is_expired(key))
+            .take(limit + 1)
+            .map(|(key, data)| (key.clone(), data.clone()))
+            .collect();
+
+        let cursor = if entries.len() > limit {
+            entries.pop().map(|(key, _)| key)
+        } else {
+            range_end
+                .filter(|range_end| end.is_none_or(|end| *range_end < end))
+                .cloned()
+        };
+
+        NodeResult::Result(ScanPage { entries, cursor })
+    }
+}
+
+/// copies of the keys of a `Node::with_transaction` call, only the declared keys are accessible
+pub struct Transaction<Data> {
+    entries: HashMap<String, Option<Data>>,
+}
+
+impl<Data> Transaction<Data> {
+    /// a transaction over `entries`, used by coordinators that collect the keys from several nodes
+    pub fn new(entries: HashMap<String, Option<Data>>) -> Self {
+        Transaction { entries }
+    }
+
+    pub fn into_entries(self) -> HashMap<String, Option<Data>> {
+        self.entries
+    }
+}
+
+impl<Data: Default> Transaction<Data> {
+    pub fn get(&self, key: &str) -> Option<&Data> {
+        self.entries.get(key).and_then(Option::as_ref)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Data> {
+        self.entries.get_mut(key).and_then(Option::as_mut)
+    }
+
+    /// like `get_mut` but a declared key without data starts from `Data::default()`
+    pub fn upsert(&mut self, key: &str) -> Option<&mut Data> {
+        self.entries
+            .get_mut(key)
+            .map(|data| data.get_or_insert_with(Default::default))
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Data> {
+        self.entries.get_mut(key).and_then(Option::take)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TransactionError<E> {
+    /// the keys are owned by more than one node
+    CrossNode,
+    /// no keys were given or no node is placed
+    NoOwner,
+    /// the action returned an error and nothing was written
+    Aborted(E),
+    /// a key is prepared by a cross-node transaction
+    Locked,
+    /// a node refused or failed to lock its keys or lost them before the commit, nothing was written
+    Prepare(String),
+    /// Nodes failed to apply the commit after every node renewed its locks, each listed as
+    /// `node: error`. The other nodes applied their writes. A listed node whose commit never
+    /// arrived keeps its old values and releases the locks once they expire.
+    Commit(String),
+}
+
+/// A membership change of the placement. Nodes applying the same changes in the same order agree
+/// on the owner of every key, see `Node::placement_history`.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub enum PlacementChange<TId> {
+    Add(TId),
+    /// ordered placements only, see `Node::add_node_at`
+    AddAt(TId, String),
+    SetWeight(TId, u32),
+    Remove(TId),
+}
+
+impl<TId: Clone> PlacementChange<TId> {
+    fn apply<P: Placement<TId>>(&self, placement: &mut P) -> bool {
+        match self {
+            PlacementChange::Add(node_id) => placement.add_node(node_id.clone()),
+            PlacementChange::AddAt(node_id, range_start) => {
+                placement.split_at(node_id.clone(), range_start.clone())
+            }
+            PlacementChange::SetWeight(node_id, weight) => placement.set_weight(node_id, *weight),
+            PlacementChange::Remove(node_id) => placement.remove_node(node_id),
+        }
+    }
+}
+
+/// a read value and the version of the key it was read from
+#[derive(Debug, PartialEq)]
+pub struct Versioned<T> {
+    pub value: T,
+    pub version: u64,
+}
+
+/// why a single key write was refused, nothing was written
+#[derive(Debug, PartialEq)]
+pub enum WriteError {
+    /// the key is prepared by a cross-node transaction, the write can be retried once it ends
+    Locked,
+    /// `with_data_mut_if_version` expected another version than the key's current one
+    VersionConflict { current_version: u64 },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScanPage<Data> {
+    pub entries: Vec<(String, Data)>,
+    pub cursor: Option<String>,
+}
+
+impl<Data> Default for ScanPage<Data> {
+    fn default() -> Self {
+        ScanPage {
+            entries: vec![],
+            cursor: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum NodeResult<TId, Data> {
+    NodeId(TId),
+    Result(Data),
+}
+
+impl<TId, Data> NodeResult<TId, Data> {
+    pub fn or_forward_unwrap<O: FnOnce(TId) -> Data>(self, op: O) -> Data {
+        match self {
+            NodeResult::NodeId(node_id) => op(node_id),
+            NodeResult::Result(data) => data,
+        }
+    }
+}
+
+enum Request {
+    Migrate,
+    Ping,
+}
+
+//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::placement::KeyRangePlacement;
+
+    #[test]
+    fn can_add_node() {
+        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+
+        node_1.add_node("node_1".to_string());
+        assert_eq!(
+            node_1.node_id_from_data_key(&"data_key".to_string()),
+            Some(&"node_1".to_string())
+        );
+    }
+
+    #[test]
+    fn can_remove_node() {
+        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+
+        node_1.add_node("node_1".to_string());
+        node_1.remove_node(&"node_1".to_string());
+        assert_eq!(node_1.node_id_from_data_key(&"data_key".to_string()), None);
+    }
+
+    #[test]
+    fn all_nodes() {
+        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+
+        node_1.add_node("node_1".to_string());
+        node_1.add_node("node_2".to_string());
+        node_1.add_node("node_3".to_string());
+        assert_eq!(node_1.all_nodes(), vec!["node_1", "node_2", "node_3"]);
+
+        node_1.remove_node(&"node_2".to_string());
+        assert_eq!(node_1.all_nodes(), vec!["node_1", "node_3"]);
+    }
+
+    #[test]
+    fn data_is_distributed_on_different_nodes() {
+        let mut index_node = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+
+        index_node.add_node("index_node_id".to_string());
+        index_node.add_node("node_1".to_string());
+
+        let mut node_ids: Vec<String> = vec![];
+
+        for id in 1..15 {
+            let data_key = format!("data_key_{}", id);
+            let node_id = index_node.node_id_from_data_key(&data_key).unwrap();
+            node_ids.push(node_id.clone());
+        }
+
+        // "index_node_id" and "node_1" should exist in node_ids
+        assert!(node_ids.contains(&"node_1".to_string()));
+        assert!(node_ids.contains(&"index_node_id".to_string()));
+
+        //node_1 should exist atleast 4 times in node_ids, Iterator::filter()
+        let node_1_count = node_ids
+            .iter()
+            .filter(|&x| x == &"node_1".to_string())
+            .count();
+        assert!(node_1_count >= 4);
+
+        //same with index_node_id
+        let index_node_id_count = node_ids
+            .iter()
+            .filter(|&x| x == &"index_node_id".to_string())
+            .count();
+        assert!(index_node_id_count >= 4);
+    }
+    #[test]
+    fn node_id_from_data_key_must_be_deterministic() {
+        let mut node_ids: Vec<String> = vec![];
+
+        for _ in 1..100 {
+            let mut index_node =
+                Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+
+            index_node.add_node("index_node_id".to_string());
+            index_node.add_node("node_1".to_string());
+
+            let data_key = "data_key".to_string();
+            let node_id = index_node.node_id_from_data_key(&data_key).unwrap();
+            node_ids.push(node_id.clone());
+        }
+        //all ids should be same
+        assert_eq!(node_ids.iter().collect::<HashSet<_>>().len(), 1);
+    }
+
+    #[test]
+    fn node_id_from_data_key_must_not_change_due_to_outoforder_node_insertions() {
+        let mut node_ids_1: Vec<String> = vec![];
+        let mut node_ids_2: Vec<String> = vec![];
+
+        for id in 1..100 {
+            let mut index_node =
+                Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+
+            index_node.add_node("index_node_id".to_string());
+            index_node.add_node("node_1".to_string());
+
+            let data_key = &format!("data_key_{}", id);
+            let node_id = index_node.node_id_from_data_key(&data_key).unwrap();
+            node_ids_1.push(node_id.clone());
+        }
+
+        for id in 1..100 {
+            let mut index_node_2 =
+                Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+
+            index_node_2.add_node("node_1".to_string());
+            index_node_2.add_node("index_node_id".to_string());
+
+            let data_key = &format!("data_key_{}", id);
+            let node_id = index_node_2.node_id_from_data_key(&data_key).unwrap();
+            node_ids_2.push(node_id.clone());
+        }
 
         assert_eq!(node_ids_1, node_ids_2);
     }
 
     #[test]
-    fn with_data_mut_returns_result_none_when_key_maps_to_node_but_data_not_available() {
-        let mut node_1 = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+    fn with_data_mut_returns_result_none_when_key_maps_to_node_but_data_not_available() {
+        let mut node_1 = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+
+        node_1.add_node("index_node_id".to_string());
+
+        let result = node_1.with_data_mut("data_key".to_string(), |data| {
+            data.push_str("data");
+            data.clone()
+        });
+
+        assert_eq!(result, NodeResult::Result(Ok(None)));
+    }
+
+    #[test]
+    fn with_data_mut_returns_result_when_key_maps_to_node_and_data_available() {
+        let mut node_1 = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+
+        node_1.add_node("index_node_id".to_string());
+        node_1.insert_data("data_key".to_string(), "data".to_string());
+
+        let result = node_1.with_data_mut("data_key".to_string(), |data| data.clone());
+
+        assert_eq!(result, NodeResult::Result(Ok(Some("data".to_string()))));
+    }
+
+    #[test]
+    fn with_data_mut_returns_node_id_when_key_maps_to_different_node() {
+        let mut node_1 = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+
+        node_1.add_node("index_node_id".to_string());
+        node_1.add_node("node_1".to_string());
+
+        node_1.insert_data("data_key".to_string(), "data".to_string());
+        node_1.insert_data("data_key_2".to_string(), "data_2".to_string());
+
+        let result = node_1.with_data_mut("data_key_5".to_string(), |data| data.clone());
+
+        assert_eq!(result, NodeResult::NodeId("node_1".to_string()));
+    }
+
+    #[test]
+    fn get_keys_to_migrate_returns_added_nodes_ids() {
+        let mut index_node = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+
+        index_node.add_node("index_node_id".to_string());
+
+        let mut all_keys = vec![];
+        for id in 0..10 {
+            let key = &format!("data_key_{}", id);
+            all_keys.push(key.clone());
+            index_node.with_upsert_data_mut(key.clone(), |data| {
+                data.push_str("data");
+            });
+        }
+
+        assert_eq!(index_node.size(), 10);
+        // assert!(node_1.size() >= 4);
+        index_node.add_node("node_1".to_string());
+
+        let keys_to_migrate = index_node.get_keys_to_migrate();
+        assert_eq!(
+            keys_to_migrate.len() >= 4 && keys_to_migrate.len() < 8,
+            true
+        );
+
+        assert!(keys_to_migrate
+            .iter()
+            .all(|key| index_node.node_id_from_data_key(key).unwrap() == &"node_1".to_string()));
+    }
+
+    #[test]
+    fn get_keys_to_migrate_returns_deleted_nodes_ids() {
+        let mut index_node = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+
+        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+
+        index_node.add_node("index_node_id".into());
+        index_node.add_node("node_1".into());
+
+        node_1.add_node("index_node_id".into());
+        node_1.add_node("node_1".into());
+
+        let mut all_keys = vec![];
+        for id in 0..10 {
+            let key = &format!("data_key_{}", id);
+            all_keys.push(key.clone());
+            index_node
+                .with_upsert_data_mut(key.clone(), |data| {
+                    data.push_str("data");
+                })
+                .or_forward_unwrap(|node_id| {
+                    if node_id == "node_1".to_string() {
+                        node_1.with_upsert_data_mut(key.clone(), |data| {
+                            data.push_str("data");
+                        });
+                        Ok(Some(()))
+                    } else {
+                        Ok(None)
+                    }
+                })
+                .unwrap();
+        }
+        //evenly distributed
+        assert_eq!(index_node.size(), 5);
+        assert_eq!(node_1.size(), 5);
+        //let's see what happens when we delete one node
+        node_1.remove_node(&"node_1".into());
+
+        let keys_to_migrate = node_1.get_keys_to_migrate();
+
+        assert!(keys_to_migrate
+            .iter()
+            .all(|key| node_1.node_id_from_data_key(key).unwrap() == &"index_node_id".to_string()));
+    }
+
+    #[test]
+    fn get_data_to_migrate_borrows_only_foreign_entries() {
+        let mut index_node = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+        index_node.add_node("index_node_id".to_string());
+
+        for id in 0..100 {
+            index_node.insert_data(format!("data_key_{}", id), format!("data_{}", id));
+        }
+        index_node.add_node("node_1".to_string());
+
+        let data_to_migrate: Vec<_> = index_node.get_data_to_migrate().collect();
+
+        assert_eq!(
+            data_to_migrate.len(),
+            index_node.get_keys_to_migrate().len()
+        );
+        assert!(data_to_migrate.iter().all(|(key, data)| {
+            index_node.node_id_from_data_key(key).unwrap() == "node_1"
+                && data.as_str() == format!("data_{}", &key["data_key_".len()..])
+        }));
+    }
+
+    #[test]
+    fn take_data_moves_migrated_entries_out() {
+        let mut index_node = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+        index_node.add_node("index_node_id".to_string());
+
+        for id in 0..100 {
+            index_node.insert_data(format!("data_key_{}", id), "data".to_string());
+        }
+        index_node.add_node("node_1".to_string());
+
+        let keys_to_migrate = index_node.get_keys_to_migrate();
+        let taken = index_node.take_data(&keys_to_migrate);
+
+        assert_eq!(taken.len(), keys_to_migrate.len());
+        assert_eq!(index_node.size(), 100 - keys_to_migrate.len());
+        assert_eq!(index_node.get_data_to_migrate().count(), 0);
+    }
+
+    #[test]
+    fn keys_migrate_with_their_whole_partition() {
+        let mut index_node = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+        index_node.add_node("index_node_id".to_string());
+
+        for id in 0..1000 {
+            index_node.insert_data(format!("data_key_{}", id), "data".to_string());
+        }
+        index_node.add_node("node_1".to_string());
+
+        let keys_to_migrate = index_node.get_keys_to_migrate();
+        let migrated_partitions: HashSet<PartitionId> = keys_to_migrate
+            .iter()
+            .map(|key| Node::<String, String>::partition_from_data_key(key))
+            .collect();
+        assert!(!migrated_partitions.is_empty());
+        index_node.take_data(&keys_to_migrate);
+
+        // no key of a migrated partition stays behind
+        assert_eq!(index_node.size(), 1000 - keys_to_migrate.len());
+        assert!(index_node
+            .partition_sizes()
+            .iter()
+            .all(|(partition, _)| !migrated_partitions.contains(partition)));
+    }
+
+    #[test]
+    fn partition_sizes_add_up_to_size() {
+        let mut index_node = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+        index_node.add_node("index_node_id".to_string());
+
+        for id in 0..500 {
+            index_node.insert_data(format!("data_key_{}", id), "data".to_string());
+        }
+
+        let partition_sizes = index_node.partition_sizes();
+
+        assert!(partition_sizes
+            .iter()
+            .all(|(partition, _)| *partition < PARTITION_COUNT));
+        assert!(partition_sizes.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(
+            partition_sizes.iter().map(|(_, size)| size).sum::<usize>(),
+            index_node.size()
+        );
+    }
+
+    #[test]
+    fn node_routes_through_its_placement() {
+        use crate::placement::RangePlacement;
+
+        let mut node_1 = Node::<_, String, _>::with_placement(
+            "node_1".to_string(),
+            RangePlacement::new(Vec::<String>::new()),
+        );
+        node_1.add_node("node_1".to_string());
+        node_1.add_node("node_2".to_string());
+
+        assert_eq!(node_1.all_nodes(), vec!["node_1", "node_2"]);
+        for id in 0..100 {
+            let key = format!("data_key_{}", id);
+            let expected = if Node::<String, String>::partition_from_data_key(&key) < 512 {
+                "node_1"
+            } else {
+                "node_2"
+            };
+            assert_eq!(node_1.node_id_from_data_key(&key).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn node_keeps_routing_past_initial_capacity() {
+        let mut node_1 = Node::<_, String>::with_capacity("node_1".to_string(), HashSet::new(), 1);
 
-        node_1.add_node("index_node_id".to_string());
+        for id in 1..=5 {
+            assert!(node_1.add_node(format!("node_{}", id)));
+        }
 
-        let result = node_1.with_data_mut("data_key".to_string(), |data| {
-            data.push_str("data");
-            data.clone()
+        assert_eq!(node_1.all_nodes().len(), 5);
+        assert!(node_1
+            .node_id_from_data_key(&"data_key".to_string())
+            .is_some());
+    }
+
+    #[test]
+    fn set_weight_moves_partitions_to_the_heavier_node() {
+        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+        node_1.add_node("node_1".to_string());
+        node_1.add_weighted_node("node_2".to_string(), 1);
+
+        for id in 0..1000 {
+            node_1.with_upsert_data_mut(format!("data_key_{}", id), |data| {
+                data.push_str("data");
+            });
+        }
+        let size_before = node_1.size();
+
+        assert!(node_1.set_weight(&"node_2".to_string(), 3));
+        assert_eq!(node_1.weight(&"node_2".to_string()), 3);
+
+        let keys_by_node = node_1.get_keys_to_migrate_by_node();
+        assert_eq!(keys_by_node.len(), 1);
+        assert!(keys_by_node["node_2"].len() > size_before / 3);
+    }
+
+    #[test]
+    fn placement_changes_that_change_nothing_keep_the_epoch() {
+        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+        node_1.add_node("node_1".to_string());
+        node_1.add_node("node_2".to_string());
+        node_1.finish_handoff();
+        let epoch = node_1.epoch();
+
+        assert!(!node_1.add_node("node_2".to_string()));
+        assert!(!node_1.add_weighted_node("node_2".to_string(), 2));
+        assert!(!node_1.remove_node(&"node_3".to_string()));
+        assert!(!node_1.set_weight(&"node_2".to_string(), 1));
+        assert!(!node_1.set_weight(&"node_2".to_string(), 0));
+        assert!(!node_1.set_weight(&"node_3".to_string(), 2));
+        assert!(!node_1.add_node_at("node_3".to_string(), "m".to_string()));
+
+        assert_eq!(node_1.epoch(), epoch);
+        assert!(!node_1.is_handing_off());
+        assert_eq!(node_1.all_nodes().len(), 2);
+        assert_eq!(node_1.weight(&"node_2".to_string()), 1);
+    }
+
+    type OrderedNode = Node<String, String, KeyRangePlacement<String>>;
+
+    fn ordered_nodes() -> (OrderedNode, OrderedNode) {
+        let mut node_1 = Node::with_placement("node_1".to_string(), KeyRangePlacement::default());
+        let mut node_2 = Node::with_placement("node_2".to_string(), KeyRangePlacement::default());
+        node_1.add_node("node_1".to_string());
+        node_2.add_node("node_1".to_string());
+
+        for id in 0..10 {
+            node_1.insert_data(format!("user:{}:order", id), format!("order_{}", id));
+        }
+        let split_key = node_1.split_key().unwrap();
+        assert_eq!(split_key, "user:5:order");
+
+        node_1.add_node_at("node_2".to_string(), split_key.clone());
+        node_2.add_node_at("node_2".to_string(), split_key);
+        for (key, data) in node_1.take_data(&node_1.get_keys_to_migrate()) {
+            node_2.insert_data(key, data);
+        }
+        (node_1, node_2)
+    }
+
+    #[test]
+    fn ordered_scale_up_splits_the_range() {
+        let (node_1, node_2) = ordered_nodes();
+
+        assert_eq!(node_1.size(), 5);
+        assert_eq!(node_2.size(), 5);
+        assert_eq!(
+            node_1.range_start(&"node_2".to_string()),
+            Some("user:5:order".to_string())
+        );
+        assert_eq!(node_1.partition_sizes(), vec![(0, 5)]);
+    }
+
+    #[test]
+    fn ordered_nodes_refuse_taken_range_starts() {
+        let (mut node_1, _) = ordered_nodes();
+        node_1.finish_handoff();
+        let epoch = node_1.epoch();
+
+        assert!(!node_1.add_node_at("node_3".to_string(), "user:5:order".to_string()));
+        assert!(!node_1.add_node_at("node_2".to_string(), "user:7".to_string()));
+
+        assert_eq!(node_1.epoch(), epoch);
+        assert!(!node_1.is_handing_off());
+    }
+
+    #[test]
+    fn scan_walks_owning_nodes_with_a_cursor() {
+        let (node_1, node_2) = ordered_nodes();
+        let nodes = [&node_1, &node_2];
+
+        let mut cursor = Some("user:".to_string());
+        let end = "user;".to_string();
+        let mut keys = vec![];
+        let mut pages = 0;
+
+        while let Some(start) = cursor {
+            let page = match node_1.scan(&start, Some(&end), 3) {
+                NodeResult::NodeId(node_id) => {
+                    let owner = nodes.iter().find(|node| node.id == node_id).unwrap();
+                    owner.scan(&start, Some(&end), 3)
+                }
+                result => result,
+            }
+            .or_forward_unwrap(|_| unreachable!());
+
+            keys.extend(page.entries.into_iter().map(|(key, _)| key));
+            cursor = page.cursor;
+            pages += 1;
+        }
+
+        assert_eq!(
+            keys,
+            (0..10)
+                .map(|id| format!("user:{}:order", id))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(pages, 4);
+    }
+
+    #[test]
+    fn scan_stops_at_end() {
+        let (node_1, _) = ordered_nodes();
+
+        let page = node_1
+            .scan(&"user:1".to_string(), Some(&"user:3".to_string()), 10)
+            .or_forward_unwrap(|_| unreachable!());
+
+        assert_eq!(page.entries.len(), 2);
+        assert_eq!(page.cursor, None);
+    }
+
+    fn transfer(
+        transaction: &mut Transaction<u64>,
+        from: &str,
+        to: &str,
+        amount: u64,
+    ) -> Result<(), String> {
+        let balance = transaction.upsert(from).unwrap();
+        if *balance < amount {
+            return Err("insufficient balance".to_string());
+        }
+        *balance -= amount;
+        *transaction.upsert(to).unwrap() += amount;
+        Ok(())
+    }
+
+    fn node_with_balances() -> Node<String, u64> {
+        let mut node_1 = Node::<_, u64>::new("node_1".to_string(), HashSet::new());
+        node_1.add_node("node_1".to_string());
+        node_1.insert_data("balance:{alice}".to_string(), 100);
+        node_1
+    }
+
+    #[test]
+    fn transaction_applies_all_writes() {
+        let mut node_1 = node_with_balances();
+        let keys = vec!["balance:{alice}".to_string(), "savings:{alice}".to_string()];
+
+        let result = node_1.with_transaction(keys, |transaction| {
+            transfer(transaction, "balance:{alice}", "savings:{alice}", 40)
         });
 
-        assert_eq!(result, NodeResult::Result(None));
+        assert_eq!(result, NodeResult::Result(Ok(())));
+        assert_eq!(node_1.get_data(&"balance:{alice}".to_string()), Some(&60));
+        assert_eq!(node_1.get_data(&"savings:{alice}".to_string()), Some(&40));
     }
 
     #[test]
-    fn with_data_mut_returns_result_when_key_maps_to_node_and_data_available() {
-        let mut node_1 = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+    fn aborted_transaction_writes_nothing() {
+        let mut node_1 = node_with_balances();
+        let keys = vec!["balance:{alice}".to_string(), "savings:{alice}".to_string()];
 
-        node_1.add_node("index_node_id".to_string());
+        let result = node_1.with_transaction(keys, |transaction| {
+            transfer(transaction, "savings:{alice}", "balance:{alice}", 10)
+        });
+
+        assert_eq!(
+            result,
+            NodeResult::Result(Err(TransactionError::Aborted(
+                "insufficient balance".to_string()
+            )))
+        );
+        assert_eq!(node_1.get_data(&"balance:{alice}".to_string()), Some(&100));
+        assert_eq!(node_1.get_data(&"savings:{alice}".to_string()), None);
+    }
+
+    #[test]
+    fn transaction_requires_keys_on_one_node() {
+        let mut node_1 = node_with_balances();
+        node_1.add_node("node_2".to_string());
+
+        let owner = |node: &Node<String, u64>, key: &str| {
+            node.node_id_from_data_key(&key.to_string())
+                .unwrap()
+                .clone()
+        };
+        let remote_user = (0..)
+            .map(|id| format!("user_{}", id))
+            .find(|user| owner(&node_1, &format!("{{{}}}", user)) == "node_2")
+            .unwrap();
+        let local_user = (0..)
+            .map(|id| format!("user_{}", id))
+            .find(|user| owner(&node_1, &format!("{{{}}}", user)) == "node_1")
+            .unwrap();
+
+        let result = node_1.with_transaction(
+            vec![
+                format!("balance:{{{}}}", remote_user),
+                format!("savings:{{{}}}", remote_user),
+            ],
+            |_| Ok::<_, ()>(()),
+        );
+        assert_eq!(result, NodeResult::NodeId("node_2".to_string()));
+
+        let result = node_1.with_transaction(
+            vec![
+                format!("balance:{{{}}}", local_user),
+                format!("balance:{{{}}}", remote_user),
+            ],
+            |_| Ok::<_, ()>(()),
+        );
+        assert_eq!(result, NodeResult::Result(Err(TransactionError::CrossNode)));
+    }
+
+    #[test]
+    fn locked_keys_block_other_transactions_until_released() {
+        let mut node_1 = node_with_balances();
+        let keys = vec!["balance:{alice}".to_string()];
+        let (tx_1, tx_2) = ("tx_1".to_string(), "tx_2".to_string());
+
+        assert_eq!(node_1.lock_keys(&tx_1, &keys, 0, 10), Ok(()));
+        assert!(node_1.lock_keys(&tx_2, &keys, 0, 10).is_err());
+        assert_eq!(
+            node_1.with_transaction(keys.clone(), |_| Ok::<_, ()>(())),
+            NodeResult::Result(Err(TransactionError::Locked))
+        );
+
+        assert_eq!(node_1.unlock_keys(&tx_1), keys);
+        assert_eq!(node_1.lock_keys(&tx_2, &keys, 0, 10), Ok(()));
+    }
+
+    #[test]
+    fn locked_keys_refuse_single_key_writes() {
+        let mut node_1 = node_with_balances();
+        let key = "balance:{alice}".to_string();
+        let tx_1 = "tx_1".to_string();
+        let version = node_1.version(&key);
         node_1
-            .data
-            .insert("data_key".to_string(), "data".to_string());
+            .lock_keys(&tx_1, std::slice::from_ref(&key), 0, 10)
+            .unwrap();
 
-        let result = node_1.with_data_mut("data_key".to_string(), |data| data.clone());
+        assert_eq!(
+            node_1.with_data_mut(key.clone(), |balance| *balance += 1),
+            NodeResult::Result(Err(WriteError::Locked))
+        );
+        assert_eq!(
+            node_1.with_upsert_data_mut(key.clone(), |balance| *balance += 1),
+            NodeResult::Result(Err(WriteError::Locked))
+        );
+        assert_eq!(
+            node_1.with_data_mut_if_version(key.clone(), version, |balance| *balance += 1),
+            NodeResult::Result(Err(WriteError::Locked))
+        );
+        assert_eq!(node_1.version(&key), version);
 
-        assert_eq!(result, NodeResult::Result(Some("data".to_string())));
+        node_1.unlock_keys(&tx_1);
+        assert_eq!(
+            node_1.with_data_mut(key, |balance| *balance += 1),
+            NodeResult::Result(Ok(Some(())))
+        );
     }
 
     #[test]
-    fn with_data_mut_returns_node_id_when_key_maps_to_different_node() {
-        let mut node_1 = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+    fn expired_locks_are_released() {
+        let mut node_1 = node_with_balances();
+        let keys = vec!["balance:{alice}".to_string()];
+        let (tx_1, tx_2) = ("tx_1".to_string(), "tx_2".to_string());
+        node_1.lock_keys(&tx_1, &keys, 0, 10).unwrap();
+
+        // an expired lock doesn't block a new one
+        assert_eq!(node_1.lock_keys(&tx_2, &keys, 10, 20), Ok(()));
+        assert_eq!(node_1.release_expired_locks(19), 0);
+        assert_eq!(node_1.release_expired_locks(20), 1);
+        assert!(!node_1.is_locked(&keys[0]));
+    }
 
-        node_1.add_node("index_node_id".to_string());
+    #[test]
+    fn foreign_keys_cannot_be_locked() {
+        let mut node_1 = node_with_balances();
+        node_1.add_node("node_2".to_string());
+        let foreign_key = (0..)
+            .map(|id| format!("data_key_{}", id))
+            .find(|key| node_1.is_foreign_key(key))
+            .unwrap();
+
+        assert!(node_1
+            .lock_keys(
+                &"tx_1".to_string(),
+                std::slice::from_ref(&foreign_key),
+                0,
+                10
+            )
+            .is_err());
+        assert!(!node_1.is_locked(&foreign_key));
+    }
+
+    #[test]
+    fn writes_bump_versions() {
+        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
         node_1.add_node("node_1".to_string());
+        let key = "key_1".to_string();
 
-        node_1
-            .data
-            .insert("data_key".to_string(), "data".to_string());
-        node_1
-            .data
-            .insert("data_key_2".to_string(), "data_2".to_string());
+        assert_eq!(node_1.version(&key), 0);
+        node_1.with_upsert_data_mut(key.clone(), |data| data.push('a'));
+        node_1.with_data_mut(key.clone(), |data| data.push('b'));
+        assert_eq!(
+            node_1.with_data(key.clone(), |data| data.clone()),
+            NodeResult::Result(Some(Versioned {
+                value: "ab".to_string(),
+                version: 2
+            }))
+        );
 
-        let result = node_1.with_data_mut("data_key_5".to_string(), |data| data.clone());
+        // a removal is a write too, and the key doesn't start over when written again
+        node_1.take_data(std::slice::from_ref(&key));
+        assert_eq!(node_1.version(&key), 3);
+        node_1.with_upsert_data_mut(key.clone(), |data| data.push('a'));
+        assert_eq!(node_1.version(&key), 4);
+    }
 
-        assert_eq!(result, NodeResult::NodeId("node_1".to_string()));
+    #[test]
+    fn removed_keys_are_not_brought_back_by_older_writes() {
+        let mut nodes = replicated_nodes();
+        let key = (0..)
+            .map(|id| format!("key_{}", id))
+            .find(|key| !nodes[0].is_foreign_key(key) && !nodes[1].is_foreign_key(key))
+            .unwrap();
+        nodes[0].insert_data(key.clone(), "value".to_string());
+        nodes[1].insert_versioned_data(key.clone(), "value".to_string(), 1);
+
+        nodes[0].take_data(std::slice::from_ref(&key));
+        nodes[0].merge_versioned_data(key.clone(), "value".to_string(), 1);
+        assert_eq!(nodes[0].get_data(&key), None);
+
+        // the removal reaches the other replica
+        let version = nodes[0].version(&key);
+        nodes[1].remove_versioned_data(&key, version);
+        assert_eq!(nodes[1].get_data(&key), None);
+        assert_eq!(nodes[0].merkle_tree(), nodes[1].merkle_tree());
+        assert_eq!(
+            nodes[1].key_versions(Node::<String, String>::partition_from_data_key(&key)),
+            vec![(key, 2)]
+        );
     }
 
     #[test]
-    fn get_keys_to_migrate_returns_added_nodes_ids() {
-        let mut index_node = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+    fn conditional_writes_apply_only_at_the_expected_version() {
+        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+        node_1.add_node("node_1".to_string());
+        let key = "key_1".to_string();
 
-        index_node.add_node("index_node_id".to_string());
+        assert_eq!(
+            node_1.with_data_mut_if_version(key.clone(), 0, |data| data.push('a')),
+            NodeResult::Result(Ok(Versioned {
+                value: (),
+                version: 1
+            }))
+        );
 
-        let mut all_keys = vec![];
+        // a second writer that also read the key as missing loses
+        assert_eq!(
+            node_1.with_data_mut_if_version(key.clone(), 0, |data| data.push('b')),
+            NodeResult::Result(Err(WriteError::VersionConflict { current_version: 1 }))
+        );
+        assert_eq!(node_1.get_data(&key), Some(&"a".to_string()));
+
+        assert!(matches!(
+            node_1.with_data_mut_if_version(key.clone(), 1, |data| data.push('c')),
+            NodeResult::Result(Ok(Versioned { version: 2, .. }))
+        ));
+        assert_eq!(node_1.get_data(&key), Some(&"ac".to_string()));
+    }
+
+    #[test]
+    fn conditional_writes_to_foreign_keys_are_forwarded() {
+        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+        node_1.add_node("node_1".to_string());
+        node_1.add_node("node_2".to_string());
+        let foreign_key = (0..)
+            .map(|id| format!("data_key_{}", id))
+            .find(|key| node_1.is_foreign_key(key))
+            .unwrap();
+
+        assert_eq!(
+            node_1.with_data_mut_if_version(foreign_key.clone(), 3, |data| data.push('a')),
+            NodeResult::NodeId("node_2".to_string())
+        );
+        assert_eq!(
+            node_1.with_data(foreign_key, |data| data.clone()),
+            NodeResult::NodeId("node_2".to_string())
+        );
+    }
+
+    thread_local! {
+        static NOW: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+    }
+
+    fn test_clock() -> u64 {
+        NOW.with(|now| now.get())
+    }
+
+    fn node_with_clock() -> Node<String, String> {
+        let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+        node_1.add_node("node_1".to_string());
+        node_1.set_clock(test_clock);
+        node_1
+    }
+
+    #[test]
+    fn expired_keys_are_invisible() {
+        let mut node_1 = node_with_clock();
+        let key = "session:1".to_string();
+        node_1.insert_data_with_ttl(key.clone(), "token".to_string(), 10);
+
+        NOW.with(|now| now.set(9));
+        assert_eq!(node_1.get_data(&key), Some(&"token".to_string()));
+
+        NOW.with(|now| now.set(10));
+        assert_eq!(node_1.get_data(&key), None);
+        assert_eq!(node_1.version(&key), 2);
+        assert_eq!(
+            node_1.with_data_mut(key.clone(), |data| data.clone()),
+            NodeResult::Result(Ok(None))
+        );
+
+        // an upsert starts over from the default value without the old ttl
+        node_1.with_upsert_data_mut(key.clone(), |data| data.push('a'));
+        assert_eq!(node_1.get_data(&key), Some(&"a".to_string()));
+        assert_eq!(node_1.expires_at(&key), None);
+    }
+
+    #[test]
+    fn upserts_with_ttl_push_the_expiry_back() {
+        let mut node_1 = node_with_clock();
+        let key = "session:1".to_string();
+
+        node_1.with_upsert_data_mut_ttl(key.clone(), 10, |data| data.push('a'));
+        NOW.with(|now| now.set(5));
+        node_1.with_upsert_data_mut_ttl(key.clone(), 10, |data| data.push('b'));
+        assert_eq!(node_1.expires_at(&key), Some(15));
+
+        // writes without a ttl keep the current one
+        node_1.with_data_mut(key.clone(), |data| data.push('c'));
+        NOW.with(|now| now.set(12));
+        assert_eq!(node_1.get_data(&key), Some(&"abc".to_string()));
+    }
+
+    #[test]
+    fn remove_expired_sweeps_within_the_limit() {
+        let mut node_1 = node_with_clock();
         for id in 0..10 {
-            let key = &format!("data_key_{}", id);
-            all_keys.push(key.clone());
-            index_node.with_upsert_data_mut(key.clone(), |data| {
-                data.push_str("data");
-            });
+            node_1.insert_data_with_ttl(format!("session:{}", id), "token".to_string(), id + 1);
         }
+        node_1.insert_data("user:1".to_string(), "alice".to_string());
+
+        NOW.with(|now| now.set(5));
+        assert_eq!(node_1.remove_expired(3), 3);
+        assert_eq!(node_1.remove_expired(10), 2);
+        assert_eq!(node_1.remove_expired(10), 0);
+        assert_eq!(node_1.size(), 6);
+        assert!(node_1.get_data(&"session:4".to_string()).is_none());
+        assert!(node_1.get_data(&"session:5".to_string()).is_some());
+    }
 
-        assert_eq!(index_node.data.len(), 10);
-        // assert!(node_1.data.len() >= 4);
-        index_node.add_node("node_1".to_string());
+    fn replicated_nodes() -> Vec<Node<String, String>> {
+        (0..3)
+            .map(|id| {
+                let mut node = Node::<_, String>::new(format!("node_{}", id), HashSet::new());
+                for id in 0..3 {
+                    node.add_node(format!("node_{}", id));
+                }
+                node.set_replication_factor(2);
+                node
+            })
+            .collect()
+    }
 
-        let keys_to_migrate = index_node.get_keys_to_migrate();
+    #[test]
+    fn replicas_serve_reads_and_forward_writes() {
+        let mut nodes = replicated_nodes();
+        let key = "key_1".to_string();
+        let replicas: Vec<String> = nodes[0].replicas_of(&key).into_iter().cloned().collect();
+        assert_eq!(replicas.len(), 2);
+
+        let index = |id: &String| nodes.iter().position(|node| node.id == *id).unwrap();
+        let (owner, replica) = (index(&replicas[0]), index(&replicas[1]));
+        let other = 3 - owner - replica;
+        nodes[owner].insert_data(key.clone(), "value".to_string());
+        nodes[replica].insert_versioned_data(key.clone(), "value".to_string(), 1);
+
+        assert!(matches!(
+            nodes[replica].with_data(key.clone(), |data| data.clone()),
+            NodeResult::Result(Some(Versioned { version: 1, .. }))
+        ));
         assert_eq!(
-            keys_to_migrate.len() >= 4 && keys_to_migrate.len() < 8,
-            true
+            nodes[replica].with_data_mut(key.clone(), |data| data.clone()),
+            NodeResult::NodeId(replicas[0].clone())
+        );
+        assert_eq!(
+            nodes[other].with_data(key.clone(), |data| data.clone()),
+            NodeResult::NodeId(replicas[0].clone())
         );
+    }
 
-        assert!(keys_to_migrate
+    #[test]
+    fn replicated_keys_are_migrated_to_every_replica() {
+        let mut nodes = replicated_nodes();
+        let node_0 = &mut nodes[0];
+        for id in 0..100 {
+            node_0.insert_data(format!("data_key_{}", id), "data".to_string());
+        }
+
+        let keys_by_node = node_0.get_keys_to_migrate_by_node();
+        for id in 0..100 {
+            let key = format!("data_key_{}", id);
+            for replica in node_0.replicas_of(&key) {
+                if *replica != node_0.id {
+                    assert!(keys_by_node[replica].contains(&key));
+                }
+            }
+        }
+
+        // after the hand-off only the keys node_0 no longer replicates are given up
+        let keys: Vec<String> = keys_by_node.into_values().flatten().collect();
+        node_0.take_foreign_data(&keys);
+        assert!(node_0.size() > 0);
+        assert!(node_0.get_keys_to_migrate().is_empty());
+    }
+
+    #[test]
+    fn merkle_tree_follows_keys_and_versions() {
+        let mut nodes = replicated_nodes();
+        let keys: Vec<String> = (0..10).map(|id| format!("key_{}", id)).collect();
+        for key in &keys {
+            nodes[0].insert_data(key.clone(), "value".to_string());
+        }
+        for key in keys.iter().rev() {
+            nodes[1].insert_versioned_data(key.clone(), "value".to_string(), 1);
+        }
+        assert_ne!(nodes[0].merkle_tree().root(), 0);
+        assert_eq!(nodes[0].merkle_tree(), nodes[1].merkle_tree());
+
+        nodes[0].insert_data(keys[3].clone(), "newer".to_string());
+        assert_ne!(nodes[0].merkle_tree(), nodes[1].merkle_tree());
+
+        nodes[1].insert_versioned_data(keys[3].clone(), "newer".to_string(), 2);
+        assert_eq!(nodes[0].merkle_tree(), nodes[1].merkle_tree());
+
+        // removals are versioned like writes
+        let shared: Vec<String> = keys
             .iter()
-            .all(|key| index_node.node_id_from_data_key(key).unwrap() == &"node_1".to_string()));
+            .filter(|key| !nodes[0].is_foreign_key(key) && !nodes[1].is_foreign_key(key))
+            .cloned()
+            .collect();
+        nodes[0].take_data(&shared);
+        assert_ne!(nodes[0].merkle_tree(), nodes[1].merkle_tree());
+        nodes[1].take_data(&shared);
+        assert_eq!(nodes[0].merkle_tree(), nodes[1].merkle_tree());
     }
 
     #[test]
-    fn get_keys_to_migrate_returns_deleted_nodes_ids() {
-        let mut index_node = Node::<_, String>::new("index_node_id".to_string(), HashSet::new());
+    fn merkle_tree_tells_values_apart() {
+        let mut nodes = replicated_nodes();
+        for node in &mut nodes {
+            node.set_digest(|value| crate::placement::hash_of(value));
+        }
+        let key = "key_1".to_string();
+        nodes[0].insert_versioned_data(key.clone(), "value".to_string(), 1);
+        nodes[1].insert_versioned_data(key.clone(), "other".to_string(), 1);
+        assert_ne!(nodes[0].merkle_tree(), nodes[1].merkle_tree());
 
+        nodes[1].insert_versioned_data(key, "value".to_string(), 1);
+        assert_eq!(nodes[0].merkle_tree(), nodes[1].merkle_tree());
+    }
+
+    #[test]
+    fn keys_behind_on_lists_missing_and_older_keys() {
+        let mut nodes = replicated_nodes();
+        let peer = nodes[1].id.clone();
+        let shared: Vec<String> = (0..)
+            .map(|id| format!("key_{}", id))
+            .filter(|key| {
+                let replicas = nodes[0].replicas_of(key);
+                replicas.contains(&&nodes[0].id) && replicas.contains(&&peer)
+            })
+            .take(3)
+            .collect();
+        for key in &shared {
+            nodes[0].insert_versioned_data(key.clone(), "value".to_string(), 2);
+        }
+        let partitions: Vec<PartitionId> = (0..PARTITION_COUNT)
+            .filter(|partition| nodes[0].shares_partition(&peer, *partition))
+            .collect();
+        assert!(!partitions.is_empty());
+
+        let remote_versions = HashMap::from([(shared[0].clone(), 2), (shared[1].clone(), 1)]);
+        let mut behind = nodes[0].keys_behind_on(&peer, &partitions, &remote_versions);
+        behind.sort();
+        let mut expected = vec![shared[1].clone(), shared[2].clone()];
+        expected.sort();
+        assert_eq!(behind, expected);
+    }
+
+    #[test]
+    fn misplaced_keys_are_grouped_by_their_owners() {
         let mut node_1 = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+        node_1.add_node("node_1".to_string());
+        for id in 0..50 {
+            node_1.insert_data(format!("key_{}", id), "value".to_string());
+        }
+        assert!(node_1.misplaced_keys(usize::MAX).is_empty());
 
-        index_node.add_node("index_node_id".into());
-        index_node.add_node("node_1".into());
+        node_1.add_node("node_2".to_string());
+        let misplaced = node_1.misplaced_keys(usize::MAX);
+        assert_eq!(misplaced.len(), 1);
+        let keys = &misplaced[&"node_2".to_string()];
+        assert!(!keys.is_empty());
+        assert!(keys
+            .iter()
+            .all(|key| node_1.owner_of(key) == Some(&"node_2".to_string())));
+        assert_eq!(keys.len(), node_1.get_keys_to_migrate().len());
+        assert_eq!(node_1.misplaced_keys(5)[&"node_2".to_string()].len(), 5);
+    }
 
-        node_1.add_node("index_node_id".into());
-        node_1.add_node("node_1".into());
+    #[derive(Clone, Default, Debug, PartialEq)]
+    struct Counter(HashMap<String, u64>);
 
-        let mut all_keys = vec![];
-        for id in 0..10 {
-            let key = &format!("data_key_{}", id);
-            all_keys.push(key.clone());
-            index_node
-                .with_upsert_data_mut(key.clone(), |data| {
-                    data.push_str("data");
-                })
-                .or_forward_unwrap(|node_id| {
-                    if node_id == "node_1".to_string() {
-                        node_1.with_upsert_data_mut(key.clone(), |data| {
-                            data.push_str("data");
-                        });
-                        Some(())
-                    } else {
-                        None
-                    }
-                });
+    /// grow-only counter CRDT, every node counts in its own slot
+    impl Merge for Counter {
+        fn merge(local: Versioned<Self>, incoming: Versioned<Self>) -> Self {
+            let mut counts = local.value.0;
+            for (node, count) in incoming.value.0 {
+                let local = counts.entry(node).or_default();
+                *local = (*local).max(count);
+            }
+            Counter(counts)
         }
-        //evenly distributed
-        assert_eq!(index_node.data.len(), 5);
-        assert_eq!(node_1.data.len(), 5);
-        //let's see what happens when we delete one node
-        node_1.remove_node(&"node_1".into());
+    }
 
-        let keys_to_migrate = node_1.get_keys_to_migrate();
+    #[test]
+    fn migrated_values_are_merged_with_local_ones() {
+        let mut node = Node::<_, String>::new("node_1".to_string(), HashSet::new());
+        node.add_node("node_1".to_string());
+        let key = "key".to_string();
+        node.insert_versioned_data(key.clone(), "local".to_string(), 3);
+
+        node.merge_versioned_data(key.clone(), "stale".to_string(), 2);
+        assert_eq!(node.get_data(&key), Some(&"local".to_string()));
+        assert_eq!(node.version(&key), 3);
+
+        node.merge_versioned_data(key.clone(), "newer".to_string(), 4);
+        assert_eq!(node.get_data(&key), Some(&"newer".to_string()));
+        assert_eq!(node.version(&key), 4);
+
+        let mut counters = Node::<_, Counter>::new("node_1".to_string(), HashSet::new());
+        counters.add_node("node_1".to_string());
+        let count = |entries: &[(&str, u64)]| {
+            Counter(
+                entries
+                    .iter()
+                    .map(|(node, count)| (node.to_string(), *count))
+                    .collect(),
+            )
+        };
+        counters.insert_versioned_data(key.clone(), count(&[("a", 2), ("b", 1)]), 5);
+        counters.merge_versioned_data(key.clone(), count(&[("b", 4)]), 2);
+        assert_eq!(counters.get_data(&key), Some(&count(&[("a", 2), ("b", 4)])));
+        assert_eq!(counters.version(&key), 5);
+    }
 
-        assert!(keys_to_migrate
+    #[test]
+    fn new_owners_fetch_missing_keys_from_the_previous_owner() {
+        let mut node_2 = Node::<_, String>::new("node_2".to_string(), HashSet::new());
+        node_2.add_node("node_1".to_string());
+        assert!(!node_2.is_handing_off());
+        node_2.add_node("node_2".to_string());
+        assert!(node_2.is_handing_off());
+        assert_eq!(node_2.epoch(), 2);
+
+        let keys: Vec<String> = (0..50).map(|id| format!("key_{}", id)).collect();
+        let (owned, other): (Vec<&String>, Vec<&String>) = keys
             .iter()
-            .all(|key| node_1.node_id_from_data_key(key).unwrap() == &"index_node_id".to_string()));
+            .partition(|key| node_2.owner_of(key) == Some(&"node_2".to_string()));
+        assert!(!owned.is_empty() && !other.is_empty());
+        assert_eq!(node_2.handoff_sources(owned[0]), vec!["node_1"]);
+        assert!(node_2.handoff_sources(other[0]).is_empty());
+
+        node_2.insert_data(owned[0].clone(), "value".to_string());
+        assert!(node_2.handoff_sources(owned[0]).is_empty());
+        node_2.finish_handoff();
+        assert!(node_2.handoff_sources(owned[1]).is_empty());
+    }
+
+    #[test]
+    fn keys_are_fetched_from_every_owner_of_an_unfinished_handoff() {
+        let mut node_3 = Node::<_, String>::new("node_3".to_string(), HashSet::new());
+        node_3.add_node("node_1".to_string());
+        node_3.add_node("node_2".to_string());
+        node_3.add_node("node_3".to_string());
+        assert_eq!(node_3.handoffs(), vec![1, 2]);
+
+        // keys node_1 still holds, moved to node_2 by the first join and to node_3 by the second
+        let key = (0..)
+            .map(|id| format!("key_{}", id))
+            .find(|key| {
+                let owners: Vec<_> = node_3
+                    .previous_placements
+                    .iter()
+                    .map(|(_, placement)| placement.node_for_key(key).unwrap().clone())
+                    .collect();
+                owners == ["node_1", "node_2"]
+                    && node_3.owner_of(key) == Some(&"node_3".to_string())
+            })
+            .unwrap();
+
+        assert_eq!(node_3.handoff_sources(&key), vec!["node_2", "node_1"]);
     }
 }
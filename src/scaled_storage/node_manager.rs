@@ -1,7 +1,20 @@
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io::{Read, Write};
 use std::ops::{Add, Div};
-
-use crate::node::Node;
-use candid::utils::ArgumentEncoder;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::Poll;
+
+use crate::merge::Merge;
+use crate::merkle::{MerkleTree, TreeIndex, ROOT};
+use crate::node::{
+    Node, NodeResult, PartitionId, PlacementChange, ScanPage, Transaction, TransactionError,
+    Versioned, PARTITION_COUNT,
+};
+use crate::placement::{hash_of, AnchorPlacement, Placement};
+use candid::utils::{decode_args, encode_args, ArgumentEncoder};
 use ic_cdk::export::{
     candid::{CandidType, Deserialize},
     Principal,
@@ -15,14 +28,113 @@ use serde::de::DeserializeOwned;
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum CanisterManagerEvent {
-    NodeCreated(Principal),
+    NodeCreated(MembershipChange),
     NodeDeleted(Principal),
     Migrate(MigrateArgs),
+    WeightChanged(MembershipChange),
+    Prepare(PrepareArgs),
+    Commit(CommitArgs),
+    /// extends the locks of a prepared transaction right before its commit
+    Renew(PrepareArgs),
+    Abort(String),
+    Read(Vec<String>),
+    TreeHashes(Vec<TreeIndex>),
+    KeyVersions(Vec<PartitionId>),
+    /// reports the node's misplaced keys, handing them to their replicas first when true
+    Audit(bool),
+    HandOff(HandOffArgs),
+    Scan(ScanArgs),
+    /// sent to the first node before a membership change, answered with an error while another
+    /// node holds the lease
+    AcquireTopologyLease(Principal),
+    ReleaseTopologyLease(Principal),
+}
+
+/// reply of `lifecycle_handle_event`, only transaction events reply with something other than `Ok`
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum CanisterManagerEventResponse {
+    Ok,
+    Prepared(ValuesArgs),
+    Values(ValuesArgs),
+    TreeHashes(Vec<u64>),
+    KeyVersions(Vec<(String, u64)>),
+    Audit(AuditReport),
+    Scanned(ScannedArgs),
+    Error(String),
+}
+
+/// how many replicas of a key have to answer before an operation on it returns
+#[derive(Clone, Copy, Debug, PartialEq, CandidType, Deserialize)]
+pub enum ConsistencyLevel {
+    One,
+    Quorum,
+    All,
+}
+
+impl ConsistencyLevel {
+    /// answers required out of `replicas`
+    pub fn required(&self, replicas: usize) -> usize {
+        match self {
+            ConsistencyLevel::One => replicas.min(1),
+            ConsistencyLevel::Quorum => replicas / 2 + 1,
+            ConsistencyLevel::All => replicas,
+        }
+    }
+}
+
+/// how long a node keeps the keys of a prepared transaction locked, in nanoseconds
+pub const TRANSACTION_TIMEOUT: u64 = 60_000_000_000;
+
+/// most expired keys removed by one heartbeat, bounds the instructions the sweep uses
+pub const EXPIRED_KEYS_PER_HEARTBEAT: usize = 1000;
+
+/// misplaced keys handed to their owners per heartbeat in `MigrationMode::Lazy`
+pub const LAZY_MIGRATION_KEYS_PER_HEARTBEAT: usize = 100;
+
+/// how long a membership change may hold the cluster lease or the local lock before they are
+/// given to another one, in nanoseconds
+pub const TOPOLOGY_LEASE_TIMEOUT: u64 = 600_000_000_000;
+
+/// differing partitions whose key versions are fetched per call during anti-entropy
+pub const SYNC_PARTITIONS_PER_CALL: usize = 32;
+
+/// a cluster member and its share of partitions relative to the other members
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct NodeMember {
+    pub id: Principal,
+    pub weight: u32,
+    /// first key of the member's range when the cluster uses an ordered placement
+    pub range_start: Option<String>,
+}
+
+/// a node joining or changing its weight, with the cluster epoch the change starts
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MembershipChange {
+    pub member: NodeMember,
+    pub epoch: u64,
+}
+
+/// when keys move to their new owner after a membership change
+#[derive(Clone, Copy, Debug, Default, PartialEq, CandidType, Deserialize)]
+pub enum MigrationMode {
+    /// every node sends the keys it no longer owns right away, the default
+    #[default]
+    Eager,
+    /// Owners fetch keys from their previous owner on first access and the heartbeat moves the
+    /// rest `LAZY_MIGRATION_KEYS_PER_HEARTBEAT` at a time. Callers must use
+    /// `pull_during_handoff` and `read_during_handoff` for the keys to be found in the meantime.
+    Lazy,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct InstallArgs {
-    pub all_nodes: Vec<Principal>,
+    /// every change of the installing node's placement in order, replayed by the new node so it
+    /// places keys like the rest of the cluster
+    pub placement_history: Vec<PlacementChange<Principal>>,
+    pub replication_factor: u32,
+    pub migration_mode: MigrationMode,
+    /// principals allowed to run admin operations, every node of the cluster accepts them
+    pub controllers: Vec<Principal>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -55,13 +167,68 @@ pub enum NodeError {
     Broadcast(String),
 }
 
+/// keys a node holds without replicating them, see `Node::misplaced_keys`
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct AuditReport {
+    pub node_id: Principal,
+    pub keys: u64,
+    pub misplaced: u64,
+    /// the misplaced keys counted by their owner
+    pub misplaced_by_owner: Vec<(Principal, u64)>,
+    /// misplaced keys handed to their replicas before the report was made
+    pub repaired: u64,
+    pub repair_error: Option<String>,
+}
+
+/// reply of a node that doesn't serve a call on a key, the caller sends the call to `owner`
+/// instead and fetches the topology again when `epoch` is newer than the one it knows
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Redirect {
+    pub owner: Principal,
+    pub epoch: u64,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct NodeInfo {
     pub all_nodes: Vec<String>,
-    pub prev_node_id: Option<Principal>,
-    pub next_node_id: Option<Principal>,
+    pub parent_id: Option<Principal>,
+    pub children: Vec<Principal>,
+    /// membership changes of the cluster, clients refetch the topology when it changes
+    pub epoch: u64,
     pub status: NodeStatus,
     pub cycles_balance: u64,
+    /// weight of every node, in `all_nodes` order
+    pub weights: Vec<u32>,
+    /// every change of the placement in order, see `Node::placement_history`
+    pub placement_history: Vec<PlacementChange<Principal>>,
+    /// number of nodes holding a copy of every key, see `Node::set_replication_factor`
+    pub replication_factor: u64,
+    /// (partition id, number of keys) for every partition held by this node
+    pub partition_sizes: Vec<(u32, u64)>,
+}
+
+/// what a node keeps in stable memory across an upgrade besides its keys, see
+/// `CanisterManager::save_stable`
+#[derive(CandidType, Deserialize)]
+struct StableCanisterManager {
+    node_id: Principal,
+    status: NodeStatus,
+    wasm_binary: Option<serde_bytes::ByteBuf>,
+    /// every change of the placement, replayed to rebuild the same placement
+    placement_history: Vec<PlacementChange<Principal>>,
+    parent_id: Option<Principal>,
+    children: Vec<Principal>,
+    replication_factor: u64,
+    migration_mode: MigrationMode,
+    controllers: Vec<Principal>,
+    epoch: u64,
+    /// see `Node::epoch`
+    applied_changes: u64,
+    /// see `Node::handoffs`
+    handoffs: Vec<u64>,
+    topology_lease: Option<(Principal, u64)>,
+    /// keys prepared by transactions, with the transaction and the expiry of the lock
+    locks: Vec<(String, String, u64)>,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -70,19 +237,51 @@ where
     Data: CandidType,
 {
     data: Vec<(String, Data)>,
+    /// version of every entry of `data`, empty when the receiver assigns new versions
+    versions: Vec<u64>,
+    /// expiry of every entry of `data`, empty when the receiver keeps its own
+    expires_at: Vec<Option<u64>>,
+    /// keys removed by the sender with the version of their removal
+    removed: Vec<(String, u64)>,
+}
+
+/// borrowed counterpart of `DataChunk`, encodes to the same candid type without cloning values
+#[derive(CandidType)]
+struct DataChunkRef<'a, Data>
+where
+    Data: CandidType,
+{
+    data: Vec<(&'a String, &'a Data)>,
+    versions: Vec<u64>,
+    expires_at: Vec<Option<u64>>,
+    removed: Vec<(&'a String, u64)>,
 }
 
 impl<Data> DataChunk<Data>
 where
     Data: CandidType + DeserializeOwned,
 {
-    fn new(data: Vec<(String, Data)>) -> Self {
-        Self { data }
+    fn encode_borrowed(
+        data: Vec<(&String, &Data)>,
+        versions: Vec<u64>,
+        expires_at: Vec<Option<u64>>,
+    ) -> Result<Vec<u8>, String> {
+        Self::encode_with_removed(data, versions, expires_at, vec![])
     }
 
-    fn encode(self) -> Result<Vec<u8>, String> {
-        // encode_args((self,)).map_err(|_| ())
-        Encode!(&self).map_err(|e| e.to_string())
+    fn encode_with_removed(
+        data: Vec<(&String, &Data)>,
+        versions: Vec<u64>,
+        expires_at: Vec<Option<u64>>,
+        removed: Vec<(&String, u64)>,
+    ) -> Result<Vec<u8>, String> {
+        Encode!(&DataChunkRef {
+            data,
+            versions,
+            expires_at,
+            removed
+        })
+        .map_err(|e| e.to_string())
     }
 
     fn decode(data: &Vec<u8>) -> Result<Self, String> {
@@ -105,30 +304,241 @@ pub struct MigrateArgs {
     data: Vec<u8>,
 }
 
-type Canister<Data> = Node<Principal, Data>;
+/// asks a node to lock `keys` for `transaction_id` for `timeout` nanoseconds
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct PrepareArgs {
+    pub transaction_id: String,
+    pub keys: Vec<String>,
+    pub timeout: u64,
+}
+
+/// encoded values of the prepared or read keys that hold data
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ValuesArgs {
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+/// asks the previous owner of `keys` to give them up to `member`, which it adds if it doesn't know it yet
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct HandOffArgs {
+    pub member: NodeMember,
+    pub keys: Vec<String>,
+}
+
+/// asks the owner of `start` for a page of its keys, see `Node::scan`
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ScanArgs {
+    pub start: String,
+    pub end: Option<String>,
+    pub limit: u64,
+}
+
+/// encoded entries of a scanned page and the start of the next one
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ScannedArgs {
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+    cursor: Option<String>,
+}
+
+/// writes of a transaction to the keys a node prepared, the locks are released afterwards
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CommitArgs {
+    pub transaction_id: String,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+    pub removed: Vec<String>,
+}
+
+type Canister<Data, P> = Node<Principal, Data, P>;
+
+/// writes `bytes` after their length, an empty frame ends the stream
+fn write_frame(writer: &mut impl Write, bytes: &[u8]) -> Result<(), String> {
+    writer
+        .write_all(&(bytes.len() as u64).to_le_bytes())
+        .and_then(|_| writer.write_all(bytes))
+        .map_err(|e| e.to_string())
+}
+
+fn read_frame(reader: &mut impl Read) -> Result<Vec<u8>, String> {
+    let mut length = [0; 8];
+    reader.read_exact(&mut length).map_err(|e| e.to_string())?;
+    let mut bytes = vec![0; u64::from_le_bytes(length) as usize];
+    reader.read_exact(&mut bytes).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+/// digest of a value for the merkle tree, the hash of its candid encoding
+fn candid_digest<Data: CandidType>(data: &Data) -> u64 {
+    Encode!(data).map_or(0, hash_of)
+}
+
+/// Awaits `calls` concurrently until `required` of them succeeded. Every call is polled once up
+/// front, which sends it, so the calls still running at that point are delivered but not awaited.
+async fn gather<T, F>(calls: Vec<F>, required: usize) -> Result<Vec<T>, String>
+where
+    F: Future<Output = Result<T, String>>,
+{
+    let total = calls.len();
+    let mut pending: Vec<Pin<Box<F>>> = calls.into_iter().map(Box::pin).collect();
+    let mut results = vec![];
+    let mut errors = vec![];
+
+    futures::future::poll_fn(|cx| {
+        pending.retain_mut(|call| match call.as_mut().poll(cx) {
+            Poll::Ready(Ok(result)) => {
+                results.push(result);
+                false
+            }
+            Poll::Ready(Err(error)) => {
+                errors.push(error);
+                false
+            }
+            Poll::Pending => true,
+        });
+        match results.len() >= required || total - errors.len() < required {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    })
+    .await;
+
+    match results.len() >= required {
+        true => Ok(results),
+        false => Err(format!(
+            "{} of {} required replicas answered: {}",
+            results.len(),
+            required,
+            errors.join(", ")
+        )),
+    }
+}
+
+/// Forwards an endpoint call to the first of `nodes` that answers, see `CanisterManager::route`.
+/// The arguments are encoded once and the same bytes are sent to every node tried. The call traps
+/// with the errors when none answers, which rejects it.
+pub async fn forward_call<R, A>(nodes: Vec<Principal>, method: &str, args: A) -> R
+where
+    A: ArgumentEncoder,
+    R: CandidType + DeserializeOwned,
+{
+    let args = encode_args(args)
+        .unwrap_or_else(|error| ic::trap(&format!("{} arguments: {}", method, error)));
+    let mut errors = vec![];
+    for node_id in nodes {
+        let reply = ic::call_raw(node_id, method, args.clone(), 0).await;
+        match reply.map(|reply| decode_args::<(R,)>(&reply)) {
+            Ok(Ok((result,))) => return result,
+            Ok(Err(error)) => errors.push(format!("{}: {}", node_id, error)),
+            Err((_, error)) => errors.push(format!("{}: {}", node_id, error)),
+        }
+    }
+    ic::trap(&format!("{} was not served: {}", method, errors.join(", ")))
+}
 
-pub struct CanisterManager<Data: Default + Clone> {
+pub struct CanisterManager<Data: Default + Clone, P = AnchorPlacement<Principal>> {
     status: NodeStatus,
-    pub canister: Canister<Data>,
+    pub canister: Canister<Data, P>,
     wasm_binary: Option<Vec<u8>>,
     should_upgrade_func: fn(usize) -> bool,
+    transaction_count: u64,
+    /// position in `all_nodes` of the next node to compare data with
+    sync_cursor: usize,
+    migration_mode: MigrationMode,
+    /// node changing the membership and the expiry of its lease, only kept by the first node
+    topology_lease: Option<(Principal, u64)>,
+    /// expiry of the lock held by the running heartbeat or membership change, see `try_lock`
+    local_lock: Option<u64>,
+    /// principals allowed to run admin operations, see `is_controller`
+    controllers: Vec<Principal>,
+    /// membership changes of the cluster, see `epoch`
+    epoch: u64,
 }
 
-impl<Data: Default + Clone + CandidType + DeserializeOwned> CanisterManager<Data> {
+impl<Data, P> CanisterManager<Data, P>
+where
+    Data: Default + Clone + CandidType + DeserializeOwned + Merge,
+    P: Placement<Principal> + Default,
+{
     pub fn new(node_id: Principal, should_upgrade_func: fn(usize) -> bool) -> Self {
-        let mut new_canister: Node<Principal, Data> =
-            Node::new(node_id.clone(), Default::default());
+        let mut new_canister: Canister<Data, P> = Node::with_placement(node_id, P::default());
 
         new_canister.add_node(node_id);
+        new_canister.set_clock(ic::time);
+        new_canister.set_digest(candid_digest::<Data>);
 
         Self {
             status: NodeStatus::Initialized,
             canister: new_canister,
             wasm_binary: None, // reserve_memory: 0,
             should_upgrade_func,
+            transaction_count: 0,
+            sync_cursor: 0,
+            migration_mode: MigrationMode::Eager,
+            topology_lease: None,
+            local_lock: None,
+            controllers: vec![],
+            epoch: 0,
+        }
+    }
+
+    /// Replaces the principals allowed to run admin operations, `init` sets the principal that
+    /// installs the first node. Nodes created later inherit them.
+    pub fn set_controllers(&mut self, controllers: Vec<Principal>) {
+        self.controllers = controllers;
+    }
+
+    pub fn controllers(&self) -> &[Principal] {
+        &self.controllers
+    }
+
+    /// whether `principal` may run admin operations such as weight changes
+    pub fn is_controller(&self, principal: &Principal) -> bool {
+        self.controllers.contains(principal)
+    }
+
+    /// Membership changes of the cluster. The node starting a change counts it and the events
+    /// announcing it carry the count, so every node it reached reports the same epoch.
+    /// `Node::epoch` counts the changes this node applied instead.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// epoch of a membership change this node starts
+    fn next_epoch(&mut self) -> u64 {
+        self.epoch += 1;
+        self.epoch
+    }
+
+    /// keeps the newest epoch, events of an older change may arrive after a newer one
+    fn adopt_epoch(&mut self, epoch: u64) {
+        self.epoch = self.epoch.max(epoch);
+    }
+
+    /// whether `principal` is a node of the cluster
+    pub fn is_member(&self, principal: &Principal) -> bool {
+        self.canister.all_nodes().contains(&principal)
+    }
+
+    /// Whether `caller` may send events to `handle_event`: only nodes of the cluster may. Checked
+    /// before the event is handled, since the caller is only known at the start of the message.
+    pub fn authorize_event(&self, caller: &Principal) -> Result<(), String> {
+        match self.is_member(caller) {
+            true => Ok(()),
+            false => Err(format!("{} is not a node of the cluster", caller)),
         }
     }
 
+    /// set on the first node before it is initialized, nodes created by scaling up inherit it
+    pub fn set_migration_mode(&mut self, migration_mode: MigrationMode) {
+        self.migration_mode = migration_mode;
+    }
+
+    pub fn migration_mode(&self) -> MigrationMode {
+        self.migration_mode
+    }
+
     pub async fn forward_request<R, M, A>(
         node_id: Principal,
         method: M,
@@ -173,10 +583,12 @@ impl<Data: Default + Clone + CandidType + DeserializeOwned> CanisterManager<Data
         &self.status
     }
 
+    /// every node scales on its own load, but not while it still holds keys of an earlier
+    /// scale-up, whose owners would otherwise not relieve it
     fn should_scale_up(&self) -> bool {
         (self.should_upgrade_func)(self.canister.size())
-            && self.canister.next_node_id.is_none()
             && matches!(self.status, NodeStatus::Ready)
+            && self.canister.get_data_to_migrate().next().is_none()
     }
 
     fn should_scale_down(&self) -> bool {
@@ -204,408 +616,3145 @@ impl<Data: Default + Clone + CandidType + DeserializeOwned> CanisterManager<Data
         }
     }
 
-    pub async fn lifecyle_init_node(&mut self, all_nodes: Option<Vec<Principal>>) -> () {
-        let node_id = self.canister.id;
-        let mut new_canister: Node<Principal, Data> = Node::new(node_id, Default::default());
+    fn member(&self, node_id: Principal) -> NodeMember {
+        NodeMember {
+            id: node_id,
+            weight: self.canister.weight(&node_id),
+            range_start: self.canister.range_start(&node_id),
+        }
+    }
 
-        if let Some(mut all_nodes) = all_nodes {
-            if all_nodes.len() > 1 {
-                let prev_node_id = all_nodes[all_nodes.len() - 2].clone(); //prev_node is actually the second to the last, since all_nodes has already been updated with the new node as the last item
-                new_canister.prev_node_id = Some(prev_node_id);
-                all_nodes.push(node_id);
-                for principal_id in all_nodes {
-                    new_canister.add_node(principal_id);
-                }
+    fn add_member(canister: &mut Canister<Data, P>, member: NodeMember) -> bool {
+        match member.range_start {
+            Some(range_start) if !range_start.is_empty() => {
+                canister.add_node_at(member.id, range_start)
             }
+            _ => canister.add_weighted_node(member.id, member.weight),
         }
+    }
 
-        self.canister = new_canister;
+    fn other_nodes(&self) -> Vec<Principal> {
+        self.canister
+            .all_nodes()
+            .into_iter()
+            .filter(|node_id| **node_id != self.canister.id)
+            .copied()
+            .collect()
+    }
 
-        self.broadcast_event(CanisterManagerEvent::NodeCreated(self.canister.id))
-            .await;
+    /// Nodes to forward a call on `key` to, in order, empty when this node serves it: the key's
+    /// owner, or for reads its replicas unless this node is one of them.
+    pub fn route(&self, key: &str, read: bool) -> Vec<Principal> {
+        let mut replicas = self.canister.replicas_of(key);
+        if !read {
+            replicas.truncate(1);
+        }
+        match replicas.contains(&&self.canister.id) {
+            true => vec![],
+            false => replicas.into_iter().copied().collect(),
+        }
+    }
+
+    /// where to send a call on `key` when this node doesn't serve it, see `route`
+    pub fn redirect(&self, key: &str, read: bool) -> Option<Redirect> {
+        self.route(key, read).first().map(|owner| Redirect {
+            owner: *owner,
+            epoch: self.epoch,
+        })
+    }
+
+    /// node granting the topology lease, the first node of the cluster and root of the genealogy
+    fn lease_coordinator(&self) -> Principal {
+        self.canister
+            .all_nodes()
+            .first()
+            .map(|node_id| **node_id)
+            .unwrap_or(self.canister.id)
+    }
+
+    fn handle_acquire_lease(&mut self, node_id: Principal) -> CanisterManagerEventResponse {
+        let now = ic::time();
+        match self.topology_lease {
+            Some((holder, expires_at)) if holder != node_id && expires_at > now => {
+                CanisterManagerEventResponse::Error(format!(
+                    "node {} is changing the membership",
+                    holder
+                ))
+            }
+            _ => {
+                self.topology_lease = Some((node_id, now + TOPOLOGY_LEASE_TIMEOUT));
+                CanisterManagerEventResponse::Ok
+            }
+        }
+    }
+
+    fn handle_release_lease(&mut self, node_id: Principal) {
+        if matches!(self.topology_lease, Some((holder, _)) if holder == node_id) {
+            self.topology_lease = None;
+        }
+    }
+
+    /// Guards the heartbeat and membership changes of this node against each other, since a
+    /// message arriving while one awaits a call runs on the same state. The lock expires in
+    /// case a trap after an await left it held.
+    fn try_lock(&mut self) -> bool {
+        let now = ic::time();
+        if matches!(self.local_lock, Some(expires_at) if expires_at > now) {
+            return false;
+        }
+        self.local_lock = Some(now + TOPOLOGY_LEASE_TIMEOUT);
+        true
+    }
+
+    fn unlock(&mut self) {
+        self.local_lock = None;
     }
 
-    pub async fn lifecyle_heartbeat_node(&mut self) -> () {
-        if self.should_scale_up() {
-            self.status = NodeStatus::ScaleUp;
-            let create_node_result = self.create_node().await;
+    /// counts the keys this node holds but no longer replicates
+    pub fn audit(&self) -> AuditReport {
+        let mut by_owner: HashMap<Principal, u64> = HashMap::new();
+        let mut misplaced = 0;
+        for (key, _) in self.canister.get_data_to_migrate() {
+            misplaced += 1;
+            if let Some(owner) = self.canister.owner_of(key) {
+                *by_owner.entry(*owner).or_default() += 1;
+            }
+        }
+        AuditReport {
+            node_id: self.canister.id,
+            keys: self.canister.size() as u64,
+            misplaced,
+            misplaced_by_owner: by_owner.into_iter().collect(),
+            repaired: 0,
+            repair_error: None,
+        }
+    }
 
-            match create_node_result {
-                Some(new_node_id) => {
-                    self.canister.add_node(new_node_id.clone());
-                    let result = self.initialize_node(new_node_id.clone()).await;
-                    if !result {
-                        self.canister.remove_node(&new_node_id);
-                        self.status = NodeStatus::Error(NodeError::Initialize(format!(
-                            "Failed to initialize node {}",
-                            new_node_id
-                        )));
+    fn delete_node(&mut self) -> () {
+        // todo!()
+        // https://github.com/open-ic/open-storage/blob/main/backend/libraries/utils/src/canister/delete.rs
+    }
 
-                        return;
+    fn handle_migrate(&mut self, args: MigrateArgs) -> bool {
+        match DataChunk::<Data>::decode(&args.data) {
+            Ok(data_chunk) => {
+                let mut versions = data_chunk.versions.into_iter();
+                let mut expires_at = data_chunk.expires_at.into_iter();
+                for (key, value) in data_chunk.data {
+                    let expiry = expires_at.next();
+                    match versions.next() {
+                        Some(version) => {
+                            let is_newer = version >= self.canister.version(&key);
+                            self.canister
+                                .merge_versioned_data(key.clone(), value, version);
+                            // the expiry goes with the newer write
+                            if !is_newer {
+                                continue;
+                            }
+                        }
+                        None => self.canister.insert_data(key.clone(), value),
                     }
-                    self.status = NodeStatus::Migrating;
-                    let result = self.migrate_data(new_node_id).await;
-
-                    if !result {
-                        self.canister.remove_node(&new_node_id);
-                        self.status = NodeStatus::Error(NodeError::Migration(format!(
-                            "Failed to migrate data to node {}",
-                            new_node_id
-                        )));
-                        return;
+                    if let Some(expires_at) = expiry {
+                        self.canister.set_expiry(&key, expires_at);
                     }
-
-                    self.status = NodeStatus::Ready;
-                    self.canister.next_node_id = Some(new_node_id);
-                    self.broadcast_event(CanisterManagerEvent::NodeCreated(new_node_id))
-                        .await;
                 }
-                None => {
-                    self.status =
-                        NodeStatus::Error(NodeError::ScaleUp("Failed to create node".to_string()));
+                for (key, version) in data_chunk.removed {
+                    self.canister.remove_versioned_data(&key, version);
                 }
+                true
+            }
+            Err(e) => {
+                self.status = NodeStatus::Error(NodeError::Migration(
+                    "Failed to handle migrate data to node".to_string(),
+                ));
+                false
             }
-        } else if self.should_scale_down() {
-            // self.status = NodeStatus::ScaleDown;
-            // self.broadcast_event(CanisterManagerEvent::NodeDeleted(self.canister.id));
         }
     }
 
-    async fn create_node(&mut self) -> Option<Principal> {
-        let arg = management::CreateCanisterArgument {
-            settings: Some(CanisterSettings {
-                compute_allocation: None,
-                controllers: Some(vec![self.canister.id]),
-                freezing_threshold: None,
-                memory_allocation: None, // reserve_memory: self.reserve_memory,
-            }),
-        };
-
-        let result = management::CreateCanister::perform_with_payment(
-            Principal::management_canister(),
-            (arg,),
-            ic::balance().div(self.canister.all_nodes().len().add(1) as u64),
-        )
-        .await;
+    /// the values of `keys` that hold data with their versions and expiries, and the removed ones
+    fn encode_keys(&self, keys: &[String]) -> Result<Vec<u8>, String> {
+        let data: Vec<_> = keys
+            .iter()
+            .filter_map(|key| self.canister.get_data(key).map(|data| (key, data)))
+            .collect();
+        let versions = data
+            .iter()
+            .map(|(key, _)| self.canister.version(key))
+            .collect();
+        let expires_at = data
+            .iter()
+            .map(|(key, _)| self.canister.expires_at(key))
+            .collect();
+        let removed = keys
+            .iter()
+            .filter_map(|key| Some((key, self.canister.removed_version(key)?)))
+            .collect();
+        DataChunk::encode_with_removed(data, versions, expires_at, removed)
+    }
 
-        match result {
-            Ok((result,)) => Some(result.canister_id),
-            Err(_) => None,
+    fn handle_read(&self, keys: Vec<String>) -> CanisterManagerEventResponse {
+        match self.encode_keys(&keys) {
+            Ok(data) => CanisterManagerEventResponse::Values(ValuesArgs { data }),
+            Err(error) => CanisterManagerEventResponse::Error(error),
         }
     }
 
-    async fn initialize_node(&mut self, canister_id: Principal) -> bool {
-        //vector of &Principal to Principal
-
-        let wasm_code = self.wasm_binary.clone().unwrap();
+    /// answers like a read and gives the keys up in the same message, so no write lands in between
+    fn handle_handoff(&mut self, args: HandOffArgs) -> CanisterManagerEventResponse {
+        Self::add_member(&mut self.canister, args.member);
+        let response = self.handle_read(args.keys.clone());
+        if let CanisterManagerEventResponse::Values(_) = response {
+            self.canister.take_foreign_data(&args.keys);
+        }
+        response
+    }
 
-        let install_args = management::InstallCodeArgument {
-            canister_id,
-            mode: management::InstallMode::Install,
-            wasm_module: wasm_code,
-            arg: Vec::<u8>::new(),
+    fn handle_scan(&self, args: ScanArgs) -> CanisterManagerEventResponse {
+        let page = match self
+            .canister
+            .scan(&args.start, args.end.as_ref(), args.limit as usize)
+        {
+            NodeResult::NodeId(owner) => {
+                return CanisterManagerEventResponse::Error(format!(
+                    "{} is owned by {}",
+                    args.start, owner
+                ))
+            }
+            NodeResult::Result(page) => page,
         };
+        let data = page.entries.iter().map(|(key, data)| (key, data)).collect();
+        match DataChunk::encode_borrowed(data, vec![], vec![]) {
+            Ok(data) => CanisterManagerEventResponse::Scanned(ScannedArgs {
+                data,
+                cursor: page.cursor,
+            }),
+            Err(error) => CanisterManagerEventResponse::Error(error),
+        }
+    }
 
-        let result = management::InstallCode::perform_with_payment(
-            Principal::management_canister(),
-            (install_args,),
-            10_000_000,
-        )
-        .await;
+    fn handle_prepare(&mut self, args: PrepareArgs) -> CanisterManagerEventResponse {
+        let now = ic::time();
+        if let Err(error) =
+            self.canister
+                .lock_keys(&args.transaction_id, &args.keys, now, now + args.timeout)
+        {
+            return CanisterManagerEventResponse::Error(error);
+        }
 
-        if result.is_err() {
-            self.status = NodeStatus::Error(NodeError::Initialize(format!(
-                "Failed to initialize node {}",
-                canister_id
-            )));
+        let data = args
+            .keys
+            .iter()
+            .filter_map(|key| self.canister.get_data(key).map(|data| (key, data)))
+            .collect();
+        match DataChunk::encode_borrowed(data, vec![], vec![]) {
+            Ok(data) => CanisterManagerEventResponse::Prepared(ValuesArgs { data }),
+            Err(error) => {
+                self.canister.unlock_keys(&args.transaction_id);
+                CanisterManagerEventResponse::Error(error)
+            }
+        }
+    }
 
-            return false;
+    /// applies the writes only if every written key is still locked by the transaction
+    fn handle_renew(&mut self, args: PrepareArgs) -> CanisterManagerEventResponse {
+        let now = ic::time();
+        match self
+            .canister
+            .renew_locks(&args.transaction_id, &args.keys, now, now + args.timeout)
+        {
+            Ok(()) => CanisterManagerEventResponse::Ok,
+            Err(error) => CanisterManagerEventResponse::Error(error),
         }
+    }
 
-        let args = InitCanisterManagerParam {
-            args: Some(InstallArgs {
-                all_nodes: self.canister.all_nodes().into_iter().cloned().collect(),
-            }),
+    fn handle_commit(&mut self, args: CommitArgs) -> CanisterManagerEventResponse {
+        let locked = self.canister.unlock_keys(&args.transaction_id);
+        let data_chunk = match DataChunk::<Data>::decode(&args.data) {
+            Ok(data_chunk) => data_chunk,
+            Err(error) => return CanisterManagerEventResponse::Error(error),
         };
 
-        let result = ic::call::<_, (), _>(canister_id, "init_canister_manager", (args,)).await;
-
-        if result.is_err() {
-            self.status = NodeStatus::Error(NodeError::Initialize(format!(
-                "Failed to initialize node {}",
-                canister_id
-            )));
-
-            return false;
+        let all_locked = data_chunk
+            .data
+            .iter()
+            .map(|(key, _)| key)
+            .chain(args.removed.iter())
+            .all(|key| locked.contains(key));
+        if !all_locked {
+            return CanisterManagerEventResponse::Error(format!(
+                "locks of transaction {} expired",
+                args.transaction_id
+            ));
         }
 
-        if !self.init_wasm(canister_id).await {
-            self.status = NodeStatus::Error(NodeError::Initialize(format!(
-                "Failed to initialize wasm {}",
-                canister_id
-            )));
-            return false;
+        for (key, data) in data_chunk.data {
+            self.canister.insert_data(key, data);
         }
-
-        true
+        self.canister.take_data(&args.removed);
+        CanisterManagerEventResponse::Ok
     }
 
-    async fn init_wasm(&self, canister_id: Principal) -> bool {
-        #[derive(CandidType, Deserialize)]
-        pub struct WasmInitArgs {
-            position: usize,
-            wasm_chunk: Vec<u8>,
+    /// Writes the node to `writer` in `pre_upgrade`, usually `stable::writer()`, its keys a
+    /// partition at a time so the heap never holds a second copy of the whole node. The local lock
+    /// is not kept, the upgrade ends the message holding it.
+    pub fn save_stable(&self, writer: &mut impl Write) -> Result<(), String> {
+        let state = StableCanisterManager {
+            node_id: self.canister.id,
+            status: self.status.clone(),
+            wasm_binary: self.wasm_binary.clone().map(serde_bytes::ByteBuf::from),
+            placement_history: self.canister.placement_history().to_vec(),
+            parent_id: self.canister.parent_id,
+            children: self.canister.children.clone(),
+            replication_factor: self.canister.replication_factor() as u64,
+            migration_mode: self.migration_mode,
+            controllers: self.controllers.clone(),
+            epoch: self.epoch,
+            applied_changes: self.canister.epoch(),
+            handoffs: self
+                .canister
+                .handoffs()
+                .into_iter()
+                .map(|position| position as u64)
+                .collect(),
+            topology_lease: self.topology_lease,
+            locks: self
+                .canister
+                .locks()
+                .map(|(key, transaction, expires_at)| {
+                    (key.clone(), transaction.clone(), expires_at)
+                })
+                .collect(),
+        };
+        write_frame(writer, &Encode!(&state).map_err(|e| e.to_string())?)?;
+        // removed keys keep their version too, including partitions that hold no data
+        for partition in 0..PARTITION_COUNT {
+            let keys: Vec<String> = self
+                .canister
+                .key_versions(partition)
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            if !keys.is_empty() {
+                write_frame(writer, &self.encode_keys(&keys)?)?;
+            }
         }
+        write_frame(writer, &[])
+    }
 
-        async fn send_wasm(args: WasmInitArgs, canister_id: Principal) -> bool {
-            let result = ic::call::<_, (bool,), _>(canister_id, "init_wasm", (args,)).await;
-            result.is_ok()
+    /// Rebuilds a node from `save_stable` in `post_upgrade`. The placement is replayed from its
+    /// history, so a hand-off in progress resumes with the same previous placements.
+    pub fn load_stable(
+        reader: &mut impl Read,
+        should_upgrade_func: fn(usize) -> bool,
+    ) -> Result<Self, String> {
+        let state =
+            Decode!(&read_frame(reader)?, StableCanisterManager).map_err(|e| e.to_string())?;
+        let mut manager = Self::new(state.node_id, should_upgrade_func);
+        manager.status = state.status;
+        manager.wasm_binary = state.wasm_binary.map(serde_bytes::ByteBuf::into_vec);
+        manager.migration_mode = state.migration_mode;
+        manager.controllers = state.controllers;
+        manager.topology_lease = state.topology_lease;
+        manager.epoch = state.epoch;
+        // `new` placed the node alone, the placement is rebuilt from its history
+        manager.canister = Node::with_placement(state.node_id, P::default());
+
+        let canister = &mut manager.canister;
+        canister.set_clock(ic::time);
+        canister.set_digest(candid_digest::<Data>);
+        canister.set_replication_factor(state.replication_factor as usize);
+        canister.parent_id = state.parent_id;
+        canister.children = state.children;
+        let handoffs: Vec<usize> = state
+            .handoffs
+            .into_iter()
+            .map(|position| position as usize)
+            .collect();
+        if !canister.restore_placement(state.placement_history, &handoffs) {
+            return Err("the placement history was refused".to_string());
+        }
+        canister.restore_epoch(state.applied_changes);
+        for (key, transaction, expires_at) in state.locks {
+            canister.restore_lock(key, transaction, expires_at);
         }
 
-        let mut byte_iterator = self
-            .wasm_binary
-            .as_ref()
-            .unwrap()
-            .chunks(1024 * 1024)
-            .into_iter();
+        loop {
+            let frame = read_frame(reader)?;
+            if frame.is_empty() {
+                break;
+            }
+            let data_chunk = DataChunk::<Data>::decode(&frame)?;
+            let entries = data_chunk
+                .data
+                .into_iter()
+                .zip(data_chunk.versions)
+                .zip(data_chunk.expires_at);
+            for (((key, value), version), expires_at) in entries {
+                canister.insert_versioned_data(key.clone(), value, version);
+                canister.set_expiry(&key, expires_at);
+            }
+            for (key, version) in data_chunk.removed {
+                canister.remove_versioned_data(&key, version);
+            }
+        }
+        Ok(manager)
+    }
 
-        if !send_wasm(
-            WasmInitArgs {
-                position: 0,
-                wasm_chunk: byte_iterator.next().unwrap().to_vec(),
-            },
-            canister_id,
-        )
-        .await
-        {
-            return false;
+    /// what a node created by this one is installed with, see `SharedCanisterManager::lifecyle_init_node`
+    pub fn install_args(&self) -> InstallArgs {
+        InstallArgs {
+            placement_history: self.canister.placement_history().to_vec(),
+            replication_factor: self.canister.replication_factor() as u32,
+            migration_mode: self.migration_mode,
+            controllers: self.controllers.clone(),
         }
+    }
 
-        while let Some(wasm_chunk) = byte_iterator.next() {
-            if !send_wasm(
-                WasmInitArgs {
-                    position: 1,
-                    wasm_chunk: wasm_chunk.to_vec(),
-                },
-                canister_id,
-            )
-            .await
-            {
-                return false;
-            }
+    pub fn node_info(&self) -> NodeInfo {
+        NodeInfo {
+            all_nodes: self
+                .canister
+                .all_nodes()
+                .iter()
+                .map(|&principal| principal.to_string())
+                .collect(),
+            parent_id: self.canister.parent_id,
+            children: self.canister.children.clone(),
+            epoch: self.epoch,
+            status: self.status.clone(),
+            cycles_balance: ic::balance(),
+            weights: self
+                .canister
+                .all_nodes()
+                .into_iter()
+                .map(|node_id| self.canister.weight(node_id))
+                .collect(),
+            placement_history: self.canister.placement_history().to_vec(),
+            replication_factor: self.canister.replication_factor() as u64,
+            partition_sizes: self
+                .canister
+                .partition_sizes()
+                .into_iter()
+                .map(|(partition, size)| (partition, size as u64))
+                .collect(),
         }
+    }
+}
 
-        if !send_wasm(
-            WasmInitArgs {
-                position: 2,
-                wasm_chunk: vec![],
-            },
-            canister_id,
-        )
-        .await
-        {
-            return false;
-        }
+/// Handle to a `CanisterManager` shared by the messages of a canister. While a message awaits a
+/// call, other messages run on the same manager, so every operation that makes calls lives on
+/// the handle and only borrows the manager between awaits. Clones refer to the same manager.
+pub struct SharedCanisterManager<Data: Default + Clone, P = AnchorPlacement<Principal>>(
+    Rc<RefCell<CanisterManager<Data, P>>>,
+);
 
-        true
+impl<Data: Default + Clone, P> Clone for SharedCanisterManager<Data, P> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
     }
+}
 
-    fn delete_node(&mut self) -> () {
-        // todo!()
-        // https://github.com/open-ic/open-storage/blob/main/backend/libraries/utils/src/canister/delete.rs
+impl<Data: Default + Clone, P> From<CanisterManager<Data, P>> for SharedCanisterManager<Data, P> {
+    fn from(manager: CanisterManager<Data, P>) -> Self {
+        Self(Rc::new(RefCell::new(manager)))
     }
+}
 
-    async fn migrate_to_node(&mut self, canister_id: Principal, data: Vec<(String, Data)>) -> bool {
-        #[derive(CandidType, Deserialize)]
-        struct Response {
-            result: bool,
-        }
-
-        let call_migrate = |args: MigrateArgs| async {
-            ic::call::<_, (), _>(
-                canister_id,
-                "handle_event",
-                (CanisterManagerEvent::Migrate(args),),
-            )
-            .await
-            .map(|_| true)
-            .map_err(|e| e.1)
-        };
+impl<Data, P> SharedCanisterManager<Data, P>
+where
+    Data: Default + Clone + CandidType + DeserializeOwned + Merge,
+    P: Placement<Principal> + Default,
+{
+    pub fn new(node_id: Principal, should_upgrade_func: fn(usize) -> bool) -> Self {
+        CanisterManager::new(node_id, should_upgrade_func).into()
+    }
 
-        let encode_data_chunk = |data_chunk: DataChunk<Data>| -> Result<MigrateArgs, String> {
-            data_chunk.encode().map(|data| MigrateArgs { data })
-        };
+    /// the borrow must be dropped before the next await, another message may need the manager
+    pub fn borrow(&self) -> Ref<'_, CanisterManager<Data, P>> {
+        self.0.borrow()
+    }
 
-        for data_chunk in data.chunks(100) {
-            let result = match encode_data_chunk(DataChunk::new(data_chunk.to_vec())) {
-                Ok(args) => call_migrate(args).await,
-                Err(error) => Err(error),
-            };
+    /// the borrow must be dropped before the next await, another message may need the manager
+    pub fn borrow_mut(&self) -> RefMut<'_, CanisterManager<Data, P>> {
+        self.0.borrow_mut()
+    }
 
-            match result {
-                Ok(response) => {
-                    if !response {
-                        self.status = NodeStatus::Error(NodeError::Migration(format!(
-                            "Failed to migrate data to node {}",
-                            canister_id
-                        )));
-                        return false;
+    /// without args the node keeps its replication factor, which the first node may set before
+    pub async fn lifecyle_init_node(&self, args: Option<InstallArgs>) -> () {
+        {
+            let mut manager = self.borrow_mut();
+            let node_id = manager.canister.id;
+            let mut new_canister: Canister<Data, P> = Node::with_placement(node_id, P::default());
+            new_canister.set_clock(ic::time);
+            new_canister.set_digest(candid_digest::<Data>);
+            new_canister.set_replication_factor(match &args {
+                Some(args) => args.replication_factor as usize,
+                None => manager.canister.replication_factor(),
+            });
+            if let Some(args) = &args {
+                manager.migration_mode = args.migration_mode;
+                for controller in &args.controllers {
+                    if !manager.is_controller(controller) {
+                        manager.controllers.push(*controller);
                     }
                 }
-                Err(error) => {
-                    self.status = NodeStatus::Error(NodeError::Migration(error));
-                    return false;
+            }
+
+            let history = args.map(|args| args.placement_history).unwrap_or_default();
+            if !history.is_empty() {
+                // the node that scaled up adds this one last before installing it, which starts
+                // the hand-off of its keys
+                let handoffs = match history.last() {
+                    Some(PlacementChange::Add(id) | PlacementChange::AddAt(id, _))
+                        if *id == node_id =>
+                    {
+                        vec![history.len() - 1]
+                    }
+                    _ => vec![],
+                };
+                new_canister.restore_placement(history, &handoffs);
+                new_canister.add_node(node_id);
+                let caller = ic::caller();
+                if caller != node_id && new_canister.all_nodes().contains(&&caller) {
+                    new_canister.parent_id = Some(caller);
                 }
             }
+
+            manager.canister = new_canister;
         }
 
-        true
+        // the parent announces this node once every node has migrated its keys for it, until
+        // then missing keys are fetched from their previous owners
+        let mut manager = self.borrow_mut();
+        if manager.canister.parent_id.is_none() && manager.migration_mode == MigrationMode::Eager {
+            manager.canister.finish_handoff();
+        }
     }
 
-    fn handle_migrate(&mut self, args: MigrateArgs) -> bool {
-        match DataChunk::<Data>::decode(&args.data) {
-            Ok(data_chunk) => {
-                let data_chunk = data_chunk.data;
-                for (key, value) in data_chunk {
-                    self.canister.insert_data(key, value);
-                }
-                true
+    /// Admin operation: changes the share of partitions `member.id` owns across the cluster.
+    /// The new weight is broadcast and every node, this one included, migrates the partitions
+    /// that changed owner. Returns false without changing anything when the caller isn't a
+    /// controller or while another membership change is in progress.
+    pub async fn lifecycle_set_weight(&self, member: NodeMember) -> bool {
+        if !self.borrow().is_controller(&ic::caller()) {
+            return false;
+        }
+        if !self.borrow_mut().try_lock() {
+            return false;
+        }
+        let result = self.change_weight(member).await;
+        self.borrow_mut().unlock();
+        result
+    }
+
+    async fn change_weight(&self, member: NodeMember) -> bool {
+        if !self.acquire_lease().await {
+            return false;
+        }
+        let changed = self
+            .borrow_mut()
+            .canister
+            .set_weight(&member.id, member.weight);
+        let result = match changed {
+            true => {
+                let epoch = self.borrow_mut().next_epoch();
+                self.broadcast_event(CanisterManagerEvent::WeightChanged(MembershipChange {
+                    member,
+                    epoch,
+                }))
+                .await;
+                self.migrate_data_to_owners().await
             }
-            Err(e) => {
-                self.status = NodeStatus::Error(NodeError::Migration(
-                    "Failed to handle migrate data to node".to_string(),
-                ));
-                false
+            false => false,
+        };
+        self.release_lease().await;
+        result
+    }
+
+    pub async fn lifecyle_heartbeat_node(&self) -> () {
+        {
+            let mut manager = self.borrow_mut();
+            // locks of coordinators that failed between prepare and commit
+            manager.canister.release_expired_locks(ic::time());
+            manager.canister.remove_expired(EXPIRED_KEYS_PER_HEARTBEAT);
+
+            // a beat still awaiting its calls would interleave with this one
+            if !manager.try_lock() {
+                return;
             }
         }
+        self.run_heartbeat().await;
+        self.borrow_mut().unlock();
     }
 
-    pub async fn lifecycle_handle_event(&mut self, event: CanisterManagerEvent) -> () {
-        match event {
-            CanisterManagerEvent::NodeCreated(node_id) => {
-                if node_id != self.canister.id {
-                    self.canister.add_node(node_id);
-                    self.migrate_data(node_id).await;
+    async fn run_heartbeat(&self) {
+        let (should_scale_up, should_scale_down, is_ready) = {
+            let manager = self.borrow();
+            (
+                manager.should_scale_up(),
+                manager.should_scale_down(),
+                matches!(manager.status, NodeStatus::Ready),
+            )
+        };
+
+        if should_scale_up {
+            // ordered placements split this node's range, which is the fullest one, at its median key
+            let split_key = self.borrow().canister.split_key();
+            if self.borrow().canister.placement_is_ordered() && split_key.is_none() {
+                return;
+            }
+            // another membership change is in progress, this node tries again on a later beat
+            if !self.acquire_lease().await {
+                return;
+            }
+            self.scale_up(split_key).await;
+            self.release_lease().await;
+        } else if should_scale_down {
+            // self.status = NodeStatus::ScaleDown;
+            // self.broadcast_event(CanisterManagerEvent::NodeDeleted(self.canister.id));
+        } else if is_ready {
+            // anti-entropy would hand over every misplaced key at once, so it waits for the sweep
+            let mut sweeping = false;
+            if self.borrow().migration_mode == MigrationMode::Lazy {
+                sweeping = self
+                    .hand_over_misplaced(LAZY_MIGRATION_KEYS_PER_HEARTBEAT)
+                    .await
+                    != Ok(0);
+            }
+            let is_handing_off = self.borrow().canister.is_handing_off();
+            if is_handing_off {
+                self.finish_handoff_if_done().await;
+            }
+            // anti-entropy with one other node per beat, drift is repaired by a later beat if this fails
+            let sync_node = {
+                let mut manager = self.borrow_mut();
+                let other_nodes = manager.other_nodes();
+                match sweeping || other_nodes.is_empty() {
+                    true => None,
+                    false => {
+                        let node_id = other_nodes[manager.sync_cursor % other_nodes.len()];
+                        manager.sync_cursor = manager.sync_cursor.wrapping_add(1);
+                        Some(node_id)
+                    }
                 }
+            };
+            if let Some(node_id) = sync_node {
+                let _ = self.sync_with(node_id).await;
             }
-            CanisterManagerEvent::NodeDeleted(node_id) => {
-                if node_id != self.canister.id {
-                    self.canister.remove_node(&node_id);
-                    self.migrate_data(node_id).await;
+        }
+    }
+
+    /// creates a child of this node and moves it its share of the keys, the caller holds the
+    /// topology lease
+    async fn scale_up(&self, split_key: Option<String>) {
+        self.borrow_mut().status = NodeStatus::ScaleUp;
+        let create_node_result = self.create_node().await;
+
+        match create_node_result {
+            Some(new_node_id) => {
+                match split_key {
+                    Some(split_key) => self
+                        .borrow_mut()
+                        .canister
+                        .add_node_at(new_node_id, split_key),
+                    None => self.borrow_mut().canister.add_node(new_node_id),
+                };
+                let result = self.initialize_node(new_node_id).await;
+                if !result {
+                    let mut manager = self.borrow_mut();
+                    manager.canister.remove_node(&new_node_id);
+                    manager.status = NodeStatus::Error(NodeError::Initialize(format!(
+                        "Failed to initialize node {}",
+                        new_node_id
+                    )));
+
+                    return;
+                }
+                // nodes only accept events from the nodes they know, so the new node can't
+                // announce itself
+                let change = {
+                    let mut manager = self.borrow_mut();
+                    MembershipChange {
+                        member: manager.member(new_node_id),
+                        epoch: manager.next_epoch(),
+                    }
+                };
+                let other_nodes: Vec<Principal> = self
+                    .borrow()
+                    .other_nodes()
+                    .into_iter()
+                    .filter(|node_id| *node_id != new_node_id)
+                    .collect();
+                self.broadcast_event_to(
+                    other_nodes,
+                    CanisterManagerEvent::NodeCreated(change.clone()),
+                )
+                .await;
+                self.borrow_mut().status = NodeStatus::Migrating;
+                let result = self.migrate_data(new_node_id).await;
+
+                if !result {
+                    let mut manager = self.borrow_mut();
+                    manager.canister.remove_node(&new_node_id);
+                    manager.status = NodeStatus::Error(NodeError::Migration(format!(
+                        "Failed to migrate data to node {}",
+                        new_node_id
+                    )));
+                    return;
+                }
+
+                {
+                    let mut manager = self.borrow_mut();
+                    manager.status = NodeStatus::Ready;
+                    manager.canister.children.push(new_node_id);
                 }
+                // every node has migrated its keys, the new node is told last
+                self.broadcast_event_to(
+                    vec![new_node_id],
+                    CanisterManagerEvent::NodeCreated(change),
+                )
+                .await;
             }
-            CanisterManagerEvent::Migrate(migrate_args) => {
-                self.handle_migrate(migrate_args);
+            None => {
+                self.borrow_mut().status =
+                    NodeStatus::Error(NodeError::ScaleUp("Failed to create node".to_string()));
             }
         }
     }
 
-    async fn migrate_data(&mut self, node_id: Principal) -> bool {
-        let data_for_migration = self.canister.get_data_to_migrate();
-        let result = self.migrate_to_node(node_id, data_for_migration).await;
-        result
+    /// asks for the cluster-wide lease every membership change holds while it runs
+    async fn acquire_lease(&self) -> bool {
+        let (coordinator, node_id) = {
+            let manager = self.borrow();
+            (manager.lease_coordinator(), manager.canister.id)
+        };
+        if coordinator == node_id {
+            return matches!(
+                self.borrow_mut().handle_acquire_lease(node_id),
+                CanisterManagerEventResponse::Ok
+            );
+        }
+        self.send_sync_event(
+            coordinator,
+            CanisterManagerEvent::AcquireTopologyLease(node_id),
+        )
+        .await
+        .is_ok()
+    }
+
+    /// a lost release is cleared by the lease's expiry
+    async fn release_lease(&self) {
+        let (coordinator, node_id) = {
+            let manager = self.borrow();
+            (manager.lease_coordinator(), manager.canister.id)
+        };
+        if coordinator == node_id {
+            self.borrow_mut().handle_release_lease(node_id);
+        } else {
+            let event = CanisterManagerEvent::ReleaseTopologyLease(node_id);
+            let _ = self.send_sync_event(coordinator, event).await;
+        }
     }
 
-    async fn broadcast_event(&mut self, event: CanisterManagerEvent) -> () {
-        let all_canisters = self.canister.all_nodes();
-        for &canister_id in all_canisters {
-            if self.canister.id != canister_id {
-                let result =
-                    ic::call::<_, (), _>(canister_id, "handle_event", (event.clone(),)).await;
+    /// Anti-entropy: compares the merkle trees of this node and `node_id` from the root down,
+    /// only through subtrees of partitions both replicate, then sends the keys of the differing
+    /// partitions `node_id` is missing or holds at an older version. Keys this node no longer
+    /// replicates, left behind by a failed migration, are given up once `node_id` has them.
+    /// Each node only sends, so a pair is fully repaired once both ran it. Returns the number of
+    /// keys sent.
+    pub async fn sync_with(&self, node_id: Principal) -> Result<usize, String> {
+        let shared: Vec<bool> = {
+            let manager = self.borrow();
+            (0..PARTITION_COUNT)
+                .map(|partition| manager.canister.shares_partition(&node_id, partition))
+                .collect()
+        };
 
-                if let Err(e) = result {
-                    self.status = NodeStatus::Error(NodeError::Broadcast(format!(
-                        "Failed to broadcast event, error {} to node {}",
-                        e.1, canister_id
-                    )));
+        let mut differing = vec![];
+        let mut indexes = vec![ROOT];
+        while !indexes.is_empty() {
+            indexes.retain(|index| MerkleTree::partitions(*index).any(|p| shared[p as usize]));
+            if indexes.is_empty() {
+                break;
+            }
+            let event = CanisterManagerEvent::TreeHashes(indexes.clone());
+            let hashes = match self.send_sync_event(node_id, event).await? {
+                CanisterManagerEventResponse::TreeHashes(hashes) => hashes,
+                _ => return Err(format!("node {} did not send its tree", node_id)),
+            };
+
+            let manager = self.borrow();
+            let tree = manager.canister.merkle_tree();
+            let mut children = vec![];
+            for (index, hash) in indexes.into_iter().zip(hashes) {
+                if tree.hash(index) == hash {
+                    continue;
+                }
+                match MerkleTree::children(index) {
+                    Some(pair) => children.extend(pair),
+                    None => differing.extend(MerkleTree::partitions(index)),
                 }
             }
+            indexes = children;
         }
+
+        let mut sent = 0;
+        for partitions in differing.chunks(SYNC_PARTITIONS_PER_CALL) {
+            let event = CanisterManagerEvent::KeyVersions(partitions.to_vec());
+            let remote_versions: HashMap<String, u64> =
+                match self.send_sync_event(node_id, event).await? {
+                    CanisterManagerEventResponse::KeyVersions(versions) => {
+                        versions.into_iter().collect()
+                    }
+                    _ => return Err(format!("node {} did not send its versions", node_id)),
+                };
+
+            let behind =
+                self.borrow()
+                    .canister
+                    .keys_behind_on(&node_id, partitions, &remote_versions);
+            for keys_chunk in behind.chunks(100) {
+                self.send_data(node_id, keys_chunk).await?;
+                self.borrow_mut().canister.take_foreign_data(keys_chunk);
+                sent += keys_chunk.len();
+            }
+            let held_remotely: Vec<String> = remote_versions.into_keys().collect();
+            self.borrow_mut().canister.take_foreign_data(&held_remotely);
+        }
+        Ok(sent)
     }
 
-    pub fn node_info(&self) -> NodeInfo {
-        NodeInfo {
-            all_nodes: self
-                .canister
-                .all_nodes()
+    /// Ends the hand-off once no other node holds keys this node owns, checked with their audits.
+    async fn finish_handoff_if_done(&self) {
+        let (epoch, node_id, other_nodes) = {
+            let manager = self.borrow();
+            (
+                manager.canister.epoch(),
+                manager.canister.id,
+                manager.other_nodes(),
+            )
+        };
+        for other_node in other_nodes {
+            let report = match self
+                .send_sync_event(other_node, CanisterManagerEvent::Audit(false))
+                .await
+            {
+                Ok(CanisterManagerEventResponse::Audit(report)) => report,
+                _ => return,
+            };
+            if report
+                .misplaced_by_owner
                 .iter()
-                .map(|&principal| principal.to_string())
-                .collect(),
-            next_node_id: self.canister.next_node_id,
-            prev_node_id: self.canister.prev_node_id,
-            status: self.status.clone(),
-            cycles_balance: ic::balance(),
+                .any(|(owner, count)| *owner == node_id && *count > 0)
+            {
+                return;
+            }
+        }
+        // a membership change while waiting for the audits started another hand-off
+        let mut manager = self.borrow_mut();
+        if manager.canister.epoch() == epoch {
+            manager.canister.finish_handoff();
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::node_manager::NodeStatus;
 
-    use super::CanisterManager;
-    use super::WasmInitArgs;
-    use async_std::test as async_test;
-    use ic_kit::mock_principals;
-    use ic_kit::MockContext;
-    use ic_kit::Principal;
+    /// Hands the misplaced keys to every one of their replicas and gives up the ones all of them
+    /// acknowledged. Replicas keep their copy when it is newer, see `handle_migrate`. Returns the
+    /// number of keys given up.
+    pub async fn repair_placement(&self) -> Result<u64, String> {
+        self.hand_over_misplaced(usize::MAX).await
+    }
 
-    #[test]
-    fn new_node() {
-        let node_id = Principal::anonymous();
-        let cm = CanisterManager::<String>::new(node_id, |size| size > 10);
-        let node_info = cm.node_info();
+    async fn hand_over_misplaced(&self, limit: usize) -> Result<u64, String> {
+        let misplaced = self.borrow().canister.misplaced_keys(limit);
+        let mut failed: HashSet<String> = HashSet::new();
+        let mut errors = vec![];
+        for (node_id, keys) in &misplaced {
+            for keys_chunk in keys.chunks(100) {
+                if let Err(error) = self.send_data(*node_id, keys_chunk).await {
+                    failed.extend(keys_chunk.iter().cloned());
+                    errors.push(error);
+                }
+            }
+        }
 
-        assert_eq!(node_info.all_nodes, vec![node_id.to_string()]);
+        let delivered: HashSet<&String> = misplaced
+            .values()
+            .flatten()
+            .filter(|key| !failed.contains(*key))
+            .collect();
+        let delivered: Vec<String> = delivered.into_iter().cloned().collect();
+        let repaired = self
+            .borrow_mut()
+            .canister
+            .take_foreign_data(&delivered)
+            .len() as u64;
+        match errors.is_empty() {
+            true => Ok(repaired),
+            false => Err(errors.join(", ")),
+        }
     }
 
-    #[async_test]
-    async fn node_initialized_properly() {
-        let node_id = mock_principals::alice();
-        let previous_node = mock_principals::bob();
+    async fn audit_node(&self, repair: bool) -> AuditReport {
+        if !repair {
+            return self.borrow().audit();
+        }
+        let misplaced = self.borrow().audit().misplaced;
+        let repair_error = self.repair_placement().await.err();
+        let report = self.borrow().audit();
+        AuditReport {
+            repaired: misplaced.saturating_sub(report.misplaced),
+            repair_error,
+            ..report
+        }
+    }
 
-        MockContext::new()
-            .with_caller(previous_node.clone())
-            .with_id(node_id.clone())
-            .with_constant_return_handler(())
-            .inject();
+    /// `audit`, and `repair_placement` when `repair` is true, on every node of the cluster. Only
+    /// controllers and nodes of the cluster may repair.
+    pub async fn audit_cluster(&self, repair: bool) -> Vec<Result<AuditReport, String>> {
+        let caller = ic::caller();
+        if repair && !self.borrow().is_controller(&caller) && !self.borrow().is_member(&caller) {
+            return vec![Err(format!("{} may not repair the placement", caller))];
+        }
+        let mut reports = vec![Ok(self.audit_node(repair).await)];
+        let other_nodes = self.borrow().other_nodes();
+        for node_id in other_nodes {
+            let report = match self
+                .send_sync_event(node_id, CanisterManagerEvent::Audit(repair))
+                .await
+            {
+                Ok(CanisterManagerEventResponse::Audit(report)) => Ok(report),
+                Ok(_) => Err(format!("node {} did not send its audit", node_id)),
+                Err(error) => Err(error),
+            };
+            reports.push(report);
+        }
+        reports
+    }
 
-        let mut cm = CanisterManager::<String>::new(node_id.clone(), |size| size > 10);
-        let all_nodes = vec![previous_node.clone()];
+    async fn send_sync_event(
+        &self,
+        node_id: Principal,
+        event: CanisterManagerEvent,
+    ) -> Result<CanisterManagerEventResponse, String> {
+        let (response,) =
+            ic::call::<_, (CanisterManagerEventResponse,), _>(node_id, "handle_event", (event,))
+                .await
+                .map_err(|e| e.1)?;
+        match response {
+            CanisterManagerEventResponse::Error(error) => Err(error),
+            response => Ok(response),
+        }
+    }
 
-        cm.lifecyle_init_node(Some(all_nodes)).await;
-        let node_info = cm.node_info();
+    async fn create_node(&self) -> Option<Principal> {
+        let (node_id, node_count) = {
+            let manager = self.borrow();
+            (manager.canister.id, manager.canister.all_nodes().len())
+        };
+        let arg = management::CreateCanisterArgument {
+            settings: Some(CanisterSettings {
+                compute_allocation: None,
+                controllers: Some(vec![node_id]),
+                freezing_threshold: None,
+                memory_allocation: None, // reserve_memory: self.reserve_memory,
+            }),
+        };
 
-        assert_eq!(
-            node_info.all_nodes,
-            vec![previous_node.to_string(), node_id.to_string()]
-        );
+        let result = management::CreateCanister::perform_with_payment(
+            Principal::management_canister(),
+            (arg,),
+            ic::balance().div(node_count.add(1) as u64),
+        )
+        .await;
 
-        assert_eq!(cm.canister.prev_node_id, Some(previous_node));
-        matches!(cm.get_status(), NodeStatus::Initialized);
+        match result {
+            Ok((result,)) => Some(result.canister_id),
+            Err(_) => None,
+        }
     }
 
-    #[test]
-    fn node_wasm_initialized_properly() {
-        let node_id = mock_principals::alice();
-        let mut cm = CanisterManager::<String>::new(node_id.clone(), |size| size > 10);
+    async fn initialize_node(&self, canister_id: Principal) -> bool {
+        //vector of &Principal to Principal
 
-        assert!(cm.lifecycle_init_wasm(WasmInitArgs {
-            position: 0,
-            wasm_chunk: Vec::<u8>::default(),
-        }));
+        let wasm_code = self.borrow().wasm_binary.clone().unwrap();
 
-        assert!(cm.lifecycle_init_wasm(WasmInitArgs {
-            position: 1,
-            wasm_chunk: Vec::<u8>::default(),
-        }));
+        let install_args = management::InstallCodeArgument {
+            canister_id,
+            mode: management::InstallMode::Install,
+            wasm_module: wasm_code,
+            arg: Vec::<u8>::new(),
+        };
 
-        assert!(cm.lifecycle_init_wasm(WasmInitArgs {
-            position: 2,
-            wasm_chunk: Vec::<u8>::default(),
-        }));
+        let result = management::InstallCode::perform_with_payment(
+            Principal::management_canister(),
+            (install_args,),
+            10_000_000,
+        )
+        .await;
 
-        matches!(cm.get_status(), NodeStatus::Ready);
+        if result.is_err() {
+            self.borrow_mut().status = NodeStatus::Error(NodeError::Initialize(format!(
+                "Failed to initialize node {}",
+                canister_id
+            )));
+
+            return false;
+        }
+
+        let args = InitCanisterManagerParam {
+            args: Some(self.borrow().install_args()),
+        };
+
+        let result = ic::call::<_, (), _>(canister_id, "init_canister_manager", (args,)).await;
+
+        if result.is_err() {
+            self.borrow_mut().status = NodeStatus::Error(NodeError::Initialize(format!(
+                "Failed to initialize node {}",
+                canister_id
+            )));
+
+            return false;
+        }
+
+        if !self.init_wasm(canister_id).await {
+            self.borrow_mut().status = NodeStatus::Error(NodeError::Initialize(format!(
+                "Failed to initialize wasm {}",
+                canister_id
+            )));
+            return false;
+        }
+
+        true
+    }
+
+    async fn init_wasm(&self, canister_id: Principal) -> bool {
+        #[derive(CandidType, Deserialize)]
+        pub struct WasmInitArgs {
+            position: usize,
+            wasm_chunk: Vec<u8>,
+        }
+
+        async fn send_wasm(args: WasmInitArgs, canister_id: Principal) -> bool {
+            let result = ic::call::<_, (bool,), _>(canister_id, "init_wasm", (args,)).await;
+            result.is_ok()
+        }
+
+        let wasm_binary = self.borrow().wasm_binary.clone().unwrap();
+        let mut byte_iterator = wasm_binary.chunks(1024 * 1024).into_iter();
+
+        if !send_wasm(
+            WasmInitArgs {
+                position: 0,
+                wasm_chunk: byte_iterator.next().unwrap().to_vec(),
+            },
+            canister_id,
+        )
+        .await
+        {
+            return false;
+        }
+
+        while let Some(wasm_chunk) = byte_iterator.next() {
+            if !send_wasm(
+                WasmInitArgs {
+                    position: 1,
+                    wasm_chunk: wasm_chunk.to_vec(),
+                },
+                canister_id,
+            )
+            .await
+            {
+                return false;
+            }
+        }
+
+        if !send_wasm(
+            WasmInitArgs {
+                position: 2,
+                wasm_chunk: vec![],
+            },
+            canister_id,
+        )
+        .await
+        {
+            return false;
+        }
+
+        true
+    }
+
+    /// copies the values of `keys` with their versions and expiries to `canister_id`
+    async fn send_data(&self, canister_id: Principal, keys: &[String]) -> Result<(), String> {
+        let data = self.borrow().encode_keys(keys)?;
+        ic::call::<_, (), _>(
+            canister_id,
+            "handle_event",
+            (CanisterManagerEvent::Migrate(MigrateArgs { data }),),
+        )
+        .await
+        .map_err(|e| e.1)
+    }
+
+    async fn migrate_to_node(&self, canister_id: Principal, keys: Vec<String>) -> bool {
+        for keys_chunk in keys.chunks(100) {
+            match self.send_data(canister_id, keys_chunk).await {
+                Ok(()) => {
+                    // the target acknowledged the chunk, so the values can be given up
+                    // unless this node is still one of their replicas
+                    self.borrow_mut().canister.take_foreign_data(keys_chunk);
+                }
+                Err(error) => {
+                    self.borrow_mut().status = NodeStatus::Error(NodeError::Migration(error));
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    pub async fn lifecycle_handle_event(
+        &self,
+        event: CanisterManagerEvent,
+    ) -> CanisterManagerEventResponse {
+        match event {
+            CanisterManagerEvent::NodeCreated(MembershipChange { member, epoch }) => {
+                self.borrow_mut().adopt_epoch(epoch);
+                let node_id = member.id;
+                if node_id != self.borrow().canister.id {
+                    CanisterManager::add_member(&mut self.borrow_mut().canister, member);
+                    self.migrate_data(node_id).await;
+                } else if self.borrow().migration_mode == MigrationMode::Eager {
+                    // announced by the parent once every node has migrated its keys for this one
+                    self.borrow_mut().canister.finish_handoff();
+                }
+            }
+            CanisterManagerEvent::NodeDeleted(node_id) => {
+                if node_id != self.borrow().canister.id {
+                    self.borrow_mut().canister.remove_node(&node_id);
+                    self.migrate_data(node_id).await;
+                }
+            }
+            CanisterManagerEvent::Migrate(migrate_args) => {
+                self.borrow_mut().handle_migrate(migrate_args);
+            }
+            CanisterManagerEvent::WeightChanged(MembershipChange { member, epoch }) => {
+                self.borrow_mut().adopt_epoch(epoch);
+                let changed = self
+                    .borrow_mut()
+                    .canister
+                    .set_weight(&member.id, member.weight);
+                if changed {
+                    self.migrate_data_to_owners().await;
+                }
+            }
+            CanisterManagerEvent::Prepare(args) => return self.borrow_mut().handle_prepare(args),
+            CanisterManagerEvent::Commit(args) => return self.borrow_mut().handle_commit(args),
+            CanisterManagerEvent::Renew(args) => return self.borrow_mut().handle_renew(args),
+            CanisterManagerEvent::Abort(transaction_id) => {
+                self.borrow_mut().canister.unlock_keys(&transaction_id);
+            }
+            CanisterManagerEvent::Read(keys) => return self.borrow().handle_read(keys),
+            CanisterManagerEvent::HandOff(args) => return self.borrow_mut().handle_handoff(args),
+            CanisterManagerEvent::Scan(args) => return self.borrow().handle_scan(args),
+            CanisterManagerEvent::Audit(repair) => {
+                return CanisterManagerEventResponse::Audit(self.audit_node(repair).await)
+            }
+            CanisterManagerEvent::TreeHashes(indexes) => {
+                let manager = self.borrow();
+                let tree = manager.canister.merkle_tree();
+                return CanisterManagerEventResponse::TreeHashes(
+                    indexes.into_iter().map(|index| tree.hash(index)).collect(),
+                );
+            }
+            CanisterManagerEvent::AcquireTopologyLease(node_id) => {
+                return self.borrow_mut().handle_acquire_lease(node_id)
+            }
+            CanisterManagerEvent::ReleaseTopologyLease(node_id) => {
+                self.borrow_mut().handle_release_lease(node_id)
+            }
+            CanisterManagerEvent::KeyVersions(partitions) => {
+                let manager = self.borrow();
+                return CanisterManagerEventResponse::KeyVersions(
+                    partitions
+                        .into_iter()
+                        .flat_map(|partition| manager.canister.key_versions(partition))
+                        .collect(),
+                );
+            }
+        }
+        CanisterManagerEventResponse::Ok
+    }
+
+    /// Reads `key` from its previous owners while this node is taking it over and hasn't received
+    /// it yet, the newest first, see `Node::handoff_sources`. None outside of a hand-off. Doesn't
+    /// change any state, so reads can fall back on it instead of answering "not found".
+    pub async fn read_during_handoff(&self, key: &str) -> Result<Option<Versioned<Data>>, String> {
+        let sources: Vec<Principal> = self
+            .borrow()
+            .canister
+            .handoff_sources(key)
+            .into_iter()
+            .copied()
+            .collect();
+        for source in sources {
+            if let Some(value) = self.read_from(source, key).await? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Moves `key` from its previous owners to this node if it hasn't arrived yet, to be called
+    /// before writing it so the write applies to the current value instead of creating a new one.
+    /// Returns true if the key was fetched.
+    pub async fn pull_during_handoff(&self, key: &str) -> Result<bool, String> {
+        let (sources, member) = {
+            let manager = self.borrow();
+            let sources: Vec<Principal> = manager
+                .canister
+                .handoff_sources(key)
+                .into_iter()
+                .copied()
+                .collect();
+            (sources, manager.member(manager.canister.id))
+        };
+        for source in sources {
+            let event = CanisterManagerEvent::HandOff(HandOffArgs {
+                member: member.clone(),
+                keys: vec![key.to_string()],
+            });
+            match self.send_sync_event(source, event).await? {
+                CanisterManagerEventResponse::Values(args) => {
+                    let mut manager = self.borrow_mut();
+                    if !manager.handle_migrate(MigrateArgs { data: args.data }) {
+                        return Err(format!("failed to decode {} from node {}", key, source));
+                    }
+                    if manager.canister.handoff_sources(key).is_empty() {
+                        return Ok(true);
+                    }
+                }
+                _ => return Err(format!("node {} did not hand off {}", source, key)),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Like `replicate` for one key, but succeeds only once `level` of the key's replicas, this
+    /// node included, hold the write. The write is sent to every replica and the call returns as
+    /// soon as enough of them acknowledged it. Returns the number of acknowledgements.
+    pub async fn replicate_with_consistency(
+        &self,
+        key: &String,
+        level: ConsistencyLevel,
+    ) -> Result<usize, String> {
+        let (other_replicas, required, local) = {
+            let manager = self.borrow();
+            let replicas = manager.canister.replicas_of(key);
+            let required = level.required(replicas.len());
+            let local = usize::from(replicas.contains(&&manager.canister.id));
+            let other_replicas: Vec<Principal> = replicas
+                .into_iter()
+                .filter(|node_id| **node_id != manager.canister.id)
+                .copied()
+                .collect();
+            (other_replicas, required, local)
+        };
+
+        let calls = other_replicas
+            .into_iter()
+            .map(|node_id| self.send_data(node_id, std::slice::from_ref(key)))
+            .collect();
+        let acknowledged = gather(calls, required.saturating_sub(local)).await?;
+        Ok(acknowledged.len() + local)
+    }
+
+    /// Reads `key` from `level` of its replicas, this node included, and returns the newest
+    /// version among the answers. None if none of them holds the key.
+    pub async fn read_with_consistency(
+        &self,
+        key: &String,
+        level: ConsistencyLevel,
+    ) -> Result<Option<Versioned<Data>>, String> {
+        let mut newest = None;
+        let mut local = 0;
+        let (other_replicas, required) = {
+            let manager = self.borrow();
+            let replicas = manager.canister.replicas_of(key);
+            let required = level.required(replicas.len());
+            if replicas.contains(&&manager.canister.id) {
+                local = 1;
+                newest = manager.canister.get_data(key).map(|data| Versioned {
+                    value: data.clone(),
+                    version: manager.canister.version(key),
+                });
+            }
+            let other_replicas: Vec<Principal> = replicas
+                .into_iter()
+                .filter(|node_id| **node_id != manager.canister.id)
+                .copied()
+                .collect();
+            (other_replicas, required)
+        };
+        if required <= local {
+            return Ok(newest);
+        }
+
+        let calls = other_replicas
+            .into_iter()
+            .map(|node_id| self.read_from(node_id, key))
+            .collect();
+        for answer in gather(calls, required - local).await?.into_iter().flatten() {
+            if newest
+                .as_ref()
+                .is_none_or(|newest: &Versioned<Data>| answer.version > newest.version)
+            {
+                newest = Some(answer);
+            }
+        }
+        Ok(newest)
+    }
+
+    async fn read_from(
+        &self,
+        node_id: Principal,
+        key: &str,
+    ) -> Result<Option<Versioned<Data>>, String> {
+        let event = CanisterManagerEvent::Read(vec![key.to_string()]);
+        let response =
+            ic::call::<_, (CanisterManagerEventResponse,), _>(node_id, "handle_event", (event,))
+                .await
+                .map_err(|e| e.1)?;
+        match response.0 {
+            CanisterManagerEventResponse::Values(args) => {
+                let data_chunk = DataChunk::<Data>::decode(&args.data)?;
+                Ok(data_chunk
+                    .data
+                    .into_iter()
+                    .zip(data_chunk.versions)
+                    .next()
+                    .map(|((_, value), version)| Versioned { value, version }))
+            }
+            CanisterManagerEventResponse::Error(error) => Err(error),
+            _ => Err(format!("node {} did not answer the read", node_id)),
+        }
+    }
+
+    /// Up to `limit` entries with keys in `[start, end)` in key order, from every node owning a
+    /// part of the range. Pages are read locally or from the owner of their start, following the
+    /// cursor of `Node::scan` from range to range. The returned cursor is the `start` of the next
+    /// call, None once the scan has reached `end`. Only ordered placements return entries.
+    pub async fn scan(
+        &self,
+        start: String,
+        end: Option<String>,
+        limit: usize,
+    ) -> Result<ScanPage<Data>, String> {
+        let mut entries = vec![];
+        let mut cursor = Some(start);
+        while let Some(start) = cursor.take() {
+            if entries.len() >= limit {
+                cursor = Some(start);
+                break;
+            }
+            let remaining = limit - entries.len();
+            let result = self.borrow().canister.scan(&start, end.as_ref(), remaining);
+            let page = match result {
+                NodeResult::Result(page) => page,
+                NodeResult::NodeId(owner) => {
+                    let args = ScanArgs {
+                        start,
+                        end: end.clone(),
+                        limit: remaining as u64,
+                    };
+                    self.scan_on(owner, args).await?
+                }
+            };
+            entries.extend(page.entries);
+            cursor = page.cursor;
+        }
+        Ok(ScanPage { entries, cursor })
+    }
+
+    async fn scan_on(&self, node_id: Principal, args: ScanArgs) -> Result<ScanPage<Data>, String> {
+        match self
+            .send_sync_event(node_id, CanisterManagerEvent::Scan(args))
+            .await?
+        {
+            CanisterManagerEventResponse::Scanned(args) => Ok(ScanPage {
+                entries: DataChunk::<Data>::decode(&args.data)?.data,
+                cursor: args.cursor,
+            }),
+            _ => Err(format!("node {} did not answer the scan", node_id)),
+        }
+    }
+
+    /// Copies the current values of `keys` to their other replicas, call it after writing them when
+    /// the replication factor is above 1. Replicas that can't be reached get the keys again with
+    /// the next membership change.
+    pub async fn replicate(&self, keys: Vec<String>) -> Result<(), String> {
+        let mut keys_by_node: HashMap<Principal, Vec<String>> = HashMap::new();
+        {
+            let manager = self.borrow();
+            for key in keys {
+                for &node_id in manager.canister.replicas_of(&key) {
+                    if node_id != manager.canister.id {
+                        keys_by_node.entry(node_id).or_default().push(key.clone());
+                    }
+                }
+            }
+        }
+
+        let mut errors = vec![];
+        for (node_id, keys) in keys_by_node {
+            for keys_chunk in keys.chunks(100) {
+                if let Err(error) = self.send_data(node_id, keys_chunk).await {
+                    errors.push(format!("{}: {}", node_id, error));
+                    break;
+                }
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join(", ")),
+        }
+    }
+
+    /// Like `forward_request` but tries the replicas of `key` in order until one answers, so reads
+    /// still succeed while the key's owner is unreachable.
+    pub async fn forward_read<R, M, A>(&self, key: &String, method: M, args: A) -> Result<R, String>
+    where
+        M: Into<String> + Clone,
+        A: ArgumentEncoder + Clone,
+        R: CandidType + DeserializeOwned,
+    {
+        let other_replicas: Vec<Principal> = {
+            let manager = self.borrow();
+            manager
+                .canister
+                .replicas_of(key)
+                .into_iter()
+                .filter(|node_id| **node_id != manager.canister.id)
+                .copied()
+                .collect()
+        };
+        let mut result = Err(format!("no replica of {}", key));
+        for node_id in other_replicas {
+            result =
+                CanisterManager::<Data, P>::forward_request(node_id, method.clone(), args.clone())
+                    .await;
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Runs `action` over keys owned by any nodes of the cluster with a two-phase commit. Every
+    /// owner locks its keys and sends back their values, then the writes are committed to each
+    /// owner, or the locks are released if a node refused them or the action returned an error.
+    /// Before the first commit every owner renews its locks, so a lock that expired while the
+    /// other owners were called aborts the transaction instead of committing it on some owners
+    /// only. A commit can still fail on an owner that can't be reached, see
+    /// `TransactionError::Commit`. The locks of a coordinator that stops midway are released by
+    /// their node's heartbeat once `TRANSACTION_TIMEOUT` has passed.
+    pub async fn lifecycle_transaction<F, R, E>(
+        &self,
+        mut keys: Vec<String>,
+        action: F,
+    ) -> Result<R, TransactionError<E>>
+    where
+        F: FnOnce(&mut Transaction<Data>) -> Result<R, E>,
+    {
+        keys.sort();
+        keys.dedup();
+        let mut keys_by_node: HashMap<Principal, Vec<String>> = HashMap::new();
+        let transaction_id = {
+            let mut manager = self.borrow_mut();
+            for key in keys {
+                match manager.canister.owner_of(&key) {
+                    Some(&node_id) => keys_by_node.entry(node_id).or_default().push(key),
+                    None => return Err(TransactionError::NoOwner),
+                }
+            }
+            if keys_by_node.is_empty() {
+                return Err(TransactionError::NoOwner);
+            }
+
+            manager.transaction_count += 1;
+            format!("{}:{}", manager.canister.id, manager.transaction_count)
+        };
+
+        let mut entries = HashMap::new();
+        let mut prepared = vec![];
+        for (&node_id, keys) in &keys_by_node {
+            let event = CanisterManagerEvent::Prepare(PrepareArgs {
+                transaction_id: transaction_id.clone(),
+                keys: keys.clone(),
+                timeout: TRANSACTION_TIMEOUT,
+            });
+            let result = match self.send_transaction_event(node_id, event).await {
+                Ok(CanisterManagerEventResponse::Prepared(args)) => {
+                    DataChunk::<Data>::decode(&args.data)
+                }
+                Ok(CanisterManagerEventResponse::Error(error)) | Err(error) => Err(error),
+                Ok(_) => Err(format!("node {} did not prepare its keys", node_id)),
+            };
+
+            match result {
+                Ok(data_chunk) => {
+                    prepared.push(node_id);
+                    entries.extend(keys.iter().map(|key| (key.clone(), None)));
+                    entries.extend(
+                        data_chunk
+                            .data
+                            .into_iter()
+                            .map(|(key, data)| (key, Some(data))),
+                    );
+                }
+                Err(error) => {
+                    self.abort_transaction(&transaction_id, prepared).await;
+                    return Err(TransactionError::Prepare(error));
+                }
+            }
+        }
+
+        let mut transaction = Transaction::new(entries);
+        let result = match action(&mut transaction) {
+            Ok(result) => result,
+            Err(error) => {
+                self.abort_transaction(&transaction_id, prepared).await;
+                return Err(TransactionError::Aborted(error));
+            }
+        };
+
+        for (&node_id, keys) in &keys_by_node {
+            let event = CanisterManagerEvent::Renew(PrepareArgs {
+                transaction_id: transaction_id.clone(),
+                keys: keys.clone(),
+                timeout: TRANSACTION_TIMEOUT,
+            });
+            let error = match self.send_transaction_event(node_id, event).await {
+                Ok(CanisterManagerEventResponse::Ok) => continue,
+                Ok(CanisterManagerEventResponse::Error(error)) | Err(error) => error,
+                Ok(_) => format!("node {} did not renew its locks", node_id),
+            };
+            self.abort_transaction(&transaction_id, prepared).await;
+            return Err(TransactionError::Prepare(error));
+        }
+
+        let mut entries = transaction.into_entries();
+        let mut errors = vec![];
+        for (node_id, keys) in keys_by_node {
+            let mut data = vec![];
+            let mut removed = vec![];
+            for key in keys {
+                match entries.remove(&key) {
+                    Some(Some(value)) => data.push((key, value)),
+                    _ => removed.push(key),
+                }
+            }
+
+            let data = match DataChunk::encode_borrowed(
+                data.iter().map(|(k, v)| (k, v)).collect(),
+                vec![],
+                vec![],
+            ) {
+                Ok(data) => data,
+                Err(error) => {
+                    errors.push(format!("{}: {}", node_id, error));
+                    continue;
+                }
+            };
+            let event = CanisterManagerEvent::Commit(CommitArgs {
+                transaction_id: transaction_id.clone(),
+                data,
+                removed,
+            });
+            match self.send_transaction_event(node_id, event).await {
+                Ok(CanisterManagerEventResponse::Ok) => {}
+                Ok(CanisterManagerEventResponse::Error(error)) | Err(error) => {
+                    errors.push(format!("{}: {}", node_id, error))
+                }
+                Ok(_) => errors.push(format!("{}: did not commit its keys", node_id)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(result)
+        } else {
+            Err(TransactionError::Commit(errors.join(", ")))
+        }
+    }
+
+    async fn abort_transaction(&self, transaction_id: &str, nodes: Vec<Principal>) {
+        for node_id in nodes {
+            // a node that misses the abort releases the locks once they expire
+            let _ = self
+                .send_transaction_event(
+                    node_id,
+                    CanisterManagerEvent::Abort(transaction_id.to_string()),
+                )
+                .await;
+        }
+    }
+
+    /// handles the event locally when this node is the participant, otherwise calls `handle_event`
+    async fn send_transaction_event(
+        &self,
+        node_id: Principal,
+        event: CanisterManagerEvent,
+    ) -> Result<CanisterManagerEventResponse, String> {
+        if node_id == self.borrow().canister.id {
+            let mut manager = self.borrow_mut();
+            return Ok(match event {
+                CanisterManagerEvent::Prepare(args) => manager.handle_prepare(args),
+                CanisterManagerEvent::Commit(args) => manager.handle_commit(args),
+                CanisterManagerEvent::Renew(args) => manager.handle_renew(args),
+                CanisterManagerEvent::Abort(transaction_id) => {
+                    manager.canister.unlock_keys(&transaction_id);
+                    CanisterManagerEventResponse::Ok
+                }
+                _ => CanisterManagerEventResponse::Error("not a transaction event".to_string()),
+            });
+        }
+
+        ic::call::<_, (CanisterManagerEventResponse,), _>(node_id, "handle_event", (event,))
+            .await
+            .map(|(response,)| response)
+            .map_err(|e| e.1)
+    }
+
+    async fn migrate_data(&self, node_id: Principal) -> bool {
+        let (migration_mode, replication_factor) = {
+            let manager = self.borrow();
+            (
+                manager.migration_mode,
+                manager.canister.replication_factor(),
+            )
+        };
+        if migration_mode == MigrationMode::Lazy {
+            // the keys follow from the heartbeat or when their new owner asks for them
+            return true;
+        }
+        if replication_factor > 1 {
+            // copies may be missing on any replica, not only on the new node
+            return self.migrate_data_to_owners().await;
+        }
+        let keys_for_migration = self.borrow().canister.get_keys_to_migrate();
+        self.migrate_to_node(node_id, keys_for_migration).await
+    }
+
+    /// sends every foreign key to its current owner, used when ownership can move in any direction
+    async fn migrate_data_to_owners(&self) -> bool {
+        let keys_by_node = self.borrow().canister.get_keys_to_migrate_by_node();
+        for (node_id, keys) in keys_by_node {
+            if !self.migrate_to_node(node_id, keys).await {
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn broadcast_event(&self, event: CanisterManagerEvent) -> () {
+        let other_nodes = self.borrow().other_nodes();
+        self.broadcast_event_to(other_nodes, event).await
+    }
+
+    async fn broadcast_event_to(&self, nodes: Vec<Principal>, event: CanisterManagerEvent) -> () {
+        for canister_id in nodes {
+            let result = ic::call::<_, (), _>(canister_id, "handle_event", (event.clone(),)).await;
+
+            if let Err(e) = result {
+                self.borrow_mut().status = NodeStatus::Error(NodeError::Broadcast(format!(
+                    "Failed to broadcast event, error {} to node {}",
+                    e.1, canister_id
+                )));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node_manager::NodeStatus;
+
+    use super::DataChunk;
+    use super::{CanisterManager, SharedCanisterManager};
+    use super::{
+        CanisterManagerEvent, CanisterManagerEventResponse, ConsistencyLevel, MembershipChange,
+        MigrateArgs, MigrationMode, NodeMember, PrepareArgs, Redirect,
+        LAZY_MIGRATION_KEYS_PER_HEARTBEAT,
+    };
+    use super::{InstallArgs, WasmInitArgs};
+    use crate::node::{
+        Node, NodeResult, PlacementChange, Transaction, TransactionError, WriteError,
+    };
+    use crate::placement::{
+        AnchorPlacement, JumpPlacement, KeyRangePlacement, Placement, RangePlacement,
+        RendezvousPlacement,
+    };
+    use async_std::test as async_test;
+    use ic_kit::mock_principals;
+    use ic_kit::Principal;
+    use ic_kit::{MockContext, RawHandler, RejectionCode};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    #[test]
+    fn new_node() {
+        let node_id = Principal::anonymous();
+        let cm = CanisterManager::<String>::new(node_id, |size| size > 10);
+        let node_info = cm.node_info();
+
+        assert_eq!(node_info.all_nodes, vec![node_id.to_string()]);
+    }
+
+    #[async_test]
+    async fn node_initialized_properly() {
+        let node_id = mock_principals::alice();
+        let previous_node = mock_principals::bob();
+
+        MockContext::new()
+            .with_caller(previous_node.clone())
+            .with_id(node_id.clone())
+            .with_constant_return_handler(())
+            .inject();
+
+        let cm = SharedCanisterManager::<String>::new(node_id.clone(), |size| size > 10);
+        // the parent adds the new node before installing it
+        let placement_history = vec![
+            PlacementChange::Add(previous_node),
+            PlacementChange::Add(node_id),
+        ];
+
+        cm.lifecyle_init_node(Some(InstallArgs {
+            placement_history,
+            replication_factor: 1,
+            migration_mode: Default::default(),
+            controllers: vec![],
+        }))
+        .await;
+        // keys are fetched from their previous owners until the parent announces the node
+        assert!(cm.borrow().canister.is_handing_off());
+        let member = cm.borrow().member(node_id);
+        cm.lifecycle_handle_event(CanisterManagerEvent::NodeCreated(MembershipChange {
+            member,
+            epoch: 1,
+        }))
+        .await;
+        assert!(!cm.borrow().canister.is_handing_off());
+
+        let cm = cm.borrow();
+        let node_info = cm.node_info();
+
+        assert_eq!(
+            node_info.all_nodes,
+            vec![previous_node.to_string(), node_id.to_string()]
+        );
+
+        assert_eq!(cm.canister.parent_id, Some(previous_node));
+        matches!(cm.get_status(), NodeStatus::Initialized);
+    }
+
+    #[async_test]
+    async fn nodes_rebuilt_from_the_placement_history_route_like_the_live_node() {
+        let (alice, bob, john) = (
+            mock_principals::alice(),
+            mock_principals::bob(),
+            mock_principals::john(),
+        );
+        MockContext::new().with_id(alice).inject();
+        let mut cm = CanisterManager::<String>::new(alice, |_| false);
+        cm.canister.add_node(bob);
+        cm.canister.set_weight(&alice, 3);
+        cm.canister.add_node(john);
+
+        // a child installed by the live node and a node rebuilt from its node info
+        MockContext::new().with_id(john).with_caller(alice).inject();
+        let child = SharedCanisterManager::<String>::new(john, |_| false);
+        child.lifecyle_init_node(Some(cm.install_args())).await;
+        let mut rebuilt = Node::<Principal, String>::with_placement(bob, Default::default());
+        assert!(rebuilt.restore_placement(cm.node_info().placement_history, &[]));
+
+        assert_eq!(child.borrow().canister.parent_id, Some(alice));
+        assert_eq!(child.borrow().canister.weight(&alice), 3);
+        for key in (0..2000).map(|i| format!("key_{}", i)) {
+            let owner = cm.canister.owner_of(&key);
+            assert_eq!(child.borrow().canister.owner_of(&key), owner);
+            assert_eq!(rebuilt.owner_of(&key), owner);
+        }
+    }
+
+    #[test]
+    fn node_wasm_initialized_properly() {
+        let node_id = mock_principals::alice();
+        let mut cm = CanisterManager::<String>::new(node_id.clone(), |size| size > 10);
+
+        assert!(cm.lifecycle_init_wasm(WasmInitArgs {
+            position: 0,
+            wasm_chunk: Vec::<u8>::default(),
+        }));
+
+        assert!(cm.lifecycle_init_wasm(WasmInitArgs {
+            position: 1,
+            wasm_chunk: Vec::<u8>::default(),
+        }));
+
+        assert!(cm.lifecycle_init_wasm(WasmInitArgs {
+            position: 2,
+            wasm_chunk: Vec::<u8>::default(),
+        }));
+
+        matches!(cm.get_status(), NodeStatus::Ready);
+    }
+
+    #[test]
+    fn borrowed_data_chunk_decodes_as_data_chunk() {
+        let entries = vec![
+            ("key_1".to_string(), "value_1".to_string()),
+            ("key_2".to_string(), "value_2".to_string()),
+        ];
+
+        let encoded = DataChunk::<String>::encode_borrowed(
+            entries.iter().map(|(k, v)| (k, v)).collect(),
+            vec![3, 1],
+            vec![Some(10), None],
+        )
+        .unwrap();
+
+        let data_chunk = DataChunk::<String>::decode(&encoded).unwrap();
+        assert_eq!(data_chunk.data, entries);
+        assert_eq!(data_chunk.versions, vec![3, 1]);
+        assert_eq!(data_chunk.expires_at, vec![Some(10), None]);
+    }
+
+    #[async_test]
+    async fn migrated_data_keeps_its_versions() {
+        let node_id = mock_principals::alice();
+        MockContext::new().with_id(node_id).inject();
+        let cm = SharedCanisterManager::<String>::new(node_id, |size| size > 10);
+        let key = "key_1".to_string();
+
+        let data = DataChunk::<String>::encode_borrowed(
+            vec![(&key, &"value_1".to_string())],
+            vec![7],
+            vec![],
+        )
+        .unwrap();
+        cm.lifecycle_handle_event(CanisterManagerEvent::Migrate(MigrateArgs { data }))
+            .await;
+
+        let mut cm = cm.borrow_mut();
+        assert_eq!(cm.canister.version(&key), 7);
+        cm.canister
+            .with_data_mut(key.clone(), |data| data.push('!'));
+        assert_eq!(cm.canister.version(&key), 8);
+    }
+
+    #[async_test]
+    async fn removals_migrate_with_their_versions() {
+        let node_id = mock_principals::alice();
+        MockContext::new().with_id(node_id).inject();
+        let cm = SharedCanisterManager::<String>::new(node_id, |size| size > 10);
+        let (key, stale_key) = ("key_1".to_string(), "key_2".to_string());
+        for key in [&key, &stale_key] {
+            cm.borrow_mut()
+                .canister
+                .insert_versioned_data(key.clone(), "value".to_string(), 3);
+        }
+
+        let data = DataChunk::<String>::encode_with_removed(
+            vec![],
+            vec![],
+            vec![],
+            vec![(&key, 5), (&stale_key, 2)],
+        )
+        .unwrap();
+        cm.lifecycle_handle_event(CanisterManagerEvent::Migrate(MigrateArgs { data }))
+            .await;
+
+        let cm = cm.borrow();
+        assert_eq!(cm.canister.get_data(&key), None);
+        assert_eq!(cm.canister.version(&key), 5);
+        // the local write is newer than the removal
+        assert_eq!(cm.canister.get_data(&stale_key), Some(&"value".to_string()));
+    }
+
+    #[async_test]
+    async fn migrated_data_keeps_its_expiry() {
+        let node_id = mock_principals::alice();
+        MockContext::new().with_id(node_id).inject();
+        let cm = SharedCanisterManager::<String>::new(node_id, |size| size > 10);
+        let (live_key, expired_key) = ("key_1".to_string(), "key_2".to_string());
+        let value = "value".to_string();
+
+        let data = DataChunk::<String>::encode_borrowed(
+            vec![(&live_key, &value), (&expired_key, &value)],
+            vec![],
+            vec![Some(u64::MAX), Some(0)],
+        )
+        .unwrap();
+        cm.lifecycle_handle_event(CanisterManagerEvent::Migrate(MigrateArgs { data }))
+            .await;
+
+        assert_eq!(cm.borrow().canister.expires_at(&live_key), Some(u64::MAX));
+        assert_eq!(cm.borrow().canister.get_data(&expired_key), None);
+
+        cm.lifecyle_heartbeat_node().await;
+        assert_eq!(cm.borrow().canister.size(), 1);
+    }
+
+    #[async_test]
+    async fn weight_changed_event_updates_weights() {
+        let node_id = mock_principals::alice();
+        let other_node = mock_principals::bob();
+
+        MockContext::new()
+            .with_caller(other_node)
+            .with_id(node_id)
+            .with_constant_return_handler(())
+            .inject();
+
+        let cm = SharedCanisterManager::<String>::new(node_id, |size| size > 10);
+        cm.lifecycle_handle_event(CanisterManagerEvent::NodeCreated(MembershipChange {
+            member: NodeMember {
+                id: other_node,
+                weight: 2,
+                range_start: None,
+            },
+            epoch: 1,
+        }))
+        .await;
+        assert_eq!(cm.borrow().node_info().weights, vec![1, 2]);
+
+        cm.lifecycle_handle_event(CanisterManagerEvent::WeightChanged(MembershipChange {
+            member: NodeMember {
+                id: other_node,
+                weight: 5,
+                range_start: None,
+            },
+            epoch: 4,
+        }))
+        .await;
+        assert_eq!(cm.borrow().node_info().weights, vec![1, 5]);
+        // the epoch of the cluster, not the count of changes this node applied
+        assert_eq!(cm.borrow().node_info().epoch, 4);
+    }
+
+    #[test]
+    fn reads_are_routed_to_replicas_and_writes_to_the_owner() {
+        let node_id = mock_principals::alice();
+        let mut cm = CanisterManager::<String>::new(node_id, |size| size > 10);
+        cm.canister.add_node(mock_principals::bob());
+        cm.canister.add_node(mock_principals::john());
+        cm.canister.set_replication_factor(2);
+
+        for key in (0..100).map(|i| format!("key_{}", i)) {
+            let replicas: Vec<Principal> =
+                cm.canister.replicas_of(&key).into_iter().copied().collect();
+            match replicas[0] == node_id {
+                true => assert!(cm.route(&key, false).is_empty()),
+                false => assert_eq!(cm.route(&key, false), vec![replicas[0]]),
+            }
+            match replicas.contains(&node_id) {
+                true => assert!(cm.route(&key, true).is_empty()),
+                false => assert_eq!(cm.route(&key, true), replicas),
+            }
+        }
+    }
+
+    #[test]
+    fn non_replicas_redirect_to_the_owner() {
+        let node_id = mock_principals::alice();
+        let mut cm = CanisterManager::<String>::new(node_id, |size| size > 10);
+        cm.canister.add_node(mock_principals::bob());
+
+        for key in (0..100).map(|i| format!("key_{}", i)) {
+            match cm.canister.owner_of(&key) == Some(&node_id) {
+                true => assert_eq!(cm.redirect(&key, true), None),
+                false => assert_eq!(
+                    cm.redirect(&key, true),
+                    Some(Redirect {
+                        owner: mock_principals::bob(),
+                        epoch: cm.epoch(),
+                    })
+                ),
+            }
+        }
+    }
+
+    fn restores_after_an_upgrade<P: Placement<Principal> + Default>() {
+        let node_id = mock_principals::alice();
+        MockContext::new().with_id(node_id).inject();
+        let mut cm = CanisterManager::<String, P>::new(node_id, |size| size > 10);
+        cm.canister.add_node(mock_principals::bob());
+        cm.canister.add_node(mock_principals::john());
+        cm.canister.remove_node(&mock_principals::bob());
+
+        let mut memory = vec![];
+        cm.save_stable(&mut memory).unwrap();
+        let restored =
+            CanisterManager::<String, P>::load_stable(&mut memory.as_slice(), |size| size > 10)
+                .unwrap();
+
+        assert_eq!(restored.node_info().all_nodes, cm.node_info().all_nodes);
+        for key in (0..200).map(|i| format!("key_{}", i)) {
+            assert_eq!(restored.canister.owner_of(&key), cm.canister.owner_of(&key));
+        }
+    }
+
+    #[test]
+    fn managers_of_every_placement_restore_after_an_upgrade() {
+        restores_after_an_upgrade::<AnchorPlacement<Principal>>();
+        restores_after_an_upgrade::<RendezvousPlacement<Principal>>();
+        restores_after_an_upgrade::<JumpPlacement<Principal>>();
+        restores_after_an_upgrade::<RangePlacement<Principal>>();
+        restores_after_an_upgrade::<KeyRangePlacement<Principal>>();
+    }
+
+    #[test]
+    fn stable_snapshot_restores_the_node() {
+        let node_id = mock_principals::alice();
+        MockContext::new().with_id(node_id).inject();
+        let mut cm = CanisterManager::<String>::new(node_id, |size| size > 10);
+        for position in 0..3 {
+            cm.lifecycle_init_wasm(WasmInitArgs {
+                position,
+                wasm_chunk: vec![position as u8],
+            });
+        }
+        cm.set_migration_mode(MigrationMode::Lazy);
+        cm.canister.set_replication_factor(2);
+        cm.canister.add_weighted_node(mock_principals::bob(), 3);
+        // a weight change after a join places keys unlike the final weights in join order would
+        cm.canister.set_weight(&node_id, 2);
+        cm.canister.add_node(mock_principals::john());
+        cm.canister.children.push(mock_principals::bob());
+        let keys: Vec<String> = (0..100).map(|i| format!("key_{}", i)).collect();
+        for key in &keys {
+            cm.canister.insert_data(key.clone(), key.clone());
+        }
+        cm.canister
+            .insert_data(keys[0].clone(), "updated".to_string());
+        cm.canister.set_expiry(&keys[1], Some(u64::MAX));
+        let owned: Vec<String> = keys
+            .iter()
+            .filter(|key| cm.canister.owner_of(key) == Some(&node_id))
+            .take(2)
+            .cloned()
+            .collect();
+        cm.canister
+            .lock_keys(&"transaction".to_string(), &owned, 0, u64::MAX)
+            .unwrap();
+
+        let mut memory = vec![];
+        cm.save_stable(&mut memory).unwrap();
+        let restored =
+            CanisterManager::<String>::load_stable(&mut memory.as_slice(), |size| size > 10)
+                .unwrap();
+
+        assert_eq!(restored.wasm_binary, Some(vec![0, 1, 2]));
+        assert_eq!(restored.migration_mode(), MigrationMode::Lazy);
+        assert_eq!(restored.node_info().weights, vec![2, 3, 1]);
+        assert_eq!(restored.canister.handoffs(), cm.canister.handoffs());
+        assert_eq!(restored.canister.children, vec![mock_principals::bob()]);
+        assert_eq!(restored.canister.epoch(), cm.canister.epoch());
+        assert_eq!(restored.canister.size(), keys.len());
+        assert_eq!(restored.canister.version(&keys[0]), 2);
+        assert_eq!(restored.canister.expires_at(&keys[1]), Some(u64::MAX));
+        for key in &keys {
+            assert_eq!(restored.canister.get_data(key), cm.canister.get_data(key));
+            assert_eq!(
+                restored.canister.replicas_of(key),
+                cm.canister.replicas_of(key)
+            );
+            assert_eq!(
+                restored.canister.handoff_sources(key),
+                cm.canister.handoff_sources(key)
+            );
+        }
+        // a transaction prepared before the upgrade can still commit
+        assert_eq!(restored.canister.locks().count(), 2);
+        assert!(restored.canister.is_locked(&owned[0]));
+        assert!(restored.canister.is_locked(&owned[1]));
+    }
+
+    #[async_test]
+    async fn ordered_node_created_event_migrates_the_split_range() {
+        let node_id = mock_principals::alice();
+        let other_node = mock_principals::bob();
+
+        MockContext::new()
+            .with_caller(other_node)
+            .with_id(node_id)
+            .with_constant_return_handler(())
+            .inject();
+
+        let cm =
+            SharedCanisterManager::<String, KeyRangePlacement<Principal>>::new(node_id, |size| {
+                size > 10
+            });
+        for id in 0..10 {
+            cm.borrow_mut()
+                .canister
+                .with_upsert_data_mut(format!("user:{}", id), |data| data.push_str("data"));
+        }
+
+        cm.lifecycle_handle_event(CanisterManagerEvent::NodeCreated(MembershipChange {
+            member: NodeMember {
+                id: other_node,
+                weight: 1,
+                range_start: Some("user:5".to_string()),
+            },
+            epoch: 1,
+        }))
+        .await;
+
+        let mut cm = cm.borrow_mut();
+        assert_eq!(cm.canister.size(), 5);
+        assert_eq!(
+            cm.canister
+                .with_data_mut("user:7".to_string(), |data| data.clone()),
+            NodeResult::NodeId(other_node)
+        );
+    }
+
+    fn transfer(
+        transaction: &mut Transaction<u64>,
+        from: &str,
+        to: &str,
+        amount: u64,
+    ) -> Result<(), String> {
+        let balance = transaction.upsert(from).unwrap();
+        if *balance < amount {
+            return Err("insufficient balance".to_string());
+        }
+        *balance -= amount;
+        *transaction.upsert(to).unwrap() += amount;
+        Ok(())
+    }
+
+    /// a coordinator on alice and a participant on bob reached through mocked `handle_event` calls,
+    /// with a key owned by each of them and 100 on alice's key
+    fn transaction_cluster() -> (
+        SharedCanisterManager<u64>,
+        SharedCanisterManager<u64>,
+        String,
+        String,
+    ) {
+        let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+        let cluster = |node_id| {
+            let mut canister = Node::new(node_id, Default::default());
+            canister.add_node(alice);
+            canister.add_node(bob);
+            let mut cm = CanisterManager::<u64>::new(node_id, |_| false);
+            cm.canister = canister;
+            SharedCanisterManager::from(cm)
+        };
+        let coordinator = cluster(alice);
+        let participant = cluster(bob);
+
+        let key_on = |node_id| {
+            (0..)
+                .map(|id| format!("balance:{}", id))
+                .find(|key| coordinator.borrow().canister.owner_of(key) == Some(&node_id))
+                .unwrap()
+        };
+        let (local_key, remote_key) = (key_on(alice), key_on(bob));
+        coordinator
+            .borrow_mut()
+            .canister
+            .insert_data(local_key.clone(), 100);
+
+        let handler_participant = participant.clone();
+        MockContext::new()
+            .with_id(alice)
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), _, _| {
+                    let response = futures::executor::block_on(
+                        handler_participant.lifecycle_handle_event(event),
+                    );
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        (coordinator, participant, local_key, remote_key)
+    }
+
+    #[async_test]
+    async fn cross_node_transaction_commits_on_every_owner() {
+        let (coordinator, participant, local_key, remote_key) = transaction_cluster();
+
+        let result = coordinator
+            .lifecycle_transaction(vec![local_key.clone(), remote_key.clone()], |transaction| {
+                transfer(transaction, &local_key, &remote_key, 40)
+            })
+            .await;
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            coordinator.borrow().canister.get_data(&local_key),
+            Some(&60)
+        );
+        assert_eq!(
+            participant.borrow().canister.get_data(&remote_key),
+            Some(&40)
+        );
+        assert!(!coordinator.borrow().canister.is_locked(&local_key));
+        assert!(!participant.borrow().canister.is_locked(&remote_key));
+    }
+
+    #[async_test]
+    async fn writes_to_prepared_keys_are_refused_until_the_commit() {
+        let (coordinator, participant, local_key, remote_key) = transaction_cluster();
+        let writes = Rc::new(RefCell::new(vec![]));
+        let (handler_participant, handler_writes, handler_key) =
+            (participant.clone(), writes.clone(), remote_key.clone());
+        MockContext::new()
+            .with_id(mock_principals::alice())
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), _, _| {
+                    let prepare = matches!(event, CanisterManagerEvent::Prepare(_));
+                    let response = futures::executor::block_on(
+                        handler_participant.lifecycle_handle_event(event),
+                    );
+                    // a write arriving between the prepare and the commit
+                    if prepare {
+                        let write = handler_participant
+                            .borrow_mut()
+                            .canister
+                            .with_upsert_data_mut(handler_key.clone(), |balance| *balance = 1000);
+                        handler_writes.borrow_mut().push(write);
+                    }
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        let result = coordinator
+            .lifecycle_transaction(vec![local_key.clone(), remote_key.clone()], |transaction| {
+                transfer(transaction, &local_key, &remote_key, 40)
+            })
+            .await;
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            *writes.borrow(),
+            vec![NodeResult::Result(Err(WriteError::Locked))]
+        );
+        assert_eq!(
+            participant.borrow().canister.get_data(&remote_key),
+            Some(&40)
+        );
+    }
+
+    #[async_test]
+    async fn failed_cross_node_transaction_releases_locks() {
+        let (coordinator, participant, local_key, remote_key) = transaction_cluster();
+
+        let result = coordinator
+            .lifecycle_transaction(vec![local_key.clone(), remote_key.clone()], |transaction| {
+                transfer(transaction, &local_key, &remote_key, 500)
+            })
+            .await;
+
+        assert_eq!(
+            result,
+            Err(TransactionError::Aborted(
+                "insufficient balance".to_string()
+            ))
+        );
+        assert_eq!(
+            coordinator.borrow().canister.get_data(&local_key),
+            Some(&100)
+        );
+        assert_eq!(participant.borrow().canister.get_data(&remote_key), None);
+        assert!(!participant.borrow().canister.is_locked(&remote_key));
+
+        // a participant that refuses to prepare aborts the keys already locked elsewhere
+        participant
+            .borrow_mut()
+            .canister
+            .lock_keys(
+                &"other:1".to_string(),
+                std::slice::from_ref(&remote_key),
+                0,
+                u64::MAX,
+            )
+            .unwrap();
+        let result = coordinator
+            .lifecycle_transaction(vec![local_key.clone(), remote_key.clone()], |transaction| {
+                transfer(transaction, &local_key, &remote_key, 40)
+            })
+            .await;
+
+        assert!(matches!(result, Err(TransactionError::Prepare(_))));
+        assert_eq!(
+            coordinator.borrow().canister.get_data(&local_key),
+            Some(&100)
+        );
+        assert!(!coordinator.borrow().canister.is_locked(&local_key));
+    }
+
+    #[async_test]
+    async fn locks_lost_before_the_commit_abort_the_transaction() {
+        let (coordinator, participant, local_key, remote_key) = transaction_cluster();
+
+        let expiring = participant.clone();
+        let result = coordinator
+            .lifecycle_transaction(vec![local_key.clone(), remote_key.clone()], |transaction| {
+                // the participant's heartbeat releases the lock while the action runs
+                expiring
+                    .borrow_mut()
+                    .canister
+                    .release_expired_locks(u64::MAX);
+                transfer(transaction, &local_key, &remote_key, 40)
+            })
+            .await;
+
+        assert!(matches!(result, Err(TransactionError::Prepare(_))));
+        assert_eq!(
+            coordinator.borrow().canister.get_data(&local_key),
+            Some(&100)
+        );
+        assert_eq!(participant.borrow().canister.get_data(&remote_key), None);
+        assert!(!coordinator.borrow().canister.is_locked(&local_key));
+    }
+
+    #[async_test]
+    async fn partial_commits_name_the_nodes_that_failed() {
+        let (coordinator, participant, local_key, remote_key) = transaction_cluster();
+        let handler_participant = participant.clone();
+        MockContext::new()
+            .with_id(mock_principals::alice())
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), _, _| {
+                    if matches!(event, CanisterManagerEvent::Commit(_)) {
+                        return Err((RejectionCode::SysTransient, "unreachable".to_string()));
+                    }
+                    let response = futures::executor::block_on(
+                        handler_participant.lifecycle_handle_event(event),
+                    );
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        let result = coordinator
+            .lifecycle_transaction(vec![local_key.clone(), remote_key.clone()], |transaction| {
+                transfer(transaction, &local_key, &remote_key, 40)
+            })
+            .await;
+
+        assert_eq!(
+            result,
+            Err(TransactionError::Commit(format!(
+                "{}: unreachable",
+                mock_principals::bob()
+            )))
+        );
+        // the coordinator applied its writes, the participant kept its old value and its lock
+        assert_eq!(
+            coordinator.borrow().canister.get_data(&local_key),
+            Some(&60)
+        );
+        assert!(!coordinator.borrow().canister.is_locked(&local_key));
+        assert_eq!(participant.borrow().canister.get_data(&remote_key), None);
+        assert!(participant.borrow().canister.is_locked(&remote_key));
+    }
+
+    #[async_test]
+    async fn heartbeat_releases_locks_of_a_failed_coordinator() {
+        let node_id = mock_principals::bob();
+        MockContext::new().with_id(node_id).inject();
+        let participant = SharedCanisterManager::<u64>::new(node_id, |_| false);
+        let key = "balance:0".to_string();
+
+        let response = participant
+            .lifecycle_handle_event(CanisterManagerEvent::Prepare(PrepareArgs {
+                transaction_id: "alice:1".to_string(),
+                keys: vec![key.clone()],
+                timeout: 0,
+            }))
+            .await;
+        assert!(matches!(
+            response,
+            CanisterManagerEventResponse::Prepared(_)
+        ));
+        assert!(participant.borrow().canister.is_locked(&key));
+
+        participant.lifecyle_heartbeat_node().await;
+        assert!(!participant.borrow().canister.is_locked(&key));
+    }
+
+    #[async_test]
+    async fn replicate_copies_writes_to_the_other_replicas() {
+        let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+        let cluster = |node_id| {
+            let mut cm = CanisterManager::<String>::new(node_id, |_| false);
+            let mut canister = Node::new(node_id, Default::default());
+            canister.add_node(alice);
+            canister.add_node(bob);
+            canister.set_replication_factor(2);
+            cm.canister = canister;
+            SharedCanisterManager::from(cm)
+        };
+        let owner = cluster(alice);
+        let replica = cluster(bob);
+
+        let handler_replica = replica.clone();
+        MockContext::new()
+            .with_id(alice)
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), _, _| {
+                    let response =
+                        futures::executor::block_on(handler_replica.lifecycle_handle_event(event));
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        let key = (0..)
+            .map(|id| format!("key_{}", id))
+            .find(|key| owner.borrow().canister.owner_of(key) == Some(&alice))
+            .unwrap();
+        {
+            let mut owner = owner.borrow_mut();
+            owner.canister.insert_data(key.clone(), "value".to_string());
+            owner
+                .canister
+                .with_data_mut(key.clone(), |data| data.push('!'));
+        }
+
+        assert_eq!(owner.replicate(vec![key.clone()]).await, Ok(()));
+        let replica = replica.borrow();
+        assert_eq!(replica.canister.get_data(&key), Some(&"value!".to_string()));
+        assert_eq!(replica.canister.version(&key), 2);
+        assert!(matches!(
+            replica.canister.with_data(key, |data| data.clone()),
+            NodeResult::Result(Some(_))
+        ));
+    }
+
+    type Replica = SharedCanisterManager<String>;
+
+    /// alice, bob and john replicating every key three times; john does not answer calls
+    fn quorum_cluster() -> (Replica, Replica, String) {
+        let nodes = [
+            mock_principals::alice(),
+            mock_principals::bob(),
+            mock_principals::john(),
+        ];
+        let cluster = |node_id| {
+            let mut cm = CanisterManager::<String>::new(node_id, |_| false);
+            let mut canister = Node::new(node_id, Default::default());
+            for node in nodes {
+                canister.add_node(node);
+            }
+            canister.set_replication_factor(3);
+            cm.canister = canister;
+            SharedCanisterManager::from(cm)
+        };
+        let coordinator = cluster(nodes[0]);
+        let bob = cluster(nodes[1]);
+
+        let handler_bob = bob.clone();
+        MockContext::new()
+            .with_id(nodes[0])
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), node_id, _| {
+                    if *node_id != handler_bob.borrow().canister.id {
+                        return Err((RejectionCode::CanisterError, "unreachable".to_string()));
+                    }
+                    let response =
+                        futures::executor::block_on(handler_bob.lifecycle_handle_event(event));
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        (coordinator, bob, "key".to_string())
+    }
+
+    #[async_test]
+    async fn quorum_write_tolerates_an_unreachable_replica() {
+        let (coordinator, bob, key) = quorum_cluster();
+        coordinator
+            .borrow_mut()
+            .canister
+            .insert_data(key.clone(), "value".to_string());
+
+        assert_eq!(
+            coordinator
+                .replicate_with_consistency(&key, ConsistencyLevel::Quorum)
+                .await,
+            Ok(2)
+        );
+        assert_eq!(
+            bob.borrow().canister.get_data(&key),
+            Some(&"value".to_string())
+        );
+        assert!(coordinator
+            .replicate_with_consistency(&key, ConsistencyLevel::All)
+            .await
+            .is_err());
+        assert_eq!(
+            coordinator
+                .replicate_with_consistency(&key, ConsistencyLevel::One)
+                .await,
+            Ok(2)
+        );
+    }
+
+    #[async_test]
+    async fn quorum_read_returns_the_newest_version() {
+        let (coordinator, bob, key) = quorum_cluster();
+        coordinator.borrow_mut().canister.insert_versioned_data(
+            key.clone(),
+            "stale".to_string(),
+            1,
+        );
+        bob.borrow_mut()
+            .canister
+            .insert_versioned_data(key.clone(), "fresh".to_string(), 3);
+
+        let read = coordinator
+            .read_with_consistency(&key, ConsistencyLevel::One)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((read.value, read.version), ("stale".to_string(), 1));
+
+        let read = coordinator
+            .read_with_consistency(&key, ConsistencyLevel::Quorum)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((read.value, read.version), ("fresh".to_string(), 3));
+
+        assert!(coordinator
+            .read_with_consistency(&key, ConsistencyLevel::All)
+            .await
+            .is_err());
+    }
+
+    #[async_test]
+    async fn sync_repairs_a_replica_that_missed_writes() {
+        let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+        let cluster = |node_id| {
+            let mut cm = CanisterManager::<String>::new(node_id, |_| false);
+            let mut canister = Node::new(node_id, Default::default());
+            canister.add_node(alice);
+            canister.add_node(bob);
+            canister.set_replication_factor(2);
+            cm.canister = canister;
+            SharedCanisterManager::from(cm)
+        };
+        let healthy = cluster(alice);
+        let replica = cluster(bob);
+
+        let handler_replica = replica.clone();
+        MockContext::new()
+            .with_id(alice)
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), _, _| {
+                    let response =
+                        futures::executor::block_on(handler_replica.lifecycle_handle_event(event));
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        for id in 0..20 {
+            let key = format!("key_{}", id);
+            healthy
+                .borrow_mut()
+                .canister
+                .insert_data(key.clone(), "value".to_string());
+            if id % 4 != 0 {
+                replica
+                    .borrow_mut()
+                    .canister
+                    .insert_data(key, "value".to_string());
+            }
+        }
+        healthy
+            .borrow_mut()
+            .canister
+            .insert_data("key_1".to_string(), "newer".to_string());
+
+        assert_eq!(healthy.sync_with(bob).await, Ok(6));
+        {
+            let replica = replica.borrow();
+            assert_eq!(
+                replica.canister.merkle_tree(),
+                healthy.borrow().canister.merkle_tree()
+            );
+            assert_eq!(
+                replica.canister.get_data(&"key_1".to_string()),
+                Some(&"newer".to_string())
+            );
+        }
+        assert_eq!(healthy.sync_with(bob).await, Ok(0));
+    }
+
+    #[async_test]
+    async fn audit_repairs_keys_left_behind_by_a_failed_migration() {
+        let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+        let cluster = |node_id| {
+            let mut cm = CanisterManager::<String>::new(node_id, |_| false);
+            let mut canister = Node::new(node_id, Default::default());
+            canister.add_node(alice);
+            cm.canister = canister;
+            SharedCanisterManager::from(cm)
+        };
+        let stale = cluster(alice);
+        let owner = cluster(bob);
+        owner.borrow_mut().canister.add_node(bob);
+
+        let handler_owner = owner.clone();
+        MockContext::new()
+            .with_id(alice)
+            .with_caller(alice)
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), _, _| {
+                    let response =
+                        futures::executor::block_on(handler_owner.lifecycle_handle_event(event));
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        for id in 0..50 {
+            stale
+                .borrow_mut()
+                .canister
+                .insert_data(format!("key_{}", id), "value".to_string());
+        }
+        // bob joined but the migration to it never happened
+        stale.borrow_mut().canister.add_node(bob);
+        let report = stale.borrow().audit();
+        assert!(report.misplaced > 0);
+        assert_eq!(report.misplaced_by_owner, vec![(bob, report.misplaced)]);
+
+        let reports = stale.audit_cluster(true).await;
+        assert_eq!(reports.len(), 2);
+        let alice_report = reports[0].clone().unwrap();
+        assert_eq!(alice_report.repaired, report.misplaced);
+        assert_eq!(alice_report.misplaced, 0);
+        assert_eq!(alice_report.repair_error, None);
+        let bob_report = reports[1].clone().unwrap();
+        assert_eq!(bob_report.node_id, bob);
+        assert_eq!(bob_report.keys, report.misplaced);
+        assert_eq!(stale.borrow().canister.size() as u64 + bob_report.keys, 50);
+    }
+
+    #[async_test]
+    async fn only_controllers_and_members_repair_the_placement() {
+        let (alice, john) = (mock_principals::alice(), mock_principals::john());
+        MockContext::new()
+            .with_id(alice)
+            .with_caller(john)
+            .with_constant_return_handler(())
+            .inject();
+        let cm = SharedCanisterManager::<String>::new(alice, |_| false);
+
+        assert!(matches!(cm.audit_cluster(true).await.as_slice(), [Err(_)]));
+        // nodes start repairs on each other through `handle_event`
+        assert!(cm.borrow().authorize_event(&john).is_err());
+        assert!(cm.borrow().authorize_event(&alice).is_ok());
+
+        cm.borrow_mut().set_controllers(vec![john]);
+        assert!(matches!(cm.audit_cluster(true).await.as_slice(), [Ok(_)]));
+    }
+
+    #[async_test]
+    async fn new_owner_fetches_keys_that_have_not_arrived_yet() {
+        let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+        let previous_owner = SharedCanisterManager::<String>::new(alice, |_| false);
+        let mut new_owner = CanisterManager::<String>::new(bob, |_| false);
+        let mut canister = Node::new(bob, Default::default());
+        canister.add_node(alice);
+        canister.add_node(bob);
+        new_owner.canister = canister;
+        let new_owner = SharedCanisterManager::from(new_owner);
+
+        let handler_owner = previous_owner.clone();
+        MockContext::new()
+            .with_id(bob)
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), _, _| {
+                    let response =
+                        futures::executor::block_on(handler_owner.lifecycle_handle_event(event));
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        let key = (0..)
+            .map(|id| format!("key_{}", id))
+            .find(|key| new_owner.borrow().canister.owner_of(key) == Some(&bob))
+            .unwrap();
+        previous_owner.borrow_mut().canister.insert_versioned_data(
+            key.clone(),
+            "value".to_string(),
+            3,
+        );
+
+        let read = new_owner.read_during_handoff(&key).await.unwrap().unwrap();
+        assert_eq!((read.value, read.version), ("value".to_string(), 3));
+        assert!(previous_owner.borrow().canister.get_data(&key).is_some());
+
+        assert_eq!(new_owner.pull_during_handoff(&key).await, Ok(true));
+        assert_eq!(
+            new_owner.borrow().canister.get_data(&key),
+            Some(&"value".to_string())
+        );
+        assert_eq!(new_owner.borrow().canister.version(&key), 3);
+        {
+            let previous_owner = previous_owner.borrow();
+            assert_eq!(previous_owner.canister.get_data(&key), None);
+            assert_eq!(previous_owner.canister.owner_of(&key), Some(&bob));
+        }
+
+        {
+            let mut new_owner = new_owner.borrow_mut();
+            new_owner.canister.take_data(std::slice::from_ref(&key));
+            new_owner.canister.finish_handoff();
+        }
+        assert_eq!(new_owner.read_during_handoff(&key).await, Ok(None));
+        assert_eq!(new_owner.pull_during_handoff(&key).await, Ok(false));
+    }
+
+    #[async_test]
+    async fn keys_moved_by_two_joins_during_one_handoff_are_found() {
+        let (alice, bob, john) = (
+            mock_principals::alice(),
+            mock_principals::bob(),
+            mock_principals::john(),
+        );
+        let cluster = |node_id| {
+            let mut canister = Node::new(node_id, Default::default());
+            for member in [alice, bob, john] {
+                canister.add_node(member);
+            }
+            let mut cm = CanisterManager::<String>::new(node_id, |_| false);
+            cm.canister = canister;
+            SharedCanisterManager::from(cm)
+        };
+        // bob and then john joined while alice still holds every key
+        let (first, second, last) = (cluster(alice), cluster(bob), cluster(john));
+        let key = (0..)
+            .map(|id| format!("key_{}", id))
+            .find(|key| last.borrow().canister.handoff_sources(key) == vec![&bob, &alice])
+            .unwrap();
+        first
+            .borrow_mut()
+            .canister
+            .insert_data(key.clone(), "value".to_string());
+
+        let (handler_first, handler_second) = (first.clone(), second.clone());
+        MockContext::new()
+            .with_id(john)
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), canister_id, _| {
+                    let node = match *canister_id == alice {
+                        true => &handler_first,
+                        false => &handler_second,
+                    };
+                    let response = futures::executor::block_on(node.lifecycle_handle_event(event));
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        let read = last.read_during_handoff(&key).await.unwrap().unwrap();
+        assert_eq!(read.value, "value");
+        assert_eq!(last.pull_during_handoff(&key).await, Ok(true));
+        assert_eq!(
+            last.borrow().canister.get_data(&key),
+            Some(&"value".to_string())
+        );
+        assert_eq!(first.borrow().canister.get_data(&key), None);
+    }
+
+    #[async_test]
+    async fn scans_follow_the_ranges_from_owner_to_owner() {
+        let (alice, bob, john) = (
+            mock_principals::alice(),
+            mock_principals::bob(),
+            mock_principals::john(),
+        );
+        MockContext::new().with_id(alice).inject();
+        let history = [
+            PlacementChange::Add(alice),
+            PlacementChange::AddAt(bob, "user:4".to_string()),
+            PlacementChange::AddAt(john, "user:7".to_string()),
+        ];
+        let cluster = |node_id| {
+            let mut canister = Node::with_placement(node_id, KeyRangePlacement::default());
+            assert!(canister.restore_placement(history.to_vec(), &[]));
+            let mut cm =
+                CanisterManager::<String, KeyRangePlacement<Principal>>::new(node_id, |_| false);
+            cm.canister = canister;
+            SharedCanisterManager::from(cm)
+        };
+        let (first, second, third) = (cluster(alice), cluster(bob), cluster(john));
+        let keys: Vec<String> = (0..10).map(|i| format!("user:{}", i)).collect();
+        for key in &keys {
+            let owner = first.borrow().canister.owner_of(key).copied();
+            let node = [&first, &second, &third]
+                .into_iter()
+                .find(|node| Some(node.borrow().canister.id) == owner)
+                .unwrap();
+            node.borrow_mut()
+                .canister
+                .insert_data(key.clone(), key.to_uppercase());
+        }
+
+        let (handler_second, handler_third) = (second.clone(), third.clone());
+        MockContext::new()
+            .with_id(alice)
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), canister_id, _| {
+                    let node = match *canister_id == bob {
+                        true => &handler_second,
+                        false => &handler_third,
+                    };
+                    let response = futures::executor::block_on(node.lifecycle_handle_event(event));
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        // pages cross the ranges of alice, bob and john
+        let mut scanned = vec![];
+        let mut cursor = Some("user:1".to_string());
+        let end = Some("user:9".to_string());
+        while let Some(start) = cursor {
+            let page = first.scan(start, end.clone(), 3).await.unwrap();
+            assert!(page.entries.len() <= 3);
+            scanned.extend(page.entries);
+            cursor = page.cursor;
+        }
+        let expected: Vec<(String, String)> = keys[1..9]
+            .iter()
+            .map(|key| (key.clone(), key.to_uppercase()))
+            .collect();
+        assert_eq!(scanned, expected);
+
+        let page = first.scan("user:".to_string(), None, 100).await.unwrap();
+        assert_eq!(page.entries.len(), keys.len());
+        assert_eq!(page.cursor, None);
+    }
+
+    #[async_test]
+    async fn lazy_migration_moves_keys_from_the_heartbeat() {
+        let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+        let mut previous_owner = CanisterManager::<String>::new(alice, |_| false);
+        previous_owner.set_migration_mode(MigrationMode::Lazy);
+        previous_owner.status = NodeStatus::Ready;
+        let mut canister = Node::new(bob, Default::default());
+        canister.add_node(alice);
+        canister.add_node(bob);
+        let mut new_owner = CanisterManager::<String>::new(bob, |_| false);
+        new_owner.canister = canister;
+        let new_owner = SharedCanisterManager::from(new_owner);
+
+        let handler_owner = new_owner.clone();
+        MockContext::new()
+            .with_id(alice)
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), _, _| {
+                    let response =
+                        futures::executor::block_on(handler_owner.lifecycle_handle_event(event));
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        for id in 0..300 {
+            previous_owner
+                .canister
+                .insert_data(format!("key_{}", id), "value".to_string());
+        }
+        let previous_owner = SharedCanisterManager::from(previous_owner);
+        let member = NodeMember {
+            id: bob,
+            weight: 1,
+            range_start: None,
+        };
+        previous_owner
+            .lifecycle_handle_event(CanisterManagerEvent::NodeCreated(MembershipChange {
+                member,
+                epoch: 1,
+            }))
+            .await;
+        assert_eq!(previous_owner.borrow().canister.size(), 300);
+        let misplaced = previous_owner.borrow().audit().misplaced;
+        assert!(misplaced as usize > LAZY_MIGRATION_KEYS_PER_HEARTBEAT);
+
+        previous_owner.lifecyle_heartbeat_node().await;
+        assert_eq!(
+            new_owner.borrow().canister.size(),
+            LAZY_MIGRATION_KEYS_PER_HEARTBEAT
+        );
+        // no other node holds keys owned by alice
+        assert!(!previous_owner.borrow().canister.is_handing_off());
+
+        while previous_owner.borrow().audit().misplaced > 0 {
+            previous_owner.lifecyle_heartbeat_node().await;
+        }
+        assert_eq!(new_owner.borrow().canister.size() as u64, misplaced);
+    }
+
+    #[async_test]
+    async fn topology_lease_is_granted_to_one_node_at_a_time() {
+        let node_id = mock_principals::alice();
+        MockContext::new().with_id(node_id).inject();
+        let cm = SharedCanisterManager::<String>::new(node_id, |size| size > 10);
+
+        let acquire = |node_id| CanisterManagerEvent::AcquireTopologyLease(node_id);
+        assert!(matches!(
+            cm.lifecycle_handle_event(acquire(mock_principals::bob()))
+                .await,
+            CanisterManagerEventResponse::Ok
+        ));
+        assert!(matches!(
+            cm.lifecycle_handle_event(acquire(mock_principals::john()))
+                .await,
+            CanisterManagerEventResponse::Error(_)
+        ));
+        // only the holder releases its lease
+        cm.lifecycle_handle_event(CanisterManagerEvent::ReleaseTopologyLease(
+            mock_principals::john(),
+        ))
+        .await;
+        assert!(!cm.acquire_lease().await);
+
+        cm.lifecycle_handle_event(CanisterManagerEvent::ReleaseTopologyLease(
+            mock_principals::bob(),
+        ))
+        .await;
+        assert!(matches!(
+            cm.lifecycle_handle_event(acquire(mock_principals::john()))
+                .await,
+            CanisterManagerEventResponse::Ok
+        ));
+    }
+
+    #[async_test]
+    async fn weight_change_aborts_during_another_membership_change() {
+        let node_id = mock_principals::alice();
+        let other_node = mock_principals::bob();
+        MockContext::new()
+            .with_id(node_id)
+            .with_constant_return_handler(())
+            .inject();
+        let cm = SharedCanisterManager::<String>::new(node_id, |size| size > 10);
+        cm.borrow_mut().canister.add_node(other_node);
+        cm.borrow_mut()
+            .set_controllers(vec![Principal::anonymous()]);
+        let member = NodeMember {
+            id: other_node,
+            weight: 3,
+            range_start: None,
+        };
+
+        cm.lifecycle_handle_event(CanisterManagerEvent::AcquireTopologyLease(other_node))
+            .await;
+        assert!(!cm.lifecycle_set_weight(member.clone()).await);
+        assert_eq!(cm.borrow().node_info().weights, vec![1, 1]);
+        cm.lifecycle_handle_event(CanisterManagerEvent::ReleaseTopologyLease(other_node))
+            .await;
+
+        // a heartbeat of this node awaiting its calls
+        assert!(cm.borrow_mut().try_lock());
+        assert!(!cm.lifecycle_set_weight(member.clone()).await);
+        cm.borrow_mut().unlock();
+
+        assert!(cm.lifecycle_set_weight(member).await);
+        assert_eq!(cm.borrow().node_info().weights, vec![1, 3]);
+        assert_eq!(cm.borrow().topology_lease, None);
+    }
+
+    #[async_test]
+    async fn weight_changes_are_refused_to_non_controllers() {
+        let node_id = mock_principals::alice();
+        let other_node = mock_principals::bob();
+        MockContext::new()
+            .with_id(node_id)
+            .with_caller(other_node)
+            .with_constant_return_handler(())
+            .inject();
+        let cm = SharedCanisterManager::<String>::new(node_id, |size| size > 10);
+        cm.borrow_mut().canister.add_node(other_node);
+        cm.borrow_mut()
+            .set_controllers(vec![mock_principals::john()]);
+
+        let member = NodeMember {
+            id: other_node,
+            weight: 3,
+            range_start: None,
+        };
+        assert!(!cm.lifecycle_set_weight(member).await);
+        assert_eq!(cm.borrow().node_info().weights, vec![1, 1]);
+    }
+
+    #[test]
+    fn node_with_children_scales_up_on_its_own_load() {
+        let node_id = mock_principals::alice();
+        let mut cm = CanisterManager::<String>::new(node_id, |size| size > 1);
+        for position in 0..3 {
+            cm.lifecycle_init_wasm(WasmInitArgs {
+                position,
+                wasm_chunk: vec![],
+            });
+        }
+        cm.canister.children.push(mock_principals::bob());
+
+        cm.canister
+            .insert_data("key_1".to_string(), "value_1".to_string());
+        assert!(!cm.should_scale_up());
+        cm.canister
+            .insert_data("key_2".to_string(), "value_2".to_string());
+        assert!(cm.should_scale_up());
+
+        // keys still waiting for their new owner hold the node back
+        cm.canister.add_node(mock_principals::bob());
+        assert!(!cm.should_scale_up());
+    }
+
+    #[async_test]
+    async fn writes_landing_during_a_migration_are_kept() {
+        let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+        let previous_owner = SharedCanisterManager::<String>::new(alice, |_| false);
+        let new_owner = SharedCanisterManager::<String>::new(bob, |_| false);
+        for id in 0..250 {
+            previous_owner
+                .borrow_mut()
+                .canister
+                .insert_data(format!("key_{}", id), "value".to_string());
+        }
+
+        // a write to alice runs while alice awaits each chunk, as another message would
+        let writes = Rc::new(Cell::new(0));
+        let (handler_alice, handler_bob, handler_writes) =
+            (previous_owner.clone(), new_owner.clone(), writes.clone());
+        MockContext::new()
+            .with_id(alice)
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), _, _| {
+                    let mut alice_manager = handler_alice.borrow_mut();
+                    let canister = &mut alice_manager.canister;
+                    let key = (0..)
+                        .map(|id| format!("key_{}", id))
+                        .find(|key| canister.owner_of(key) == Some(&alice))
+                        .unwrap();
+                    canister.with_upsert_data_mut(key, |data| data.push('!'));
+                    drop(alice_manager);
+                    handler_writes.set(handler_writes.get() + 1);
+
+                    let response =
+                        futures::executor::block_on(handler_bob.lifecycle_handle_event(event));
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        let member = NodeMember {
+            id: bob,
+            weight: 1,
+            range_start: None,
+        };
+        previous_owner
+            .lifecycle_handle_event(CanisterManagerEvent::NodeCreated(MembershipChange {
+                member,
+                epoch: 1,
+            }))
+            .await;
+
+        assert!(writes.get() > 1);
+        let previous_owner = previous_owner.borrow();
+        let key = (0..)
+            .map(|id| format!("key_{}", id))
+            .find(|key| previous_owner.canister.owner_of(key) == Some(&alice))
+            .unwrap();
+        assert_eq!(
+            previous_owner.canister.get_data(&key),
+            Some(&format!("value{}", "!".repeat(writes.get())))
+        );
+        assert_eq!(
+            previous_owner.canister.version(&key),
+            1 + writes.get() as u64
+        );
+        assert_eq!(
+            previous_owner.canister.size() + new_owner.borrow().canister.size(),
+            250
+        );
+    }
+
+    #[async_test]
+    async fn heartbeat_is_not_reentered_while_it_awaits_calls() {
+        let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+        let cluster = |node_id| {
+            let mut cm = CanisterManager::<String>::new(node_id, |_| false);
+            let mut canister = Node::new(node_id, Default::default());
+            canister.add_node(alice);
+            canister.add_node(bob);
+            canister.set_replication_factor(2);
+            cm.canister = canister;
+            cm.status = NodeStatus::Ready;
+            SharedCanisterManager::from(cm)
+        };
+        let (healthy, replica) = (cluster(alice), cluster(bob));
+        for id in 0..20 {
+            healthy
+                .borrow_mut()
+                .canister
+                .insert_data(format!("key_{}", id), "value".to_string());
+        }
+
+        // the first call starts a second beat, whose calls are counted
+        let (calls, nested_calls) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+        let (handler_healthy, handler_replica) = (healthy.clone(), replica.clone());
+        let (handler_calls, handler_nested_calls) = (calls.clone(), nested_calls.clone());
+        MockContext::new()
+            .with_id(alice)
+            .with_handler(RawHandler::new(
+                move |_, (event,): (CanisterManagerEvent,), _, _| {
+                    handler_calls.set(handler_calls.get() + 1);
+                    if handler_calls.get() == 1 {
+                        futures::executor::block_on(handler_healthy.lifecyle_heartbeat_node());
+                        handler_nested_calls.set(handler_calls.get() - 1);
+                    }
+                    let response =
+                        futures::executor::block_on(handler_replica.lifecycle_handle_event(event));
+                    Ok((response,))
+                },
+            ))
+            .inject();
+
+        healthy.lifecyle_heartbeat_node().await;
+        assert_eq!(nested_calls.get(), 0);
+        assert_eq!(replica.borrow().canister.size(), 20);
+
+        // the lock was released, so the next beat runs
+        let calls_before = calls.get();
+        healthy.lifecyle_heartbeat_node().await;
+        assert!(calls.get() > calls_before);
+    }
+
+    #[test]
+    fn consistency_level_required_answers() {
+        assert_eq!(ConsistencyLevel::One.required(3), 1);
+        assert_eq!(ConsistencyLevel::Quorum.required(3), 2);
+        assert_eq!(ConsistencyLevel::Quorum.required(4), 3);
+        assert_eq!(ConsistencyLevel::All.required(3), 3);
+        assert_eq!(ConsistencyLevel::One.required(0), 0);
     }
 }
 
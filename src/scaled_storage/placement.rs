@@ -0,0 +1,862 @@
+/// Placement strategies deciding which node owns a partition
+use crate::node::{PartitionId, PARTITION_COUNT};
+use anchorhash::AnchorHash;
+use highway::HighwayBuildHasher;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Bound;
+
+/// default number of nodes an `AnchorPlacement` can hold before it has to grow
+pub const DEFAULT_CAPACITY: u16 = 100;
+
+/// Maps partitions to nodes. Every node of a cluster must use the same strategy and
+/// apply the same sequence of `add_node`/`remove_node` calls to agree on ownership.
+/// Placements are cloned so nodes can remember the one from before a membership change.
+pub trait Placement<TId>: Clone {
+    fn node_for(&self, partition: PartitionId) -> Option<&TId>;
+
+    /// returns false if the node is already placed or could not be added
+    fn add_node(&mut self, node_id: TId) -> bool;
+
+    /// returns false if the node is not placed
+    fn remove_node(&mut self, node_id: &TId) -> bool;
+
+    fn contains(&self, node_id: &TId) -> bool;
+
+    /// sets the share of partitions a placed node receives relative to the others (default 1),
+    /// returns false for zero weights, unknown nodes or strategies without weight support
+    fn set_weight(&mut self, _node_id: &TId, _weight: u32) -> bool {
+        false
+    }
+
+    /// partition a key is stored in, hashed unless the placement keeps keys ordered
+    fn partition_for_key(&self, key: &str) -> PartitionId {
+        hashed_partition(key)
+    }
+
+    fn node_for_key(&self, key: &str) -> Option<&TId> {
+        self.node_for(self.partition_for_key(key))
+    }
+
+    /// ordered placements route keys by range instead of by partition
+    fn is_ordered(&self) -> bool {
+        false
+    }
+
+    /// places `node_id` as the owner of the keys from `range_start` up to the next range,
+    /// returns false for placements that are not ordered
+    fn split_at(&mut self, _node_id: TId, _range_start: String) -> bool {
+        false
+    }
+
+    /// first key of the range owned by `node_id`, ordered placements only
+    fn range_start(&self, _node_id: &TId) -> Option<String> {
+        None
+    }
+
+    /// start of the first range after the one containing `key`, ordered placements only
+    fn next_range_start(&self, _key: &str) -> Option<&String> {
+        None
+    }
+
+    /// Up to `count` distinct nodes holding copies of a partition: its owner followed by the owners
+    /// of the next partitions, wrapping around. Fewer when fewer nodes are placed.
+    fn replicas_for(&self, partition: PartitionId, count: usize) -> Vec<&TId>
+    where
+        TId: PartialEq,
+    {
+        let mut replicas: Vec<&TId> = vec![];
+        for offset in 0..PARTITION_COUNT {
+            if replicas.len() >= count {
+                break;
+            }
+            if let Some(node_id) = self.node_for((partition + offset) % PARTITION_COUNT) {
+                if !replicas.contains(&node_id) {
+                    replicas.push(node_id);
+                }
+            }
+        }
+        replicas
+    }
+
+    /// replicas of the partition `key` is stored in, the first one is the key's owner
+    fn replicas_for_key(&self, key: &str, count: usize) -> Vec<&TId>
+    where
+        TId: PartialEq,
+    {
+        self.replicas_for(self.partition_for_key(key), count)
+    }
+}
+
+pub(crate) fn hash_of<T: Hash>(value: T) -> u64 {
+    HighwayBuildHasher::default().hash_one(value)
+}
+
+/// Part of the key that is hashed, Redis style: the content of the first `{...}` if it is
+/// not empty, otherwise the whole key. `profile:{alice}` and `settings:{alice}` both hash
+/// `alice` so they always share a partition and therefore a node.
+pub fn hash_tag(key: &str) -> &str {
+    key.find('{')
+        .and_then(|open| {
+            key[open + 1..]
+                .find('}')
+                .filter(|len| *len > 0)
+                .map(|len| &key[open + 1..open + 1 + len])
+        })
+        .unwrap_or(key)
+}
+
+pub fn hashed_partition(key: &str) -> PartitionId {
+    (hash_of(hash_tag(key)) % PARTITION_COUNT as u64) as PartitionId
+}
+
+/// the default `HighwayBuildHasher`, which isn't `Clone`
+#[derive(Clone, Default)]
+struct AnchorHasher;
+
+impl BuildHasher for AnchorHasher {
+    type Hasher = <HighwayBuildHasher as BuildHasher>::Hasher;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        HighwayBuildHasher::default().build_hasher()
+    }
+}
+
+/// AnchorHash placement, the default. A node with weight `w` is added as `w` buckets, so
+/// weight changes only move partitions from or to the re-weighted node.
+///
+/// AnchorHash is built for a fixed capacity. When a node is added to a full placement
+/// the capacity is doubled and the hash is rebuilt from the current nodes in insertion
+/// order. The rebuild remaps partitions across all nodes, which the `NodeCreated`
+/// migration that follows every `add_node` moves to their new owners. Because every node
+/// applies the same insertions, they all grow at the same point and agree on the result.
+#[derive(Clone)]
+pub struct AnchorPlacement<TId> {
+    capacity: u16,
+    /// (node, weight) in insertion order
+    nodes: Vec<(TId, u32)>,
+    /// resources are (node, bucket index) so every bucket of a weighted node is unique
+    hash: AnchorHash<PartitionId, (TId, u32), AnchorHasher>,
+}
+
+impl<TId: PartialEq + Clone> AnchorPlacement<TId> {
+    pub fn new(capacity: u16, nodes: impl IntoIterator<Item = TId>) -> Self {
+        let nodes: Vec<(TId, u32)> = nodes.into_iter().map(|node_id| (node_id, 1)).collect();
+        let capacity = capacity.max(nodes.len() as u16).max(1);
+        Self {
+            capacity,
+            hash: Self::build(capacity, &nodes),
+            nodes,
+        }
+    }
+
+    pub fn capacity(&self) -> u16 {
+        self.capacity
+    }
+
+    fn build(
+        capacity: u16,
+        nodes: &[(TId, u32)],
+    ) -> AnchorHash<PartitionId, (TId, u32), AnchorHasher> {
+        anchorhash::Builder::with_hasher(Default::default())
+            .with_resources(
+                nodes
+                    .iter()
+                    .flat_map(|(node_id, weight)| (0..*weight).map(|i| (node_id.clone(), i))),
+            )
+            .build(capacity)
+    }
+
+    fn bucket_count(&self) -> u32 {
+        self.nodes.iter().map(|(_, weight)| weight).sum()
+    }
+
+    /// grows until `buckets` more buckets fit, returns false once u16::MAX is reached
+    fn grow(&mut self, buckets: u32) -> bool {
+        let required = self.bucket_count() + buckets;
+        if required > u16::MAX as u32 {
+            return false;
+        }
+        while (self.capacity as u32) < required {
+            self.capacity = self.capacity.saturating_mul(2);
+        }
+        self.hash = Self::build(self.capacity, &self.nodes);
+        true
+    }
+
+    fn add_bucket(&mut self, node_id: &TId, index: u32) -> bool {
+        match self.hash.add_resource((node_id.clone(), index)) {
+            Ok(()) => true,
+            Err(anchorhash::Error::CapacityLimitReached) => {
+                self.grow(1) && self.hash.add_resource((node_id.clone(), index)).is_ok()
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl<TId: PartialEq + Clone> Placement<TId> for AnchorPlacement<TId> {
+    fn node_for(&self, partition: PartitionId) -> Option<&TId> {
+        self.hash
+            .get_resource(partition)
+            .map(|(node_id, _)| node_id)
+    }
+
+    fn add_node(&mut self, node_id: TId) -> bool {
+        if self.contains(&node_id) || !self.add_bucket(&node_id, 0) {
+            return false;
+        }
+        self.nodes.push((node_id, 1));
+        true
+    }
+
+    fn remove_node(&mut self, node_id: &TId) -> bool {
+        let weight = match self.nodes.iter().position(|(id, _)| id == node_id) {
+            Some(index) => self.nodes.remove(index).1,
+            None => return false,
+        };
+        (0..weight).all(|index| self.hash.remove_resource(&(node_id.clone(), index)).is_ok())
+    }
+
+    fn contains(&self, node_id: &TId) -> bool {
+        self.nodes.iter().any(|(id, _)| id == node_id)
+    }
+
+    fn set_weight(&mut self, node_id: &TId, weight: u32) -> bool {
+        let current = match self.nodes.iter().find(|(id, _)| id == node_id) {
+            Some((_, current)) => *current,
+            None => return false,
+        };
+        if weight == 0 {
+            return false;
+        }
+
+        // the recorded weight follows every bucket so a rebuild while growing keeps them
+        for index in current..weight {
+            if !self.add_bucket(node_id, index) {
+                return false;
+            }
+            self.set_recorded_weight(node_id, index + 1);
+        }
+        for index in (weight..current).rev() {
+            self.hash.remove_resource(&(node_id.clone(), index)).ok();
+            self.set_recorded_weight(node_id, index);
+        }
+        true
+    }
+}
+
+impl<TId: PartialEq> AnchorPlacement<TId> {
+    fn set_recorded_weight(&mut self, node_id: &TId, weight: u32) {
+        if let Some((_, recorded)) = self.nodes.iter_mut().find(|(id, _)| id == node_id) {
+            *recorded = weight;
+        }
+    }
+}
+
+/// Rendezvous (highest random weight) placement, every partition goes to the node with the
+/// highest hash of (node, partition). Lookups are linear in the number of nodes.
+#[derive(Clone)]
+pub struct RendezvousPlacement<TId> {
+    /// (node, weight)
+    nodes: Vec<(TId, u32)>,
+}
+
+impl<TId> RendezvousPlacement<TId> {
+    pub fn new(nodes: impl IntoIterator<Item = TId>) -> Self {
+        Self {
+            nodes: nodes.into_iter().map(|node_id| (node_id, 1)).collect(),
+        }
+    }
+}
+
+impl<TId> Default for RendezvousPlacement<TId> {
+    fn default() -> Self {
+        Self { nodes: vec![] }
+    }
+}
+
+impl<TId: Hash + PartialEq + Clone> Placement<TId> for RendezvousPlacement<TId> {
+    /// weighted rendezvous: score = weight / -ln(h) with h uniform in (0, 1)
+    fn node_for(&self, partition: PartitionId) -> Option<&TId> {
+        self.nodes
+            .iter()
+            .map(|(node_id, weight)| {
+                let h = (hash_of((node_id, partition)) >> 11) as f64 + 0.5;
+                let h = h / (1u64 << 53) as f64;
+                (node_id, *weight as f64 / -h.ln())
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(node_id, _)| node_id)
+    }
+
+    fn add_node(&mut self, node_id: TId) -> bool {
+        if self.contains(&node_id) {
+            return false;
+        }
+        self.nodes.push((node_id, 1));
+        true
+    }
+
+    fn remove_node(&mut self, node_id: &TId) -> bool {
+        let len = self.nodes.len();
+        self.nodes.retain(|(id, _)| id != node_id);
+        self.nodes.len() != len
+    }
+
+    fn contains(&self, node_id: &TId) -> bool {
+        self.nodes.iter().any(|(id, _)| id == node_id)
+    }
+
+    fn set_weight(&mut self, node_id: &TId, weight: u32) -> bool {
+        match self.nodes.iter_mut().find(|(id, _)| id == node_id) {
+            Some((_, current)) if weight > 0 => {
+                *current = weight;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Jump consistent hash placement. Nodes are buckets in insertion order, so only removing
+/// the most recently added node is minimal, removing any other node shifts the buckets after it.
+#[derive(Clone)]
+pub struct JumpPlacement<TId> {
+    nodes: Vec<TId>,
+}
+
+impl<TId> JumpPlacement<TId> {
+    pub fn new(nodes: impl IntoIterator<Item = TId>) -> Self {
+        Self {
+            nodes: nodes.into_iter().collect(),
+        }
+    }
+
+    fn jump_hash(mut key: u64, buckets: usize) -> usize {
+        let (mut b, mut j) = (-1i64, 0i64);
+        while j < buckets as i64 {
+            b = j;
+            key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+            j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+        }
+        b as usize
+    }
+}
+
+impl<TId> Default for JumpPlacement<TId> {
+    fn default() -> Self {
+        Self { nodes: vec![] }
+    }
+}
+
+impl<TId: PartialEq + Clone> Placement<TId> for JumpPlacement<TId> {
+    fn node_for(&self, partition: PartitionId) -> Option<&TId> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        self.nodes
+            .get(Self::jump_hash(hash_of(partition), self.nodes.len()))
+    }
+
+    fn add_node(&mut self, node_id: TId) -> bool {
+        if self.contains(&node_id) {
+            return false;
+        }
+        self.nodes.push(node_id);
+        true
+    }
+
+    fn remove_node(&mut self, node_id: &TId) -> bool {
+        let len = self.nodes.len();
+        self.nodes.retain(|id| id != node_id);
+        self.nodes.len() != len
+    }
+
+    fn contains(&self, node_id: &TId) -> bool {
+        self.nodes.contains(node_id)
+    }
+}
+
+/// Explicit range partitioning of the partition space, each node owns one contiguous
+/// range. A new node takes the upper half of the widest range, a removed node's range is
+/// merged into its lower neighbour (or upper neighbour for the first range).
+#[derive(Clone)]
+pub struct RangePlacement<TId> {
+    /// range start -> owner, a range ends where the next one starts
+    ranges: BTreeMap<PartitionId, TId>,
+}
+
+impl<TId: PartialEq + Clone> RangePlacement<TId> {
+    pub fn new(nodes: impl IntoIterator<Item = TId>) -> Self {
+        let mut placement = Self {
+            ranges: BTreeMap::new(),
+        };
+        for node_id in nodes {
+            placement.add_node(node_id);
+        }
+        placement
+    }
+
+    /// (start, end exclusive, owner) for every range in partition order
+    pub fn ranges(&self) -> Vec<(PartitionId, PartitionId, &TId)> {
+        let mut ranges: Vec<_> = self
+            .ranges
+            .iter()
+            .map(|(start, node_id)| (*start, PARTITION_COUNT, node_id))
+            .collect();
+        for i in 1..ranges.len() {
+            ranges[i - 1].1 = ranges[i].0;
+        }
+        ranges
+    }
+}
+
+impl<TId> Default for RangePlacement<TId> {
+    fn default() -> Self {
+        Self {
+            ranges: BTreeMap::new(),
+        }
+    }
+}
+
+impl<TId: PartialEq + Clone> Placement<TId> for RangePlacement<TId> {
+    fn node_for(&self, partition: PartitionId) -> Option<&TId> {
+        self.ranges
+            .range(..=partition)
+            .next_back()
+            .map(|(_, node_id)| node_id)
+    }
+
+    fn add_node(&mut self, node_id: TId) -> bool {
+        if self.contains(&node_id) {
+            return false;
+        }
+
+        // ties go to the lowest range so every node picks the same one
+        let widest = self
+            .ranges()
+            .into_iter()
+            .rev()
+            .max_by_key(|(start, end, _)| end - start)
+            .map(|(start, end, _)| (start, end));
+
+        match widest {
+            None => {
+                self.ranges.insert(0, node_id);
+                true
+            }
+            Some((start, end)) if end - start > 1 => {
+                self.ranges.insert(start + (end - start) / 2, node_id);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    fn remove_node(&mut self, node_id: &TId) -> bool {
+        let start = match self.ranges.iter().find(|(_, id)| *id == node_id) {
+            Some((start, _)) => *start,
+            None => return false,
+        };
+        self.ranges.remove(&start);
+
+        // the first range must always start at 0
+        if start == 0 {
+            if let Some((&next_start, _)) = self.ranges.iter().next() {
+                let next_owner = self.ranges.remove(&next_start).unwrap();
+                self.ranges.insert(0, next_owner);
+            }
+        }
+        true
+    }
+
+    fn contains(&self, node_id: &TId) -> bool {
+        self.ranges.values().any(|id| id == node_id)
+    }
+}
+
+/// Ordered placement, each node owns one contiguous range of keys so related keys such as
+/// `user:123:*` can be scanned in order. Every key is kept in partition 0 of its node.
+/// The first node owns every key; later nodes are added with `split_at`, usually at the median
+/// key of the fullest range. A removed node's range is merged like in `RangePlacement`.
+#[derive(Clone)]
+pub struct KeyRangePlacement<TId> {
+    /// range start -> owner, a range ends where the next one starts
+    ranges: BTreeMap<String, TId>,
+}
+
+impl<TId> Default for KeyRangePlacement<TId> {
+    fn default() -> Self {
+        Self {
+            ranges: BTreeMap::new(),
+        }
+    }
+}
+
+impl<TId: PartialEq> KeyRangePlacement<TId> {
+    /// (start, end exclusive, owner) for every range in key order, the last range is unbounded
+    pub fn ranges(&self) -> Vec<(&String, Option<&String>, &TId)> {
+        let starts: Vec<&String> = self.ranges.keys().collect();
+        self.ranges
+            .iter()
+            .enumerate()
+            .map(|(i, (start, node_id))| (start, starts.get(i + 1).copied(), node_id))
+            .collect()
+    }
+}
+
+impl<TId: PartialEq + Clone> Placement<TId> for KeyRangePlacement<TId> {
+    /// ordered placement has no partition owners, keys are routed with `node_for_key`
+    fn node_for(&self, _partition: PartitionId) -> Option<&TId> {
+        None
+    }
+
+    fn add_node(&mut self, node_id: TId) -> bool {
+        match self.ranges.is_empty() {
+            true => self.ranges.insert(String::new(), node_id).is_none(),
+            false => false,
+        }
+    }
+
+    fn remove_node(&mut self, node_id: &TId) -> bool {
+        let start = match self.ranges.iter().find(|(_, id)| *id == node_id) {
+            Some((start, _)) => start.clone(),
+            None => return false,
+        };
+        self.ranges.remove(&start);
+
+        // the first range must always start at the empty key
+        if start.is_empty() {
+            if let Some(next_start) = self.ranges.keys().next().cloned() {
+                let next_owner = self.ranges.remove(&next_start).unwrap();
+                self.ranges.insert(String::new(), next_owner);
+            }
+        }
+        true
+    }
+
+    fn contains(&self, node_id: &TId) -> bool {
+        self.ranges.values().any(|id| id == node_id)
+    }
+
+    fn partition_for_key(&self, _key: &str) -> PartitionId {
+        0
+    }
+
+    fn node_for_key(&self, key: &str) -> Option<&TId> {
+        self.ranges
+            .range::<str, _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .map(|(_, node_id)| node_id)
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+
+    fn split_at(&mut self, node_id: TId, range_start: String) -> bool {
+        if self.ranges.is_empty()
+            || self.contains(&node_id)
+            || self.ranges.contains_key(&range_start)
+        {
+            return false;
+        }
+        self.ranges.insert(range_start, node_id);
+        true
+    }
+
+    fn range_start(&self, node_id: &TId) -> Option<String> {
+        self.ranges
+            .iter()
+            .find(|(_, id)| *id == node_id)
+            .map(|(start, _)| start.clone())
+    }
+
+    fn next_range_start(&self, key: &str) -> Option<&String> {
+        self.ranges
+            .range::<str, _>((Bound::Excluded(key), Bound::Unbounded))
+            .next()
+            .map(|(start, _)| start)
+    }
+
+    /// the owner of the key's range followed by the owners of the next ranges, wrapping around
+    fn replicas_for_key(&self, key: &str, count: usize) -> Vec<&TId> {
+        let owned = self
+            .ranges
+            .range::<str, _>((Bound::Unbounded, Bound::Included(key)))
+            .next_back()
+            .map(|(start, _)| start.as_str())
+            .unwrap_or_default();
+        let mut replicas: Vec<&TId> = vec![];
+        for node_id in self
+            .ranges
+            .range::<str, _>((Bound::Included(owned), Bound::Unbounded))
+            .chain(
+                self.ranges
+                    .range::<str, _>((Bound::Unbounded, Bound::Excluded(owned))),
+            )
+            .map(|(_, node_id)| node_id)
+        {
+            if replicas.len() >= count {
+                break;
+            }
+            if !replicas.contains(&node_id) {
+                replicas.push(node_id);
+            }
+        }
+        replicas
+    }
+}
+
+impl<TId: PartialEq + Clone> Default for AnchorPlacement<TId> {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY, vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn owners<P: Placement<String>>(placement: &P) -> Vec<String> {
+        (0..PARTITION_COUNT)
+            .map(|partition| placement.node_for(partition).unwrap().clone())
+            .collect()
+    }
+
+    fn share<P: Placement<String>>(placement: &P) -> HashMap<String, usize> {
+        let mut share = HashMap::new();
+        for owner in owners(placement) {
+            *share.entry(owner).or_insert(0) += 1;
+        }
+        share
+    }
+
+    fn nodes(count: usize) -> Vec<String> {
+        (0..count).map(|id| format!("node_{}", id)).collect()
+    }
+
+    #[test]
+    fn anchor_placement_grows_past_its_capacity() {
+        let mut placement = AnchorPlacement::new(2, nodes(2));
+
+        assert!(placement.add_node("node_2".to_string()));
+        assert!(placement.add_node("node_3".to_string()));
+        assert!(placement.add_node("node_4".to_string()));
+
+        assert_eq!(placement.capacity(), 8);
+        assert_eq!(share(&placement).len(), 5);
+    }
+
+    #[test]
+    fn anchor_placement_growth_is_deterministic() {
+        let mut placement_1 = AnchorPlacement::new(2, nodes(2));
+        let mut placement_2 = AnchorPlacement::new(2, nodes(2));
+
+        for node_id in nodes(6).into_iter().skip(2) {
+            placement_1.add_node(node_id.clone());
+            placement_2.add_node(node_id);
+        }
+
+        assert_eq!(owners(&placement_1), owners(&placement_2));
+    }
+
+    #[test]
+    fn rendezvous_placement_only_moves_partitions_to_the_new_node() {
+        let mut placement = RendezvousPlacement::new(nodes(3));
+        let before = owners(&placement);
+
+        placement.add_node("node_3".to_string());
+        let after = owners(&placement);
+
+        assert!(before
+            .iter()
+            .zip(after.iter())
+            .all(|(before, after)| before == after || after == "node_3"));
+        assert!(share(&placement)["node_3"] > 0);
+    }
+
+    #[test]
+    fn jump_placement_spreads_partitions_over_all_nodes() {
+        let placement = JumpPlacement::new(nodes(4));
+        let share = share(&placement);
+
+        assert_eq!(share.len(), 4);
+        assert!(share
+            .values()
+            .all(|count| *count > PARTITION_COUNT as usize / 8));
+    }
+
+    #[test]
+    fn range_placement_splits_the_widest_range() {
+        let mut placement = RangePlacement::new(nodes(1));
+        placement.add_node("node_1".to_string());
+        placement.add_node("node_2".to_string());
+
+        let ranges: Vec<_> = placement
+            .ranges()
+            .into_iter()
+            .map(|(start, end, node_id)| (start, end, node_id.clone()))
+            .collect();
+
+        assert_eq!(
+            ranges,
+            vec![
+                (0, 256, "node_0".to_string()),
+                (256, 512, "node_2".to_string()),
+                (512, 1024, "node_1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn range_placement_merges_removed_ranges() {
+        let mut placement = RangePlacement::new(nodes(3));
+
+        assert!(placement.remove_node(&"node_0".to_string()));
+        assert_eq!(placement.node_for(0), Some(&"node_2".to_string()));
+        assert_eq!(share(&placement).len(), 2);
+        assert!(!placement.remove_node(&"node_0".to_string()));
+    }
+
+    #[test]
+    fn anchor_placement_share_follows_weight() {
+        let mut placement = AnchorPlacement::new(DEFAULT_CAPACITY, nodes(2));
+        let before = owners(&placement);
+
+        assert!(placement.set_weight(&"node_1".to_string(), 3));
+        let after = owners(&placement);
+        let share = share(&placement);
+
+        assert!(share["node_1"] > share["node_0"] * 2);
+        // only partitions moving to the re-weighted node change owner
+        assert!(before
+            .iter()
+            .zip(after.iter())
+            .all(|(before, after)| before == after || after == "node_1"));
+    }
+
+    #[test]
+    fn anchor_placement_weight_decrease_only_moves_from_the_node() {
+        let mut placement = AnchorPlacement::new(DEFAULT_CAPACITY, nodes(3));
+        placement.set_weight(&"node_2".to_string(), 4);
+        let before = owners(&placement);
+
+        assert!(placement.set_weight(&"node_2".to_string(), 1));
+        let after = owners(&placement);
+
+        assert!(before
+            .iter()
+            .zip(after.iter())
+            .all(|(before, after)| before == after || before == "node_2"));
+        assert!(!placement.set_weight(&"node_2".to_string(), 0));
+        assert!(!placement.set_weight(&"node_9".to_string(), 2));
+    }
+
+    #[test]
+    fn anchor_placement_grows_for_weights() {
+        let mut placement = AnchorPlacement::new(2, nodes(2));
+
+        assert!(placement.set_weight(&"node_0".to_string(), 5));
+        assert_eq!(placement.capacity(), 8);
+        assert!(placement.remove_node(&"node_0".to_string()));
+        assert_eq!(share(&placement).len(), 1);
+    }
+
+    #[test]
+    fn rendezvous_placement_share_follows_weight() {
+        let mut placement = RendezvousPlacement::new(nodes(2));
+        placement.set_weight(&"node_1".to_string(), 3);
+        let share = share(&placement);
+
+        assert!(share["node_1"] > share["node_0"] * 2);
+    }
+
+    #[test]
+    fn key_range_placement_routes_by_range() {
+        let mut placement = KeyRangePlacement::default();
+        placement.add_node("node_0".to_string());
+
+        assert!(placement.split_at("node_1".to_string(), "m".to_string()));
+        assert!(placement.split_at("node_2".to_string(), "user:".to_string()));
+        assert!(!placement.split_at("node_3".to_string(), "m".to_string()));
+        assert!(!placement.add_node("node_3".to_string()));
+
+        let owner = |key: &str| placement.node_for_key(key).unwrap().clone();
+        assert_eq!(owner("apple"), "node_0");
+        assert_eq!(owner("m"), "node_1");
+        assert_eq!(owner("orders:1"), "node_1");
+        assert_eq!(owner("user:123:orders"), "node_2");
+        assert_eq!(placement.next_range_start("apple"), Some(&"m".to_string()));
+        assert_eq!(placement.next_range_start("user:1"), None);
+    }
+
+    #[test]
+    fn key_range_placement_merges_removed_ranges() {
+        let mut placement = KeyRangePlacement::default();
+        placement.add_node("node_0".to_string());
+        placement.split_at("node_1".to_string(), "m".to_string());
+
+        assert!(placement.remove_node(&"node_0".to_string()));
+        assert_eq!(placement.node_for_key("apple"), Some(&"node_1".to_string()));
+        assert_eq!(
+            placement.range_start(&"node_1".to_string()),
+            Some(String::new())
+        );
+    }
+
+    #[test]
+    fn hash_tag_is_the_first_braces_if_not_empty() {
+        assert_eq!(hash_tag("profile:{alice}"), "alice");
+        assert_eq!(hash_tag("{alice}:settings:{bob}"), "alice");
+        assert_eq!(hash_tag("profile:{}:{alice}"), "profile:{}:{alice}");
+        assert_eq!(hash_tag("profile:{alice"), "profile:{alice");
+        assert_eq!(hash_tag("profile:alice"), "profile:alice");
+    }
+
+    #[test]
+    fn keys_with_the_same_hash_tag_share_a_node() {
+        let placement = AnchorPlacement::new(DEFAULT_CAPACITY, nodes(10));
+
+        for user in 0..100 {
+            let owner = |key: String| placement.node_for_key(&key).unwrap().clone();
+            assert_eq!(
+                owner(format!("profile:{{user_{}}}", user)),
+                owner(format!("settings:{{user_{}}}", user))
+            );
+        }
+    }
+
+    #[test]
+    fn replicas_are_distinct_and_start_with_the_owner() {
+        let placement = AnchorPlacement::new(DEFAULT_CAPACITY, nodes(5));
+
+        for partition in 0..PARTITION_COUNT {
+            let replicas = placement.replicas_for(partition, 3);
+            assert_eq!(replicas.len(), 3);
+            assert_eq!(Some(replicas[0]), placement.node_for(partition));
+            assert!(!replicas[1..].contains(&replicas[0]));
+            assert_ne!(replicas[1], replicas[2]);
+        }
+        assert_eq!(placement.replicas_for(0, 10).len(), 5);
+    }
+
+    #[test]
+    fn key_range_replicas_are_the_following_ranges() {
+        let mut placement = KeyRangePlacement::default();
+        placement.add_node("node_0".to_string());
+        placement.split_at("node_1".to_string(), "m".to_string());
+        placement.split_at("node_2".to_string(), "t".to_string());
+
+        assert_eq!(
+            placement.replicas_for_key("orders:1", 2),
+            vec![&"node_1".to_string(), &"node_2".to_string()]
+        );
+        assert_eq!(
+            placement.replicas_for_key("user:1", 2),
+            vec![&"node_2".to_string(), &"node_0".to_string()]
+        );
+    }
+}
@@ -0,0 +1,45 @@
+/// Stable memory of the canister as `std::io` streams, kept on the heap outside a canister so
+/// upgrades can be tested
+use std::io::{Read, Write};
+
+#[cfg(target_arch = "wasm32")]
+pub fn writer() -> impl Write {
+    ic_cdk::api::stable::StableWriter::default()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn reader() -> impl Read {
+    ic_cdk::api::stable::StableReader::default()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+thread_local! {
+    static MEMORY: std::cell::RefCell<Vec<u8>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+struct MemoryWriter;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        MEMORY.with(|memory| memory.borrow_mut().extend_from_slice(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// writes from the start of the memory, like a canister's stable memory after an upgrade
+#[cfg(not(target_arch = "wasm32"))]
+pub fn writer() -> impl Write {
+    MEMORY.with(|memory| memory.borrow_mut().clear());
+    MemoryWriter
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn reader() -> impl Read {
+    std::io::Cursor::new(MEMORY.with(|memory| memory.borrow().clone()))
+}
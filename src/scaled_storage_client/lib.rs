@@ -0,0 +1,451 @@
+/// Client sending every request straight to the Scaled Storage node that owns its key
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_agent::{Agent, AgentError};
+use scaled_storage::node::Node;
+use scaled_storage::node_manager::{NodeInfo, Redirect};
+use scaled_storage::placement::{AnchorPlacement, Placement};
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+/// how long the reply of an update call is waited for
+pub const UPDATE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The cluster's placement as reported by one of its nodes, routes keys like the nodes do.
+/// `P` must be the placement the nodes were built with, a history the placement refuses, such as
+/// ranges added to a hashed placement, fails `from_node_info`.
+pub struct Topology<P: Placement<Principal> = AnchorPlacement<Principal>> {
+    placement: Node<Principal, (), P>,
+    epoch: u64,
+}
+
+impl<P: Placement<Principal> + Default> Topology<P> {
+    /// rebuilds the nodes' placement by replaying its history, see `Node::restore_placement`
+    pub fn from_node_info(node_info: &NodeInfo) -> Result<Self, String> {
+        let mut placement = Node::with_placement(Principal::anonymous(), P::default());
+        if !placement.restore_placement(node_info.placement_history.clone(), &[]) {
+            return Err("the placement refused the cluster's history".to_string());
+        }
+        placement.set_replication_factor(node_info.replication_factor as usize);
+        Ok(Self {
+            placement,
+            epoch: node_info.epoch,
+        })
+    }
+
+    pub fn owner_of(&self, key: &str) -> Option<Principal> {
+        self.placement.owner_of(&key.to_string()).copied()
+    }
+
+    /// nodes holding copies of `key`, its owner first. Calls are only sent to the owner.
+    pub fn replicas_of(&self, key: &str) -> Vec<Principal> {
+        self.placement
+            .replicas_of(key)
+            .into_iter()
+            .copied()
+            .collect()
+    }
+
+    /// epoch of the cluster when the topology was fetched, see `NodeInfo::epoch`
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn nodes(&self) -> Vec<Principal> {
+        self.placement.all_nodes().into_iter().copied().collect()
+    }
+}
+
+/// why a call to a node failed
+#[derive(Clone, Debug, PartialEq)]
+pub enum CallError {
+    /// the node refused the call or trapped, so it had no effect
+    Rejected(String),
+    /// no reply arrived, an update may have run or not
+    Unknown(String),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Rejected(error) => write!(f, "rejected: {}", error),
+            CallError::Unknown(error) => write!(f, "no reply: {}", error),
+        }
+    }
+}
+
+/// sends candid encoded calls to the nodes, an `Agent` talks to the network
+pub trait Transport {
+    fn call(
+        &self,
+        node_id: &Principal,
+        method: &str,
+        args: &[u8],
+        query: bool,
+    ) -> impl Future<Output = Result<Vec<u8>, CallError>>;
+}
+
+impl Transport for Agent {
+    async fn call(
+        &self,
+        node_id: &Principal,
+        method: &str,
+        args: &[u8],
+        query: bool,
+    ) -> Result<Vec<u8>, CallError> {
+        let reply = match query {
+            true => self.query(node_id, method).with_arg(args).call().await,
+            false => {
+                let waiter = garcon::Delay::builder()
+                    .throttle(Duration::from_millis(500))
+                    .timeout(UPDATE_TIMEOUT)
+                    .build();
+                self.update(node_id, method)
+                    .with_arg(args)
+                    .call_and_wait(waiter)
+                    .await
+            }
+        };
+        reply.map_err(|error| match error {
+            AgentError::ReplicaError { .. } => CallError::Rejected(error.to_string()),
+            error => CallError::Unknown(error.to_string()),
+        })
+    }
+}
+
+/// Sends calls on a key to the key's owner, saving the hop through a node that would forward
+/// them. The topology is fetched once and again whenever a call fails or a node reports a newer
+/// epoch.
+pub struct Client<T: Transport = Agent, P: Placement<Principal> = AnchorPlacement<Principal>> {
+    transport: T,
+    topology: Topology<P>,
+}
+
+impl<T: Transport> Client<T> {
+    /// fetches the topology of a cluster placed by AnchorHash from `canister_id`, any node of it
+    pub async fn new(transport: T, canister_id: Principal) -> Result<Self, String> {
+        Self::connect(transport, canister_id).await
+    }
+}
+
+impl<T: Transport, P: Placement<Principal> + Default> Client<T, P> {
+    /// fetches the topology from `canister_id`, any node of a cluster placed by `P`
+    pub async fn connect(transport: T, canister_id: Principal) -> Result<Self, String> {
+        let node_info = fetch_node_info(&transport, &canister_id).await?;
+        Ok(Self {
+            transport,
+            topology: Topology::from_node_info(&node_info)?,
+        })
+    }
+
+    pub fn topology(&self) -> &Topology<P> {
+        &self.topology
+    }
+
+    /// fetches the topology again from the first known node that answers
+    pub async fn refresh(&mut self) -> Result<(), String> {
+        let mut errors = vec![];
+        for node_id in self.topology.nodes() {
+            match fetch_node_info(&self.transport, &node_id).await {
+                Ok(node_info) => {
+                    self.topology = Topology::from_node_info(&node_info)?;
+                    return Ok(());
+                }
+                Err(error) => errors.push(format!("{}: {}", node_id, error)),
+            }
+        }
+        Err(format!("no node answered: {}", errors.join(", ")))
+    }
+
+    /// fetches the topology again when a node reports an epoch newer than the cached one
+    pub async fn observe_epoch(&mut self, epoch: u64) -> Result<(), String> {
+        match epoch > self.topology.epoch {
+            true => self.refresh().await,
+            false => Ok(()),
+        }
+    }
+
+    /// update call of `method` on the owner of `key`
+    pub async fn update<A, R>(&mut self, key: &str, method: &str, args: A) -> Result<R, String>
+    where
+        A: ArgumentEncoder,
+        R: CandidType + DeserializeOwned,
+    {
+        self.call(key, method, args, false).await
+    }
+
+    /// query call of `method` on the owner of `key`
+    pub async fn query<A, R>(&mut self, key: &str, method: &str, args: A) -> Result<R, String>
+    where
+        A: ArgumentEncoder,
+        R: CandidType + DeserializeOwned,
+    {
+        self.call(key, method, args, true).await
+    }
+
+    /// Query call of `method` on a replica of `key`, for endpoints routed with `redirect` which
+    /// return `Result<R, Redirect>`. A redirected call is sent once to the node it names, and the
+    /// topology is fetched again when the redirect's epoch is newer than the cached one.
+    pub async fn query_redirected<A, R>(
+        &mut self,
+        key: &str,
+        method: &str,
+        args: A,
+    ) -> Result<R, String>
+    where
+        A: ArgumentEncoder,
+        R: CandidType + DeserializeOwned,
+    {
+        let args = candid::encode_args(args).map_err(|error| error.to_string())?;
+        let owner = self.owner_of(key)?;
+        let reply = self.send(&owner, method, &args, true).await?;
+        let redirect = match Decode!(&reply, Result<R, Redirect>) {
+            Ok(Ok(reply)) => return Ok(reply),
+            Ok(Err(redirect)) => redirect,
+            Err(error) => return Err(error.to_string()),
+        };
+        self.observe_epoch(redirect.epoch).await?;
+        let reply = self.send(&redirect.owner, method, &args, true).await?;
+        match Decode!(&reply, Result<R, Redirect>) {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(redirect)) => Err(format!(
+                "{} redirected {} again, to {}",
+                method, key, redirect.owner
+            )),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    /// A failed call is sent again to the key's new owner when the key moved since the topology
+    /// was fetched. Updates are only sent again when the owner rejected them, since one whose
+    /// reply was lost may have run.
+    async fn call<A, R>(
+        &mut self,
+        key: &str,
+        method: &str,
+        args: A,
+        query: bool,
+    ) -> Result<R, String>
+    where
+        A: ArgumentEncoder,
+        R: CandidType + DeserializeOwned,
+    {
+        let args = candid::encode_args(args).map_err(|error| error.to_string())?;
+        let owner = self.owner_of(key)?;
+        let reply = match self.transport.call(&owner, method, &args, query).await {
+            Ok(reply) => reply,
+            Err(error @ CallError::Unknown(_)) if !query => return Err(error.to_string()),
+            Err(error) => {
+                self.refresh().await?;
+                let new_owner = self.owner_of(key)?;
+                if new_owner == owner {
+                    return Err(error.to_string());
+                }
+                self.send(&new_owner, method, &args, query).await?
+            }
+        };
+        Decode!(&reply, R).map_err(|error| error.to_string())
+    }
+
+    fn owner_of(&self, key: &str) -> Result<Principal, String> {
+        self.topology
+            .owner_of(key)
+            .ok_or_else(|| format!("no owner of {}", key))
+    }
+
+    async fn send(
+        &self,
+        node_id: &Principal,
+        method: &str,
+        args: &[u8],
+        query: bool,
+    ) -> Result<Vec<u8>, String> {
+        self.transport
+            .call(node_id, method, args, query)
+            .await
+            .map_err(|error| error.to_string())
+    }
+}
+
+async fn fetch_node_info(
+    transport: &impl Transport,
+    node_id: &Principal,
+) -> Result<NodeInfo, String> {
+    let args = Encode!().map_err(|error| error.to_string())?;
+    let reply = transport
+        .call(node_id, "node_info", &args, true)
+        .await
+        .map_err(|error| error.to_string())?;
+    Decode!(&reply, NodeInfo).map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallError, Client, Topology, Transport};
+    use candid::{Encode, Principal};
+    use futures::executor::block_on;
+    use ic_kit::{mock_principals, MockContext};
+    use scaled_storage::node_manager::{CanisterManager, NodeInfo};
+    use scaled_storage::placement::{AnchorPlacement, KeyRangePlacement};
+    use std::cell::{Cell, RefCell};
+
+    #[test]
+    fn topology_routes_keys_like_the_nodes() {
+        let node_id = mock_principals::alice();
+        MockContext::new().with_id(node_id).inject();
+        let mut cm = CanisterManager::<String>::new(node_id, |size| size > 10);
+        cm.canister.set_replication_factor(2);
+        cm.canister.add_weighted_node(mock_principals::bob(), 3);
+        // a weight change after a join places keys unlike the final weights in join order would
+        cm.canister.set_weight(&node_id, 2);
+        cm.canister.add_node(mock_principals::john());
+
+        let topology: Topology = Topology::from_node_info(&cm.node_info()).unwrap();
+
+        assert_eq!(topology.epoch(), cm.epoch());
+        assert_eq!(
+            topology.nodes(),
+            vec![node_id, mock_principals::bob(), mock_principals::john()]
+        );
+        for key in (0..1000).map(|i| format!("key_{}", i)) {
+            assert_eq!(topology.owner_of(&key), cm.canister.owner_of(&key).copied());
+            let replicas: Vec<Principal> =
+                cm.canister.replicas_of(&key).into_iter().copied().collect();
+            assert_eq!(topology.replicas_of(&key), replicas);
+        }
+    }
+
+    #[test]
+    fn topology_routes_key_ranges_like_the_nodes() {
+        let node_id = mock_principals::alice();
+        MockContext::new().with_id(node_id).inject();
+        let mut cm =
+            CanisterManager::<String, KeyRangePlacement<Principal>>::new(node_id, |_| false);
+        cm.canister
+            .add_node_at(mock_principals::bob(), "key_5".to_string());
+        cm.canister
+            .add_node_at(mock_principals::john(), "key_2".to_string());
+
+        let topology =
+            Topology::<KeyRangePlacement<Principal>>::from_node_info(&cm.node_info()).unwrap();
+        for key in (0..100).map(|i| format!("key_{}", i)) {
+            assert_eq!(topology.owner_of(&key), cm.canister.owner_of(&key).copied());
+        }
+
+        // ranges can't be placed by a hash
+        assert!(Topology::<AnchorPlacement<Principal>>::from_node_info(&cm.node_info()).is_err());
+    }
+
+    /// answers `node_info` with alice alone the first time and with bob joined afterwards, and
+    /// other calls with `reply`
+    struct MockTransport<F> {
+        node_infos: [NodeInfo; 2],
+        node_info_calls: Cell<usize>,
+        reply: F,
+        calls: RefCell<Vec<(Principal, String)>>,
+    }
+
+    impl<F: Fn(&Principal) -> Result<Vec<u8>, CallError>> Transport for MockTransport<F> {
+        async fn call(
+            &self,
+            node_id: &Principal,
+            method: &str,
+            _: &[u8],
+            _: bool,
+        ) -> Result<Vec<u8>, CallError> {
+            self.calls.borrow_mut().push((*node_id, method.to_string()));
+            if method != "node_info" {
+                return (self.reply)(node_id);
+            }
+            let node_info_calls = self.node_info_calls.get();
+            self.node_info_calls.set(node_info_calls + 1);
+            let node_info = &self.node_infos[node_info_calls.min(1)];
+            Ok(Encode!(node_info).unwrap())
+        }
+    }
+
+    /// a client that knows alice alone, and a key owned by bob once it joined
+    fn client<F>(reply: F) -> (Client<MockTransport<F>>, String)
+    where
+        F: Fn(&Principal) -> Result<Vec<u8>, CallError>,
+    {
+        let alice = mock_principals::alice();
+        MockContext::new().with_id(alice).inject();
+        let mut cm = CanisterManager::<String>::new(alice, |_| false);
+        let alone = cm.node_info();
+        cm.canister.add_node(mock_principals::bob());
+        let key = (0..)
+            .map(|i| format!("key_{}", i))
+            .find(|key| cm.canister.owner_of(key) == Some(&mock_principals::bob()))
+            .unwrap();
+        let transport = MockTransport {
+            node_infos: [alone, cm.node_info()],
+            node_info_calls: Cell::new(0),
+            reply,
+            calls: RefCell::new(vec![]),
+        };
+        (block_on(Client::new(transport, alice)).unwrap(), key)
+    }
+
+    fn calls<F>(client: &Client<MockTransport<F>>) -> Vec<(Principal, String)>
+    where
+        F: Fn(&Principal) -> Result<Vec<u8>, CallError>,
+    {
+        client.transport.calls.borrow().clone()
+    }
+
+    #[test]
+    fn rejected_updates_are_sent_to_the_new_owner() {
+        let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+        let (mut client, key) = client(|node_id| match *node_id == mock_principals::bob() {
+            true => Ok(Encode!(&"done".to_string()).unwrap()),
+            false => Err(CallError::Rejected("not the owner".to_string())),
+        });
+
+        let reply: Result<String, String> = block_on(client.update(&key, "update_data", ()));
+
+        assert_eq!(reply, Ok("done".to_string()));
+        assert_eq!(
+            calls(&client),
+            vec![
+                (alice, "node_info".to_string()),
+                (alice, "update_data".to_string()),
+                (alice, "node_info".to_string()),
+                (bob, "update_data".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn updates_without_a_reply_are_not_sent_again() {
+        let alice = mock_principals::alice();
+        let (mut client, key) = client(|_| Err(CallError::Unknown("timeout".to_string())));
+
+        let reply: Result<String, String> = block_on(client.update(&key, "update_data", ()));
+
+        assert_eq!(reply, Err("no reply: timeout".to_string()));
+        assert_eq!(
+            calls(&client),
+            vec![
+                (alice, "node_info".to_string()),
+                (alice, "update_data".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn queries_without_a_reply_are_sent_to_the_new_owner() {
+        let bob = mock_principals::bob();
+        let (mut client, key) = client(|node_id| match *node_id == mock_principals::bob() {
+            true => Ok(Encode!(&"value".to_string()).unwrap()),
+            false => Err(CallError::Unknown("timeout".to_string())),
+        });
+
+        let reply: Result<String, String> = block_on(client.query(&key, "get_data", ()));
+
+        assert_eq!(reply, Ok("value".to_string()));
+        assert_eq!(calls(&client).last(), Some(&(bob, "get_data".to_string())));
+    }
+}
@@ -1,142 +1,201 @@
 use candid::{CandidType, Deserialize, Principal};
 use ic_kit::{ic, macros::*};
-use scaled_storage::node::NodeResult;
-use scaled_storage::node_manager::{
-    CanisterManager, CanisterManagerEvent, InitCanisterManagerParam, NodeInfo, WasmInitArgs,
-};
+use scaled_storage::node::{NodeResult, WriteError};
+use scaled_storage::node_manager::{forward_call, CanisterManager, ConsistencyLevel, Redirect};
+use scaled_storage::routed;
 
-static mut CANISTER_MANAGER: Option<CanisterManager<String>> = None;
-
-#[init]
-fn init() {
-    unsafe {
-        CANISTER_MANAGER = Some(CanisterManager::new(ic::id(), |size| size > 50));
-    }
-}
+scaled_storage::scaled_storage_canister!(String, |size| size > 50);
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct OperationResult {
     data: String,
     from: Principal,
+    version: u64,
 }
 
-#[update]
-async fn update_data(key: String, value: String) -> OperationResult {
-    unsafe {
-        let canister_manager = &mut CANISTER_MANAGER.as_mut().unwrap().canister;
+/// why a write was not applied
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum UpdateError {
+    /// the key is at another version than the expected one, carries the current version
+    VersionConflict(u64),
+    /// a cross-node transaction prepared the key, the write can be retried once it ends
+    Locked,
+    /// the key or its owner couldn't be reached
+    Unavailable(String),
+}
 
-        match canister_manager.with_upsert_data_mut(key.clone(), |data| {
-            *data = value.clone();
-            data.clone()
-        }) {
-            NodeResult::NodeId(node_id) => {
-                match CanisterManager::<String>::forward_request::<OperationResult, _, _>(
-                    node_id,
-                    "update_data",
-                    (key, value),
-                )
-                .await
-                {
-                    Ok(result) => result,
-                    Err(error) => OperationResult {
-                        data: error,
-                        from: node_id,
-                    },
-                }
+impl From<WriteError> for UpdateError {
+    fn from(error: WriteError) -> Self {
+        match error {
+            WriteError::Locked => UpdateError::Locked,
+            WriteError::VersionConflict { current_version } => {
+                UpdateError::VersionConflict(current_version)
             }
-            NodeResult::Result(result) => OperationResult {
-                data: result.unwrap_or_default(),
-                from: canister_manager.id,
-            },
         }
     }
 }
 
-#[query]
-async fn get_data(key: String) -> OperationResult {
-    unsafe {
-        let canister = &mut CANISTER_MANAGER.as_mut().unwrap().canister;
-
-        match canister.with_data_mut(key.clone(), |data| data.clone()) {
-            NodeResult::NodeId(node_id) => {
-                let result = CanisterManager::<String>::forward_request::<OperationResult, _, _>(
-                    node_id,
-                    "get_data",
-                    (key,),
-                )
-                .await;
-                match result {
-                    Ok(result) => result,
-                    Err(error) => OperationResult {
-                        data: error,
-                        from: node_id,
-                    },
-                }
-            }
-            NodeResult::Result(result) => OperationResult {
-                data: result.unwrap_or_default(),
-                from: canister.id,
-            },
+// runs on the key's owner, calls on other nodes are forwarded to it
+#[routed(key)]
+#[update]
+async fn update_data(key: String, value: String) -> Result<OperationResult, UpdateError> {
+    let manager = canister_manager();
+    // while this node takes over keys from their previous owner, fetch the key before writing it
+    manager
+        .pull_during_handoff(&key)
+        .await
+        .map_err(UpdateError::Unavailable)?;
+    let result = manager
+        .borrow_mut()
+        .canister
+        .with_upsert_data_mut(key.clone(), |data| {
+            *data = value.clone();
+            data.clone()
+        });
+
+    match result {
+        // the key moved while its previous owner was fetched
+        NodeResult::NodeId(node_id) => {
+            forward_call(vec![node_id], "update_data", (key, value)).await
+        }
+        NodeResult::Result(result) => {
+            let result = OperationResult {
+                data: result?.unwrap_or_default(),
+                from: ic::id(),
+                version: manager.borrow().canister.version(&key),
+            };
+            // replicas that miss the write get it again with the next membership change
+            let _ = manager.replicate(vec![key]).await;
+            Ok(result)
         }
     }
 }
 
+// only writes if the key is still at `expected_version`, otherwise returns the current version
 #[update]
-async fn init_canister_manager(param: InitCanisterManagerParam) {
-    unsafe {
-        match param.args {
-            Some(args) => CANISTER_MANAGER
-                .as_mut()
-                .unwrap()
-                .lifecyle_init_node(Some(args.all_nodes)),
-            None => CANISTER_MANAGER.as_mut().unwrap().lifecyle_init_node(None),
-        }
+async fn update_data_if_version(
+    key: String,
+    value: String,
+    expected_version: u64,
+) -> Result<OperationResult, UpdateError> {
+    let manager = canister_manager();
+    manager
+        .pull_during_handoff(&key)
         .await
+        .map_err(UpdateError::Unavailable)?;
+    let result = manager.borrow_mut().canister.with_data_mut_if_version(
+        key.clone(),
+        expected_version,
+        |data| {
+            *data = value.clone();
+            data.clone()
+        },
+    );
+
+    match result {
+        NodeResult::NodeId(node_id) => CanisterManager::<String>::forward_request::<
+            Result<OperationResult, UpdateError>,
+            _,
+            _,
+        >(
+            node_id,
+            "update_data_if_version",
+            (key, value, expected_version),
+        )
+        .await
+        .map_err(UpdateError::Unavailable)?,
+        NodeResult::Result(result) => {
+            let versioned = result?;
+            let _ = manager.replicate(vec![key]).await;
+            Ok(OperationResult {
+                data: versioned.value,
+                from: ic::id(),
+                version: versioned.version,
+            })
+        }
     }
 }
 
+// only returns once `level` of the key's replicas hold the write
 #[update]
-fn init_wasm(param: WasmInitArgs) -> bool {
-    unsafe {
-        CANISTER_MANAGER
-            .as_mut()
-            .unwrap()
-            .lifecycle_init_wasm(param)
-    }
-}
+async fn update_data_with_consistency(
+    key: String,
+    value: String,
+    level: ConsistencyLevel,
+) -> Result<OperationResult, String> {
+    let manager = canister_manager();
+    manager.pull_during_handoff(&key).await?;
+    let result = manager
+        .borrow_mut()
+        .canister
+        .with_upsert_data_mut(key.clone(), |data| {
+            *data = value.clone();
+            data.clone()
+        });
 
-#[heartbeat]
-async fn heartbeat() {
-    unsafe {
-        CANISTER_MANAGER
-            .as_mut()
-            .unwrap()
-            .lifecyle_heartbeat_node()
-            .await;
+    match result {
+        NodeResult::NodeId(node_id) => {
+            CanisterManager::<String>::forward_request::<Result<OperationResult, String>, _, _>(
+                node_id,
+                "update_data_with_consistency",
+                (key, value, level),
+            )
+            .await?
+        }
+        NodeResult::Result(Err(_)) => Err(format!("key {} is locked", key)),
+        NodeResult::Result(Ok(result)) => {
+            let result = OperationResult {
+                data: result.unwrap_or_default(),
+                from: ic::id(),
+                version: manager.borrow().canister.version(&key),
+            };
+            manager.replicate_with_consistency(&key, level).await?;
+            Ok(result)
+        }
     }
 }
 
+// returns the newest value among `level` of the key's replicas
 #[update]
-async fn handle_event(event: CanisterManagerEvent) {
-    unsafe {
-        CANISTER_MANAGER
-            .as_mut()
-            .unwrap()
-            .lifecycle_handle_event(event)
-            .await
-    }
+async fn get_data_with_consistency(
+    key: String,
+    level: ConsistencyLevel,
+) -> Result<OperationResult, String> {
+    let (data, version) = canister_manager()
+        .read_with_consistency(&key, level)
+        .await?
+        .map(|versioned| (versioned.value, versioned.version))
+        .unwrap_or_default();
+    Ok(OperationResult {
+        data,
+        from: ic::id(),
+        version,
+    })
 }
 
+// served by any of the key's replicas, calls on other nodes are redirected to the key's owner
+// since queries can't call other canisters. A missing key reads as `None`, including one still
+// being handed off to this node, which `get_data_with_consistency` reads from its previous owner.
+#[routed(key, read, redirect)]
 #[query]
-fn node_info() -> NodeInfo {
-    unsafe { CANISTER_MANAGER.as_mut().unwrap().node_info() }
+fn get_data(key: String) -> Result<Option<OperationResult>, Redirect> {
+    let manager = canister_manager();
+    let manager = manager.borrow();
+    Ok(manager.canister.get_data(&key).map(|data| OperationResult {
+        data: data.clone(),
+        from: ic::id(),
+        version: manager.canister.version(&key),
+    }))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ic_kit::{mock_principals, MockContext};
-    use scaled_storage::node_manager::{InstallArgs, NodeStatus};
+    use ic_kit::{mock_principals, MockContext, RawHandler};
+    use scaled_storage::node::PlacementChange;
+    use scaled_storage::node_manager::{
+        InitCanisterManagerParam, InstallArgs, NodeStatus, WasmInitArgs,
+    };
 
     #[test]
     fn initial_canister() {
@@ -171,7 +230,10 @@ mod tests {
 
         init_canister_manager(InitCanisterManagerParam {
             args: Some(InstallArgs {
-                all_nodes: vec![previous_node],
+                placement_history: vec![PlacementChange::Add(previous_node)],
+                replication_factor: 1,
+                migration_mode: Default::default(),
+                controllers: vec![],
             }),
         })
         .await;
@@ -217,4 +279,148 @@ mod tests {
 
         matches!(node_info.status, NodeStatus::Ready);
     }
+
+    #[async_std::test]
+    async fn upgrade_keeps_data_and_members() {
+        let node_id = mock_principals::alice();
+        let previous_node = mock_principals::bob();
+
+        MockContext::new()
+            .with_caller(previous_node)
+            .with_id(node_id)
+            .with_constant_return_handler(())
+            .inject();
+
+        init();
+        init_canister_manager(InitCanisterManagerParam {
+            args: Some(InstallArgs {
+                placement_history: vec![
+                    PlacementChange::Add(previous_node),
+                    PlacementChange::Add(node_id),
+                ],
+                replication_factor: 2,
+                migration_mode: Default::default(),
+                controllers: vec![],
+            }),
+        })
+        .await;
+        canister_manager()
+            .borrow_mut()
+            .canister
+            .insert_data("alice".to_string(), "value".to_string());
+        let epoch = canister_manager().borrow().canister.epoch();
+
+        pre_upgrade();
+        // the upgrade drops the heap
+        CANISTER_MANAGER.with(|manager| *manager.borrow_mut() = None);
+        post_upgrade();
+
+        let manager = canister_manager();
+        let manager = manager.borrow();
+        assert_eq!(
+            node_info().all_nodes,
+            vec![previous_node.to_string(), node_id.to_string()]
+        );
+        assert_eq!(
+            manager.canister.get_data(&"alice".to_string()),
+            Some(&"value".to_string())
+        );
+        assert_eq!(manager.canister.version(&"alice".to_string()), 1);
+        assert_eq!(manager.canister.replication_factor(), 2);
+        assert_eq!(manager.canister.epoch(), epoch);
+        assert_eq!(manager.controllers(), &[previous_node]);
+    }
+
+    #[async_std::test]
+    async fn routed_update_is_forwarded_to_the_owner() {
+        let node_id = mock_principals::alice();
+        let owner = mock_principals::bob();
+
+        MockContext::new()
+            .with_id(node_id)
+            .with_handler(RawHandler::new(
+                |_, (key, value): (String, String), canister_id, method| {
+                    Ok((Ok::<_, UpdateError>(OperationResult {
+                        data: format!("{} {} {}", method, key, value),
+                        from: *canister_id,
+                        version: 1,
+                    }),))
+                },
+            ))
+            .inject();
+
+        init();
+        canister_manager().borrow_mut().canister.add_node(owner);
+        let owned_by = |node: Principal| {
+            (0..)
+                .map(|i| format!("key_{}", i))
+                .find(|key| canister_manager().borrow().canister.owner_of(key) == Some(&node))
+                .unwrap()
+        };
+
+        let key = owned_by(owner);
+        let result = update_data(key.clone(), "value".to_string()).await.unwrap();
+        assert_eq!(result.from, owner);
+        assert_eq!(result.data, format!("update_data {} value", key));
+        assert_eq!(canister_manager().borrow().canister.size(), 0);
+
+        let key = owned_by(node_id);
+        let result = update_data(key.clone(), "value".to_string()).await.unwrap();
+        assert_eq!(result.from, node_id);
+        assert_eq!(
+            canister_manager().borrow().canister.get_data(&key),
+            Some(&"value".to_string())
+        );
+
+        // a key prepared by a cross-node transaction refuses the write
+        canister_manager()
+            .borrow_mut()
+            .canister
+            .lock_keys(&"tx_1".to_string(), std::slice::from_ref(&key), 0, u64::MAX)
+            .unwrap();
+        assert!(matches!(
+            update_data(key.clone(), "other".to_string()).await,
+            Err(UpdateError::Locked)
+        ));
+        assert!(matches!(
+            update_data_if_version(key, "other".to_string(), 1).await,
+            Err(UpdateError::Locked)
+        ));
+    }
+
+    #[test]
+    fn reads_of_other_nodes_keys_are_redirected() {
+        let node_id = mock_principals::alice();
+        let owner = mock_principals::bob();
+        MockContext::new().with_id(node_id).inject();
+
+        init();
+        canister_manager().borrow_mut().canister.add_node(owner);
+        let owned_by = |node: Principal| {
+            (0..)
+                .map(|i| format!("key_{}", i))
+                .find(|key| canister_manager().borrow().canister.owner_of(key) == Some(&node))
+                .unwrap()
+        };
+
+        let epoch = canister_manager().borrow().epoch();
+        let redirect = get_data(owned_by(owner)).unwrap_err();
+        assert_eq!(redirect, Redirect { owner, epoch });
+
+        assert!(get_data(owned_by(node_id)).unwrap().is_none());
+        let key = owned_by(node_id);
+        canister_manager()
+            .borrow_mut()
+            .canister
+            .insert_data(key.clone(), "value".to_string());
+        let result = get_data(key).unwrap().unwrap();
+        assert_eq!(result.from, node_id);
+        assert_eq!(result.data, "value");
+    }
+
+    #[test]
+    fn candid_file_offers_the_housekeeping_methods() {
+        let did = include_str!("../scaled_storage.did");
+        assert_eq!(scaled_storage::interface::check_service(did), Ok(()));
+    }
 }
@@ -0,0 +1,347 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, parse_quote, Error, FnArg, Ident, ItemFn, LitStr, Pat, Path, Token};
+
+/// arguments of `#[routed(key, read, redirect, method = "name", manager = path)]`
+struct RoutedArgs {
+    key: Ident,
+    /// replicas of the key serve the call too, not only its owner
+    read: bool,
+    /// calls this node doesn't serve return a redirect instead of being forwarded
+    redirect: bool,
+    /// method the call is forwarded to, the function's name by default
+    method: Option<LitStr>,
+    /// function returning the `SharedCanisterManager`, `canister_manager` by default
+    manager: Option<Path>,
+}
+
+impl Parse for RoutedArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = RoutedArgs {
+            key: input.parse()?,
+            read: false,
+            redirect: false,
+            method: None,
+            manager: None,
+        };
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let name: Ident = input.parse()?;
+            match name.to_string().as_str() {
+                "read" => args.read = true,
+                "redirect" => args.redirect = true,
+                "method" => {
+                    input.parse::<Token![=]>()?;
+                    args.method = Some(input.parse()?);
+                }
+                "manager" => {
+                    input.parse::<Token![=]>()?;
+                    args.manager = Some(input.parse()?);
+                }
+                _ => {
+                    return Err(Error::new(
+                        name.span(),
+                        "expected `read`, `redirect`, `method = \"name\"` or `manager = path`",
+                    ))
+                }
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Routes an endpoint by one of its arguments, the key it works on. When another node owns the
+/// key, the whole call is forwarded to it with the same arguments and its reply is returned,
+/// otherwise the body runs on this node. With `read` the key's replicas serve the call too, and
+/// the forwarded call falls back to the next replica when one doesn't answer. A call no node
+/// answered traps, see `scaled_storage::node_manager::forward_call`.
+///
+/// Queries can't call other canisters, so `#[query]` endpoints must use `redirect`: instead of
+/// forwarding the call, the endpoint returns `Err(Redirect)` with the node to call and the topology
+/// epoch, and its return type must be `Result<_, Redirect>`. Redirecting endpoints may be sync.
+/// Composite queries, which may call queries of other canisters, are not supported: the `ic-cdk`
+/// and `ic-kit` versions this crate builds on have no composite query entry point, and a
+/// forwarded call is made with `ic::call`, which only issues update calls. `#[composite_query]`
+/// endpoints are refused with or without `redirect`.
+///
+/// Forwarding endpoints must be async. Their arguments are taken by name and moved into the
+/// forwarded call, which encodes them once. The node the call is forwarded to runs the endpoint
+/// with the forwarding node as `ic::caller()`, so an endpoint that checks its caller has to accept
+/// the nodes of the cluster or take the original caller as an argument.
+///
+/// `method` names the forwarded method when it isn't the function's name, and `manager` the
+/// function returning the manager when it isn't the `canister_manager()` that
+/// `scaled_storage_canister!` declares.
+///
+/// ```ignore
+/// #[routed(key)]
+/// #[update]
+/// async fn update_data(key: String, value: String) -> OperationResult {
+///     // only runs on the key's owner
+/// }
+///
+/// #[routed(key, read, redirect)]
+/// #[query]
+/// fn get_data(key: String) -> Result<OperationResult, Redirect> {
+///     // only runs on the key's replicas
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn routed(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as RoutedArgs);
+    let function = parse_macro_input!(item as ItemFn);
+    expand_routed(args, function)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand_routed(args: RoutedArgs, function: ItemFn) -> syn::Result<TokenStream2> {
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = function;
+    let query = attrs.iter().find(|attr| {
+        attr.path()
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "query")
+    });
+    let composite = attrs.iter().find(|attr| {
+        attr.path()
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "composite_query")
+    });
+    if let Some(composite) = composite {
+        return Err(Error::new_spanned(
+            composite,
+            "composite queries are not supported, route a `#[query]` with `redirect`",
+        ));
+    }
+    if let (Some(query), false) = (query, args.redirect) {
+        return Err(Error::new_spanned(
+            query,
+            "queries can't call other canisters, route them with `redirect`",
+        ));
+    }
+    if sig.asyncness.is_none() && !args.redirect {
+        return Err(Error::new_spanned(
+            sig.fn_token,
+            "routed endpoints must be async, forwarding awaits a call",
+        ));
+    }
+
+    let mut arg_names = vec![];
+    for input in &sig.inputs {
+        match input {
+            FnArg::Typed(arg) => match &*arg.pat {
+                Pat::Ident(pat) => arg_names.push(pat.ident.clone()),
+                pat => {
+                    return Err(Error::new_spanned(
+                        pat,
+                        "routed endpoints take their arguments by name",
+                    ))
+                }
+            },
+            FnArg::Receiver(receiver) => {
+                return Err(Error::new_spanned(
+                    receiver,
+                    "routed endpoints can't take self",
+                ))
+            }
+        }
+    }
+    let key = args.key;
+    if !arg_names.contains(&key) {
+        return Err(Error::new_spanned(
+            &key,
+            format!("`{}` is not an argument of `{}`", key, sig.ident),
+        ));
+    }
+
+    let read = args.read;
+    let method = args
+        .method
+        .unwrap_or_else(|| LitStr::new(&sig.ident.to_string(), sig.ident.span()));
+    let manager = args
+        .manager
+        .unwrap_or_else(|| parse_quote!(canister_manager));
+    let stmts = block.stmts;
+    if args.redirect {
+        return Ok(quote! {
+            #(#attrs)*
+            #vis #sig {
+                let scaled_storage_redirect = #manager().borrow().redirect(&#key, #read);
+                if let Some(redirect) = scaled_storage_redirect {
+                    return Err(redirect);
+                }
+                #(#stmts)*
+            }
+        });
+    }
+    Ok(quote! {
+        #(#attrs)*
+        #vis #sig {
+            let scaled_storage_route = #manager().borrow().route(&#key, #read);
+            if !scaled_storage_route.is_empty() {
+                return ::scaled_storage::node_manager::forward_call(
+                    scaled_storage_route,
+                    #method,
+                    (#(#arg_names,)*),
+                )
+                .await;
+            }
+            #(#stmts)*
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expand_routed, RoutedArgs};
+    use syn::{parse_quote, ItemFn, Stmt};
+
+    fn expand(args: RoutedArgs, function: ItemFn) -> Result<ItemFn, String> {
+        expand_routed(args, function)
+            .map(|tokens| syn::parse2(tokens).unwrap())
+            .map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn routed_endpoint_forwards_its_arguments() {
+        let function: ItemFn = parse_quote! {
+            #[update]
+            async fn get_data(key: String, mut limit: u64) -> Vec<String> {
+                vec![]
+            }
+        };
+        let expanded = expand(parse_quote!(key, read, method = "get"), function.clone()).unwrap();
+
+        assert_eq!(expanded.attrs, function.attrs);
+        assert_eq!(expanded.sig, function.sig);
+        let route: Stmt = parse_quote! {
+            let scaled_storage_route = canister_manager().borrow().route(&key, true);
+        };
+        let forward: Stmt = parse_quote! {
+            if !scaled_storage_route.is_empty() {
+                return ::scaled_storage::node_manager::forward_call(
+                    scaled_storage_route,
+                    "get",
+                    (key, limit,),
+                )
+                .await;
+            }
+        };
+        assert_eq!(expanded.block.stmts[..2], [route, forward]);
+        assert_eq!(expanded.block.stmts[2..], function.block.stmts);
+    }
+
+    #[test]
+    fn routed_endpoint_defaults_to_its_owner_and_name() {
+        let expanded = expand(
+            parse_quote!(id, manager = crate::manager),
+            parse_quote! {
+                async fn update_data(id: String) -> String {
+                    id
+                }
+            },
+        )
+        .unwrap();
+
+        let route: Stmt = parse_quote! {
+            let scaled_storage_route = crate::manager().borrow().route(&id, false);
+        };
+        let forward: Stmt = parse_quote! {
+            if !scaled_storage_route.is_empty() {
+                return ::scaled_storage::node_manager::forward_call(
+                    scaled_storage_route,
+                    "update_data",
+                    (id,),
+                )
+                .await;
+            }
+        };
+        assert_eq!(expanded.block.stmts[..2], [route, forward]);
+    }
+
+    #[test]
+    fn redirecting_endpoint_returns_the_redirect() {
+        let function: ItemFn = parse_quote! {
+            #[query]
+            fn get_data(key: String) -> Result<String, Redirect> {
+                Ok(key)
+            }
+        };
+        let expanded = expand(parse_quote!(key, read, redirect), function.clone()).unwrap();
+
+        assert_eq!(expanded.sig, function.sig);
+        let redirect: Stmt = parse_quote! {
+            let scaled_storage_redirect = canister_manager().borrow().redirect(&key, true);
+        };
+        let early_return: Stmt = parse_quote! {
+            if let Some(redirect) = scaled_storage_redirect {
+                return Err(redirect);
+            }
+        };
+        assert_eq!(expanded.block.stmts[..2], [redirect, early_return]);
+        assert_eq!(expanded.block.stmts[2..], function.block.stmts);
+    }
+
+    #[test]
+    fn routed_endpoint_must_be_async_and_take_the_key() {
+        let function: ItemFn = parse_quote! {
+            fn update_data(key: String) -> String {
+                key
+            }
+        };
+        assert!(expand(parse_quote!(key), function).is_err());
+
+        let function: ItemFn = parse_quote! {
+            async fn update_data(value: String) -> String {
+                value
+            }
+        };
+        assert_eq!(
+            expand(parse_quote!(key), function),
+            Err("`key` is not an argument of `update_data`".to_string())
+        );
+    }
+
+    #[test]
+    fn queries_must_redirect() {
+        let function: ItemFn = parse_quote! {
+            #[ic_kit::macros::query]
+            async fn get_data(key: String) -> String {
+                key
+            }
+        };
+        assert_eq!(
+            expand(parse_quote!(key, read), function),
+            Err("queries can't call other canisters, route them with `redirect`".to_string())
+        );
+    }
+
+    #[test]
+    fn composite_queries_are_refused() {
+        let function: ItemFn = parse_quote! {
+            #[composite_query]
+            async fn get_data(key: String) -> Result<String, Redirect> {
+                Ok(key)
+            }
+        };
+        assert_eq!(
+            expand(parse_quote!(key, read, redirect), function),
+            Err(
+                "composite queries are not supported, route a `#[query]` with `redirect`"
+                    .to_string()
+            )
+        );
+    }
+}
@@ -1,14 +1,9 @@
 use candid::{CandidType, Decode, Deserialize, Encode};
 use clap::Parser;
-use garcon::Delay;
-use ic_agent::{
-    agent::{QueryBuilder, UpdateBuilder},
-    ic_types::Principal,
-    identity::AnonymousIdentity,
-    Agent,
-};
+use ic_agent::{agent::QueryBuilder, ic_types::Principal, identity::AnonymousIdentity, Agent};
 use rand::seq::SliceRandom;
 use random_string::generate;
+use scaled_storage_client::Client;
 use std::collections::HashSet;
 
 #[derive(Parser, Debug)]
@@ -31,15 +26,13 @@ async fn main() {
     println!("Parsed args");
     let _ = agent.fetch_root_key().await;
 
-    let mut update_builder = agent.update(
-        &Principal::from_text(args.canister_id).unwrap(),
-        "update_data",
-    );
-
-    let waiter = garcon::Delay::builder()
-        .throttle(std::time::Duration::from_millis(500))
-        .timeout(std::time::Duration::from_secs(20))
-        .build();
+    // writes go straight to the owner of their key
+    let mut client = Client::new(
+        agent.clone(),
+        Principal::from_text(args.canister_id).unwrap(),
+    )
+    .await
+    .unwrap();
 
     println!("Created agent");
 
@@ -53,7 +46,7 @@ async fn main() {
     // Able to send and retrieve all data intact
     for (key, value) in &key_values {
         println!("{} {}", key, value);
-        let result = set(key, value, &mut update_builder, &waiter).await;
+        let result = set(key, value, &mut client).await;
         values.insert(result.data.clone());
         results.push(result);
     }
@@ -78,36 +71,41 @@ async fn main() {
         //get random query builder
 
         let query_builder = query_builders.choose_mut(&mut rand::thread_rng()).unwrap();
-        let result = get(key, query_builder).await;
+        let result = get(key, query_builder, &agent).await;
         assert!(values.contains(&result.data), "{}", result.data);
     }
 
     println!("Queries can be received from any canister");
 }
 
-async fn set(
-    key: &String,
-    value: &String,
-    update_builder: &mut UpdateBuilder<'_>,
-    waiter: &Delay,
-) -> NodeResult {
-    let response = update_builder
-        .with_arg(&Encode!(key, value).unwrap())
-        .call_and_wait(waiter.to_owned())
+async fn set(key: &String, value: &String, client: &mut Client) -> NodeResult {
+    client
+        .update::<_, Result<NodeResult, UpdateError>>(key, "update_data", (key, value))
         .await
-        .unwrap();
-
-    Decode!(response.as_slice(), NodeResult).unwrap()
+        .unwrap()
+        .unwrap()
 }
 
-async fn get(key: String, query_builder: &mut QueryBuilder<'_>) -> NodeResult {
-    let response = query_builder
-        .with_arg(&Encode!(&key).unwrap())
-        .call()
-        .await
-        .unwrap();
-
-    Decode!(response.as_slice(), NodeResult).unwrap()
+// nodes that don't hold the key redirect the query to its owner
+async fn get(key: String, query_builder: &mut QueryBuilder<'_>, agent: &Agent) -> NodeResult {
+    let arg = Encode!(&key).unwrap();
+    let response = query_builder.with_arg(&arg).call().await.unwrap();
+
+    let result = match Decode!(response.as_slice(), Result<Option<NodeResult>, Redirect>).unwrap() {
+        Ok(result) => result,
+        Err(redirect) => {
+            let response = agent
+                .query(&redirect.owner, "get_data")
+                .with_arg(arg)
+                .call()
+                .await
+                .unwrap();
+            Decode!(response.as_slice(), Result<Option<NodeResult>, Redirect>)
+                .unwrap()
+                .unwrap()
+        }
+    };
+    result.unwrap_or_else(|| panic!("{} was not found", key))
 }
 
 fn generate_key_value_pair(size: usize) -> HashSet<(String, String)> {
@@ -133,3 +131,16 @@ struct NodeResult {
     data: String,
     from: Principal,
 }
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum UpdateError {
+    VersionConflict(u64),
+    Locked,
+    Unavailable(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Redirect {
+    owner: Principal,
+    epoch: u64,
+}